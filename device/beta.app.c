@@ -0,0 +1,143 @@
+#include "dht11.h"
+#include "led.h"
+#include "log.h"
+#include "mlt_bt05.h"
+
+#include <avr/io.h>
+#include <util/delay.h>
+
+#include <stdint.h>
+
+// Every measurement is sent as a separate notification:
+// [kind symbol] [decimal exponent] [mantissa, int32 little endian]
+#define MEASUREMENT_LENGTH (6)
+#define MAX_MEASUREMENTS (8)
+// Gives the BLE module time to flush the previous notification
+#define NOTIFICATION_GAP_MS (50)
+
+enum CommandResult {
+    Ok = 0,
+    SensorFail = 1,
+    InvalidCommand = 2
+};
+
+struct Measurement {
+    char Kind;
+    int8_t Exponent;
+    int32_t Mantissa;
+};
+
+static uint16_t adc_read(uint8_t channel) {
+    // AVcc reference, right adjusted result
+    ADMUX = (1 << REFS0) | (channel & 0x07);
+    // Enable with 64 prescaler (125kHz at 8MHz)
+    ADCSRA = (1 << ADEN) | (1 << ADPS2) | (1 << ADPS1);
+
+    ADCSRA |= (1 << ADSC);
+    while (ADCSRA & (1 << ADSC));
+
+    return ADC;
+}
+
+static uint8_t read_measurements(struct Measurement measurements[MAX_MEASUREMENTS]) {
+    uint8_t count = 0;
+
+    int8_t temperature = 0;
+    uint8_t humidity = 0;
+    if (dht11_read(&temperature, &humidity)) {
+        measurements[count++] = (struct Measurement){ .Kind = 'T', .Exponent = 0, .Mantissa = temperature };
+        measurements[count++] = (struct Measurement){ .Kind = 'H', .Exponent = 0, .Mantissa = humidity };
+    }
+
+    // Photoresistor divider on ADC0, reported in percent with one decimal place
+    uint16_t light = adc_read(0);
+    measurements[count++] = (struct Measurement){ .Kind = 'L', .Exponent = -1, .Mantissa = ((int32_t)light * 1000) / 1023 };
+
+    return count;
+}
+
+static void send_result(enum CommandResult result, uint8_t first, uint8_t second, uint8_t third) {
+    uint8_t response[4] = { result, first, second, third };
+    bt_mlt05_send(response, sizeof(response));
+}
+
+static void command_read(void) {
+    struct Measurement measurements[MAX_MEASUREMENTS];
+    uint8_t count = read_measurements(measurements);
+
+    if (count == 0) {
+        uint8_t response[2] = { SensorFail, 0 };
+        bt_mlt05_send(response, sizeof(response));
+        return;
+    }
+
+    uint8_t header[2] = { Ok, count };
+    bt_mlt05_send(header, sizeof(header));
+
+    for (uint8_t i = 0; i < count; i++) {
+        _delay_ms(NOTIFICATION_GAP_MS);
+
+        uint32_t mantissa = (uint32_t)measurements[i].Mantissa;
+        uint8_t frame[MEASUREMENT_LENGTH] = {
+            (uint8_t)measurements[i].Kind,
+            (uint8_t)measurements[i].Exponent,
+            mantissa & 0xFF,
+            (mantissa >> 8) & 0xFF,
+            (mantissa >> 16) & 0xFF,
+            (mantissa >> 24) & 0xFF
+        };
+        bt_mlt05_send(frame, sizeof(frame));
+    }
+}
+
+static void handle_command(uint8_t command) {
+    switch (command) {
+        case 0x66: command_read(); return;
+        // 'B' distinguishes the reply from the Alpha hello
+        case 0x10: send_result(Ok, 0xF0, 0x14, 0x42); return;
+    }
+
+    send_result(InvalidCommand, 0, 0, 0);
+}
+
+int main(void) {
+
+    struct Led read_indicator = {
+        .Gpio = {
+            .Port = PortB,
+            .Pin = PB0
+        }
+    };
+
+    // Count every 1us
+#if F_CPU == 1000000
+    TCCR0B |= (1 << CS00);
+#elif F_CPU == 8000000
+    TCCR0B |= (1 << CS01);
+#else
+#error "Unsupported CPU speed"
+#endif
+
+    log_print("Init\r\n");
+    bt_mlt05_init();
+
+    dht11_init();
+
+    bt_mlt05_set_name("AirBetaWoland");
+    bt_mlt05_set_pin("432523");
+
+    while (1) {
+        log_print("Wait for command...\r\n");
+
+        led_on(read_indicator);
+        uint8_t command = bt_mlt05_receive();
+        led_off(read_indicator);
+
+        handle_command(command);
+
+        log_print("Sleeping... \r\n");
+        _delay_ms(5000);
+    }
+
+    return 0;
+}
@@ -0,0 +1,18 @@
+#include "crc8.h"
+
+uint8_t crc8(const uint8_t* data, unsigned int length) {
+    uint8_t crc = 0;
+
+    for (unsigned int i = 0; i < length; i++) {
+        crc ^= data[i];
+        for (uint8_t bit = 0; bit < 8; bit++) {
+            if (crc & 0x80) {
+                crc = (crc << 1) ^ 0x07;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    return crc;
+}
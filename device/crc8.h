@@ -0,0 +1,5 @@
+#pragma once
+#include <stdint.h>
+
+// CRC-8 with the 0x07 polynomial and zero initial value (CRC-8/SMBUS)
+uint8_t crc8(const uint8_t* data, unsigned int length);
@@ -1,3 +1,4 @@
+#include "crc8.h"
 #include "dht11.h"
 #include "uart.h"
 #include "led.h"
@@ -5,7 +6,9 @@
 #include "log.h"
 #include "mlt_bt05.h"
 
+#include <avr/eeprom.h>
 #include <avr/io.h>
+#include <avr/wdt.h>
 #include <util/delay.h>
 
 #include <stdio.h>
@@ -13,7 +16,68 @@
 #include <assert.h>
 #include <time.h>
 
-#define RESPONSE_DATA_LENGTH (3)
+#define PROTOCOL_VERSION (2)
+// Announced in the hello response, one bit per optional command
+#define CAPABILITY_FRACTIONAL_READ (1 << 0)
+#define CAPABILITY_FRAMED (1 << 1)
+#define CAPABILITY_SUPPLY_VOLTAGE (1 << 2)
+#define CAPABILITY_CONFIG (1 << 3)
+#define CAPABILITIES (CAPABILITY_FRACTIONAL_READ | CAPABILITY_FRAMED | CAPABILITY_SUPPLY_VOLTAGE | CAPABILITY_CONFIG)
+
+#define MAX_RESPONSE_DATA_LENGTH (6)
+// Keeps the longest framed request within a single 20 byte BLE write
+#define MAX_ARGUMENT_LENGTH (15)
+
+// Framed request: [0xA5] [length] [sequence] [command] [arguments...] [crc8 of length, sequence, command and arguments]
+// The response repeats the sequence and carries [status] [data...] as the payload
+#define FRAME_START (0xA5)
+#define MAX_REQUEST_PAYLOAD (MAX_ARGUMENT_LENGTH + 1)
+#define MAX_RESPONSE_PAYLOAD (MAX_RESPONSE_DATA_LENGTH + 1)
+#define FRAME_OVERHEAD (4)
+
+#define MAX_NAME_LENGTH (MAX_ARGUMENT_LENGTH)
+#define PIN_LENGTH (6)
+#define MAX_SLEEP_MS (60000)
+
+// Bumped whenever the EEPROM layout changes, which resets the stored configuration
+#define CONFIG_MAGIC (0xC0F1)
+
+struct Config {
+    uint16_t Magic;
+    char Name[MAX_NAME_LENGTH + 1];
+    char Pin[PIN_LENGTH + 1];
+    uint16_t SleepMs;
+};
+
+static struct Config EEMEM stored_config;
+static struct Config config;
+
+static struct Led read_indicator = {
+    .Gpio = {
+        .Port = PortB,
+        .Pin = PB0
+    }
+};
+
+static void config_load(void) {
+    eeprom_read_block(&config, &stored_config, sizeof(config));
+    if (config.Magic != CONFIG_MAGIC) {
+        config.Magic = CONFIG_MAGIC;
+        strcpy(config.Name, "WeatherWoland");
+        strcpy(config.Pin, "432523");
+        config.SleepMs = 5000;
+    }
+}
+
+static void config_save(void) {
+    eeprom_update_block(&config, &stored_config, sizeof(config));
+}
+
+struct Request {
+    uint8_t Command;
+    uint8_t Length;
+    uint8_t Arguments[MAX_ARGUMENT_LENGTH];
+};
 
 enum CommandResult {
     Ok = 0,
@@ -21,43 +85,236 @@ enum CommandResult {
     InvalidCommand = 2
 };
 
-enum CommandResult command_read(uint8_t response[RESPONSE_DATA_LENGTH]) {
+struct Response {
+    uint8_t Length;
+    uint8_t Data[MAX_RESPONSE_DATA_LENGTH];
+};
+
+// v1 frame: [temperature] [humidity] [0]
+enum CommandResult command_read(struct Response* response) {
     int8_t temperature = 0;
     uint8_t humidity = 0;
 
+    response->Length = 3;
     if (dht11_read(&temperature, &humidity)) {
-        response[0] = temperature;
-        response[1] = humidity;
+        response->Data[0] = temperature;
+        response->Data[1] = humidity;
         return Ok;
     }
 
     return SensorFail;
 }
 
-enum CommandResult command_hello(uint8_t response[RESPONSE_DATA_LENGTH]) {
-    response[0] = 0xF0;
-    response[1] = 0x14;
-    response[2] = 0x4D;
+// Fractional frame: [temperature, int16 LE tenths of a degree] [humidity, uint16 LE tenths of a percent]
+enum CommandResult command_read_fractional(struct Response* response) {
+    int8_t temperature = 0;
+    uint8_t humidity = 0;
+
+    response->Length = 4;
+    if (dht11_read(&temperature, &humidity)) {
+        uint16_t temperature_tenths = (uint16_t)((int16_t)temperature * 10);
+        uint16_t humidity_tenths = (uint16_t)humidity * 10;
+        response->Data[0] = temperature_tenths & 0xFF;
+        response->Data[1] = temperature_tenths >> 8;
+        response->Data[2] = humidity_tenths & 0xFF;
+        response->Data[3] = humidity_tenths >> 8;
+        return Ok;
+    }
+
+    return SensorFail;
+}
+
+// Measures the internal 1.1V bandgap against AVcc: [millivolts, uint16 LE]
+enum CommandResult command_supply_voltage(struct Response* response) {
+    ADMUX = (1 << REFS0) | (1 << MUX3) | (1 << MUX2) | (1 << MUX1);
+    ADCSRA = (1 << ADEN) | (1 << ADPS2) | (1 << ADPS1);
+    // Let the reference settle after switching the input
+    _delay_ms(2);
+
+    ADCSRA |= (1 << ADSC);
+    while (ADCSRA & (1 << ADSC));
+    uint16_t bandgap = ADC;
+    ADCSRA &= ~(1 << ADEN);
+
+    response->Length = 2;
+    if (bandgap == 0) {
+        return SensorFail;
+    }
+
+    uint16_t millivolts = (uint16_t)(1125300UL / bandgap);
+    response->Data[0] = millivolts & 0xFF;
+    response->Data[1] = millivolts >> 8;
+    return Ok;
+}
+
+// The magic is followed by the protocol version and the capabilities (uint16 LE),
+// v1 devices reply with the magic only
+enum CommandResult command_hello(struct Response* response) {
+    response->Length = 6;
+    response->Data[0] = 0xF0;
+    response->Data[1] = 0x14;
+    response->Data[2] = 0x4D;
+    response->Data[3] = PROTOCOL_VERSION;
+    response->Data[4] = CAPABILITIES & 0xFF;
+    response->Data[5] = CAPABILITIES >> 8;
     return Ok;
 }
 
-enum CommandResult handle_command(uint8_t command, uint8_t response[RESPONSE_DATA_LENGTH]) {
-    switch (command) {
+// [name characters], applied by the BLE module after a reboot
+enum CommandResult command_set_name(const struct Request* request) {
+    if (request->Length == 0 || request->Length > MAX_NAME_LENGTH) {
+        return InvalidCommand;
+    }
+    memcpy(config.Name, request->Arguments, request->Length);
+    config.Name[request->Length] = '\0';
+    config_save();
+    return Ok;
+}
+
+// [6 digits], applied by the BLE module after a reboot
+enum CommandResult command_set_pin(const struct Request* request) {
+    if (request->Length != PIN_LENGTH) {
+        return InvalidCommand;
+    }
+    for (uint8_t i = 0; i < PIN_LENGTH; i++) {
+        if (request->Arguments[i] < '0' || request->Arguments[i] > '9') {
+            return InvalidCommand;
+        }
+    }
+    memcpy(config.Pin, request->Arguments, PIN_LENGTH);
+    config.Pin[PIN_LENGTH] = '\0';
+    config_save();
+    return Ok;
+}
+
+// [sleep after every command in milliseconds, uint16 LE]
+enum CommandResult command_set_sleep(const struct Request* request) {
+    if (request->Length != 2) {
+        return InvalidCommand;
+    }
+    uint16_t sleep_ms = request->Arguments[0] | ((uint16_t)request->Arguments[1] << 8);
+    if (sleep_ms > MAX_SLEEP_MS) {
+        return InvalidCommand;
+    }
+    config.SleepMs = sleep_ms;
+    config_save();
+    return Ok;
+}
+
+// Blinks the read LED so the device can be found among the others
+enum CommandResult command_identify(void) {
+    for (uint8_t i = 0; i < 10; i++) {
+        led_on(read_indicator);
+        _delay_ms(150);
+        led_off(read_indicator);
+        _delay_ms(150);
+    }
+    return Ok;
+}
+
+static bool has_arguments(uint8_t command) {
+    return command >= 0x30 && command <= 0x32;
+}
+
+// Configuration commands come in batches, so the next one is awaited right away
+static bool is_configuration(uint8_t command) {
+    return command >= 0x30 && command <= 0x34;
+}
+
+enum CommandResult handle_command(const struct Request* request, struct Response* response) {
+    switch (request->Command) {
         case 0x66: return command_read(response);
+        case 0x67: return command_read_fractional(response);
+        case 0x20: return command_supply_voltage(response);
         case 0x10: return command_hello(response);
+        case 0x30: return command_set_name(request);
+        case 0x31: return command_set_pin(request);
+        case 0x32: return command_set_sleep(request);
+        // Rebooted after the response is sent
+        case 0x33: return Ok;
+        case 0x34: return command_identify();
     }
 
+    response->Length = 3;
     return InvalidCommand;
 }
 
-int main(void) {
+// The watchdog is the only way to reset the MCU from the software
+static void reboot(void) {
+    log_print("Rebooting...\r\n");
+    _delay_ms(100);
+    wdt_enable(WDTO_15MS);
+    while (1);
+}
 
-    struct Led read_indicator = {
-        .Gpio = {
-            .Port = PortB,
-            .Pin = PB0
+// Commands taking arguments are followed by [length] [arguments...]
+static uint8_t handle_plain(uint8_t command) {
+    struct Request request = { .Command = command, .Length = 0 };
+    if (has_arguments(command)) {
+        uint8_t length = bt_mlt05_receive();
+        for (uint8_t i = 0; i < length; i++) {
+            uint8_t argument = bt_mlt05_receive();
+            if (i < MAX_ARGUMENT_LENGTH) {
+                request.Arguments[i] = argument;
+            }
         }
-    };
+        // Too long arguments are rejected by the commands
+        request.Length = length;
+    }
+
+    struct Response response = { 0 };
+    uint8_t frame[MAX_RESPONSE_PAYLOAD] = { 0 };
+    frame[0] = (uint8_t)handle_command(&request, &response);
+    memcpy(&frame[1], response.Data, response.Length);
+    bt_mlt05_send(frame, response.Length + 1);
+
+    if (command == 0x33) {
+        reboot();
+    }
+    return command;
+}
+
+// Returns the handled command, 0 when the frame was dropped
+static uint8_t handle_framed(void) {
+    uint8_t request[MAX_REQUEST_PAYLOAD + 2];
+    request[0] = bt_mlt05_receive();
+    if (request[0] == 0 || request[0] > MAX_REQUEST_PAYLOAD) {
+        log_print("Invalid frame length\r\n");
+        return 0;
+    }
+    for (uint8_t i = 1; i < request[0] + 2; i++) {
+        request[i] = bt_mlt05_receive();
+    }
+    uint8_t checksum = bt_mlt05_receive();
+    // The server gives up on the corrupted request after a timeout
+    if (checksum != crc8(request, request[0] + 2)) {
+        log_print("Frame checksum mismatch\r\n");
+        return 0;
+    }
+
+    struct Request command = { .Command = request[2], .Length = request[0] - 1 };
+    memcpy(command.Arguments, &request[3], command.Length);
+
+    struct Response response = { 0 };
+    uint8_t frame[MAX_RESPONSE_PAYLOAD + FRAME_OVERHEAD] = { 0 };
+    frame[0] = FRAME_START;
+    frame[2] = request[1];
+    frame[3] = (uint8_t)handle_command(&command, &response);
+    memcpy(&frame[4], response.Data, response.Length);
+    frame[1] = response.Length + 1;
+    frame[frame[1] + 3] = crc8(&frame[1], frame[1] + 2);
+    bt_mlt05_send(frame, frame[1] + FRAME_OVERHEAD);
+
+    if (command.Command == 0x33) {
+        reboot();
+    }
+    return command.Command;
+}
+
+int main(void) {
+    // The watchdog stays enabled after the reboot command
+    MCUSR = 0;
+    wdt_disable();
 
     // Count every 1us
 #if F_CPU == 1000000
@@ -77,8 +334,9 @@ int main(void) {
 
     dht11_init();
 
-    bt_mlt05_set_name("WeatherWoland");
-    bt_mlt05_set_pin("432523");
+    config_load();
+    bt_mlt05_set_name(config.Name);
+    bt_mlt05_set_pin(config.Pin);
 
     while (1) {
 
@@ -88,12 +346,16 @@ int main(void) {
         uint8_t command = bt_mlt05_receive();
         led_off(read_indicator);
 
-        uint8_t response[RESPONSE_DATA_LENGTH + 1] = { 0 };
-        response[0] = (uint8_t)handle_command(command, &response[1]);
-        bt_mlt05_send(response, sizeof(response));
+        uint8_t handled = command == FRAME_START ? handle_framed() : handle_plain(command);
+        if (is_configuration(handled)) {
+            continue;
+        }
 
         log_print("Sleeping... \r\n");
-        _delay_ms(5000);
+        // _delay_ms needs a compile time constant
+        for (uint16_t i = 0; i < config.SleepMs / 10; i++) {
+            _delay_ms(10);
+        }
     }
 
     return 0;
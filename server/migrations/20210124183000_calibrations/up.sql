@@ -0,0 +1,10 @@
+CREATE TABLE Calibrations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor INTEGER NOT NULL,
+    kind CHAR(1) NOT NULL,
+    offset REAL NOT NULL DEFAULT 0,
+    gain REAL NOT NULL DEFAULT 1,
+    effective_from DATETIME NOT NULL,
+    FOREIGN KEY(sensor) REFERENCES Sensors(id),
+    FOREIGN KEY(kind) REFERENCES ReadingKinds(symbol)
+);
@@ -0,0 +1,15 @@
+CREATE TABLE ReadingsWithoutQuality (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor INTEGER NOT NULL,
+    timestamp DATETIME NOT NULL,
+    kind CHAR(1) NOT NULL,
+    value INT NOT NULL,
+    FOREIGN KEY(sensor) REFERENCES Sensors(id),
+    FOREIGN KEY(kind) REFERENCES ReadingKinds(symbol)
+);
+
+INSERT INTO ReadingsWithoutQuality (id, sensor, timestamp, kind, value)
+    SELECT id, sensor, timestamp, kind, value FROM Readings;
+
+DROP TABLE Readings;
+ALTER TABLE ReadingsWithoutQuality RENAME TO Readings;
@@ -0,0 +1,2 @@
+-- 0 = good, 1 = outside of the plausible range, 2 = changed faster than plausible
+ALTER TABLE Readings ADD COLUMN quality INT NOT NULL DEFAULT 0;
@@ -0,0 +1,23 @@
+CREATE TABLE IntegerReadings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor INTEGER NOT NULL,
+    timestamp DATETIME NOT NULL,
+    kind CHAR(1) NOT NULL,
+    value INT NOT NULL,
+    quality INT NOT NULL DEFAULT 0,
+    FOREIGN KEY(sensor) REFERENCES Sensors(id),
+    FOREIGN KEY(kind) REFERENCES ReadingKinds(symbol)
+);
+
+INSERT INTO IntegerReadings (id, sensor, timestamp, kind, value, quality)
+    SELECT id, sensor, timestamp, kind, CAST(ROUND(value) AS INT), quality FROM Readings;
+
+DROP TABLE Readings;
+ALTER TABLE IntegerReadings RENAME TO Readings;
+
+CREATE TABLE ReadingKindsSymbols (
+    symbol CHAR(1) PRIMARY KEY
+);
+INSERT INTO ReadingKindsSymbols SELECT symbol FROM ReadingKinds;
+DROP TABLE ReadingKinds;
+ALTER TABLE ReadingKindsSymbols RENAME TO ReadingKinds;
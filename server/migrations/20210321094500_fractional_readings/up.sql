@@ -0,0 +1,24 @@
+ALTER TABLE ReadingKinds ADD COLUMN name VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE ReadingKinds ADD COLUMN unit VARCHAR NOT NULL DEFAULT '';
+-- Number of meaningful decimal places
+ALTER TABLE ReadingKinds ADD COLUMN precision INT NOT NULL DEFAULT 0;
+
+UPDATE ReadingKinds SET name = 'temperature', unit = '°C', precision = 1 WHERE symbol = 'T';
+UPDATE ReadingKinds SET name = 'humidity', unit = '%', precision = 1 WHERE symbol = 'H';
+
+CREATE TABLE FractionalReadings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor INTEGER NOT NULL,
+    timestamp DATETIME NOT NULL,
+    kind CHAR(1) NOT NULL,
+    value REAL NOT NULL,
+    quality INT NOT NULL DEFAULT 0,
+    FOREIGN KEY(sensor) REFERENCES Sensors(id),
+    FOREIGN KEY(kind) REFERENCES ReadingKinds(symbol)
+);
+
+INSERT INTO FractionalReadings (id, sensor, timestamp, kind, value, quality)
+    SELECT id, sensor, timestamp, kind, CAST(value AS REAL), quality FROM Readings;
+
+DROP TABLE Readings;
+ALTER TABLE FractionalReadings RENAME TO Readings;
@@ -0,0 +1,15 @@
+DELETE FROM Readings WHERE kind NOT IN ('T', 'H');
+DELETE FROM Calibrations WHERE kind NOT IN ('T', 'H');
+DELETE FROM ReadingKinds WHERE symbol NOT IN ('T', 'H');
+
+CREATE TABLE SensorsWithoutFamily (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    address CHAR(16) NOT NULL,
+    name VARCHAR NULL
+);
+
+INSERT INTO SensorsWithoutFamily (id, address, name)
+    SELECT id, address, name FROM Sensors;
+
+DROP TABLE Sensors;
+ALTER TABLE SensorsWithoutFamily RENAME TO Sensors;
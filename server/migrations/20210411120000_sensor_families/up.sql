@@ -0,0 +1,8 @@
+-- Every sensor stored so far is an Alpha one
+ALTER TABLE Sensors ADD COLUMN family VARCHAR NOT NULL DEFAULT 'Alpha';
+
+INSERT INTO ReadingKinds (symbol, name, unit, precision) VALUES
+    ('P', 'pressure', 'hPa', 1),
+    ('C', 'CO2', 'ppm', 0),
+    ('V', 'VOC', 'ppb', 0),
+    ('L', 'light', '%', 1);
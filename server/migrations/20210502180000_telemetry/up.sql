@@ -0,0 +1,11 @@
+-- Health of the sensors themselves, e.g. the signal strength and the supply voltage
+CREATE TABLE Telemetry (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor INTEGER NOT NULL,
+    timestamp DATETIME NOT NULL,
+    kind VARCHAR NOT NULL,
+    value REAL NOT NULL,
+    FOREIGN KEY(sensor) REFERENCES Sensors(id)
+);
+
+CREATE INDEX TelemetryBySensorAndKind ON Telemetry (sensor, kind);
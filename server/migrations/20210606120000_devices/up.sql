@@ -0,0 +1,13 @@
+-- Every device recognized by a driver, whether its readings are stored is decided by the status
+CREATE TABLE Devices (
+    address VARCHAR PRIMARY KEY NOT NULL,
+    name VARCHAR,
+    family VARCHAR NOT NULL,
+    status VARCHAR NOT NULL,
+    first_seen DATETIME NOT NULL,
+    last_seen DATETIME NOT NULL
+);
+
+-- Sensors stored so far keep being polled
+INSERT INTO Devices (address, name, family, status, first_seen, last_seen)
+    SELECT address, name, family, 'adopted', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP FROM Sensors;
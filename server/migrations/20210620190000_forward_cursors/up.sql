@@ -0,0 +1,6 @@
+-- Last readings and telemetry rows forwarded to each central server
+CREATE TABLE ForwardCursors (
+    target VARCHAR PRIMARY KEY NOT NULL,
+    readings INTEGER NOT NULL,
+    telemetry INTEGER NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Only the SHA-256 of the tokens is stored, the tokens themselves are shown once when created
+CREATE TABLE ApiTokens (
+    name VARCHAR PRIMARY KEY NOT NULL,
+    hash CHAR(64) NOT NULL UNIQUE,
+    scope VARCHAR NOT NULL,
+    created DATETIME NOT NULL
+);
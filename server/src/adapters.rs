@@ -0,0 +1,383 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use btleplug::api::{BDAddr, Central, CentralEvent, Peripheral};
+use serde::Deserialize;
+
+/// How a sensor seen by several adapters is assigned to one of them
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all="snake_case")]
+pub enum AdapterBalance {
+    /// The adapter with the strongest signal the last time the sensor was connected through it.
+    /// btleplug does not report the signal strength of the advertisements, so until the sensor has
+    /// been connected through every adapter which sees it, it goes to the one with the fewest connections.
+    BestRssi,
+    FewestConnections
+}
+
+/// Failed connection attempts in a row after which the adapter is considered unresponsive
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Wait before an unresponsive adapter is tried again, doubled after every try that fails
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Event of one of the adapters, `None` when the adapter stopped reporting them
+pub struct AdapterEvent {
+    index: usize,
+    /// Tells the events of a reset adapter from the ones still queued by its previous connection
+    generation: u32,
+    event: Option<CentralEvent>
+}
+
+struct PooledAdapter<C> {
+    name: String,
+    central: C,
+    generation: u32,
+    /// Only the adapters we own may be power cycled, the others may be used by other programs
+    owned: bool,
+    healthy: bool,
+    failures: u32,
+    retry_delay: Duration,
+    /// When an unresponsive adapter is tried again
+    retry_at: Option<Instant>
+}
+
+/// All the BLE adapters the sensors are spread across
+pub struct AdapterPool<P: Peripheral, C: Central<P>> {
+    adapters: Vec<PooledAdapter<C>>,
+    balance: AdapterBalance,
+    /// Adapter of every connected or queued sensor
+    assigned: HashMap<BDAddr, usize>,
+    /// Signal strength of the connection to the sensor through the adapter
+    rssi: HashMap<(BDAddr, usize), i8>,
+    events: mpsc::Sender<AdapterEvent>,
+    peripheral: PhantomData<P>
+}
+
+impl<P: Peripheral, C: Central<P> + 'static> AdapterPool<P, C> {
+    pub fn new(balance: AdapterBalance) -> (Self, mpsc::Receiver<AdapterEvent>) {
+        let (tx, rx) = mpsc::channel();
+        let pool = AdapterPool {
+            adapters: Vec::new(),
+            balance,
+            assigned: HashMap::new(),
+            rssi: HashMap::new(),
+            events: tx,
+            peripheral: PhantomData
+        };
+        (pool, rx)
+    }
+
+    pub fn add(&mut self, name: String, central: C, owned: bool) {
+        let index = self.adapters.len();
+        self.forward_events(index, 0, &central);
+        self.adapters.push(PooledAdapter {
+            name,
+            central,
+            generation: 0,
+            owned,
+            healthy: true,
+            failures: 0,
+            retry_delay: FIRST_RETRY_DELAY,
+            retry_at: None
+        });
+    }
+
+    fn forward_events(&self, index: usize, generation: u32, central: &C) {
+        let events = self.events.clone();
+        let receiver = match central.event_receiver() {
+            Some(receiver) => receiver,
+            None => {
+                let _ = events.send(AdapterEvent { index, generation, event: None });
+                return;
+            }
+        };
+
+        thread::spawn(move || {
+            for event in receiver.iter() {
+                if events.send(AdapterEvent { index, generation, event: Some(event) }).is_err() {
+                    return;
+                }
+            }
+            let _ = events.send(AdapterEvent { index, generation, event: None });
+        });
+    }
+
+    /// Waits for the next event of the adapters in use, as the adapter index and the event
+    pub fn receive(&self, events: &mpsc::Receiver<AdapterEvent>, timeout: Duration) -> Option<(usize, Option<CentralEvent>)> {
+        let received = events.recv_timeout(timeout).ok()?;
+        let adapter = &self.adapters[received.index];
+        if received.generation != adapter.generation || !adapter.healthy {
+            return None;
+        }
+        Some((received.index, received.event))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adapters.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.adapters.len()
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        &self.adapters[index].name
+    }
+
+    pub fn is_owned(&self, index: usize) -> bool {
+        self.adapters[index].owned
+    }
+
+    /// Returns false when the adapter does not respond
+    pub fn start_scan(&self, index: usize) -> bool {
+        match self.adapters[index].central.start_scan() {
+            Ok(()) => true,
+            Err(err) => {
+                println!("Failed to start scan on {}: {:?}", self.name(index), err);
+                false
+            }
+        }
+    }
+
+    pub fn stop_scan_all(&self) {
+        for adapter in self.adapters.iter().filter(|adapter| adapter.healthy) {
+            if let Err(err) = adapter.central.stop_scan() {
+                println!("Failed to stop scan on {}: {:?}", adapter.name, err);
+            }
+        }
+    }
+
+    pub fn peripheral(&self, index: usize, address: BDAddr) -> Option<P> {
+        self.adapters[index].central.peripheral(address)
+    }
+
+    pub fn assigned_to(&self, address: BDAddr) -> Option<usize> {
+        self.assigned.get(&address).copied()
+    }
+
+    fn connections(&self, index: usize) -> usize {
+        self.assigned.values().filter(|&&assigned| assigned == index).count()
+    }
+
+    /// Picks the adapter to connect to the sensor through, among the responding ones which see it,
+    /// as its index and the peripheral seen by it. None when it is already assigned.
+    pub fn assign(&mut self, address: BDAddr) -> Option<(usize, P)> {
+        if self.assigned.contains_key(&address) {
+            return None;
+        }
+
+        let candidates: Vec<(usize, P)> = (0..self.adapters.len())
+            .filter(|&index| self.adapters[index].healthy)
+            .filter_map(|index| self.peripheral(index, address).map(|peripheral| (index, peripheral)))
+            .collect();
+        // An adapter the signal was never measured on is not ranked below the measured ones
+        let measured = candidates.iter().all(|(index, _)| self.rssi.contains_key(&(address, *index)));
+        let (index, peripheral) = match self.balance {
+            AdapterBalance::BestRssi if measured => candidates.into_iter().max_by_key(|(index, _)| (
+                self.rssi[&(address, *index)],
+                Reverse(self.connections(*index)),
+                Reverse(*index))),
+            _ => candidates.into_iter().min_by_key(|(index, _)| (self.connections(*index), *index))
+        }?;
+
+        println!("Assigning {} to {}", address, self.name(index));
+        self.assigned.insert(address, index);
+        Some((index, peripheral))
+    }
+
+    pub fn release(&mut self, address: BDAddr) {
+        self.assigned.remove(&address);
+    }
+
+    pub fn note_rssi(&mut self, address: BDAddr, rssi: i8) {
+        if let Some(index) = self.assigned_to(address) {
+            self.rssi.insert((address, index), rssi);
+        }
+    }
+
+    /// The adapter connected to the sensor, whether or not the sensor turned out to speak the protocol
+    pub fn on_connected(&mut self, address: BDAddr) {
+        if let Some(index) = self.assigned_to(address) {
+            let adapter = &mut self.adapters[index];
+            adapter.failures = 0;
+            adapter.retry_delay = FIRST_RETRY_DELAY;
+        }
+    }
+
+    /// Counts the connections the adapter failed to establish, returns the adapter which seems
+    /// to have stopped responding
+    pub fn on_connect_failed(&mut self, address: BDAddr) -> Option<usize> {
+        let index = self.assigned_to(address)?;
+        self.release(address);
+        let adapter = &mut self.adapters[index];
+        adapter.failures += 1;
+        if adapter.healthy && adapter.failures >= MAX_CONSECUTIVE_FAILURES {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Stops using the adapter, returns the sensors which have to be assigned elsewhere
+    pub fn fail(&mut self, index: usize) -> Vec<BDAddr> {
+        println!("Adapter {} stopped responding", self.name(index));
+        self.postpone(index);
+
+        let orphans: Vec<BDAddr> = self.assigned.iter()
+            .filter(|(_, &assigned)| assigned == index)
+            .map(|(&address, _)| address)
+            .collect();
+        for address in orphans.iter() {
+            self.release(*address);
+        }
+        orphans
+    }
+
+    /// Leaves the adapter unused until the next try, which is further away every time
+    pub fn postpone(&mut self, index: usize) {
+        let adapter = &mut self.adapters[index];
+        adapter.healthy = false;
+        adapter.retry_at = Some(Instant::now() + adapter.retry_delay);
+        adapter.retry_delay = (adapter.retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+
+    /// Unresponsive adapters whose wait is over
+    pub fn due_for_retry(&self, now: Instant) -> Vec<usize> {
+        (0..self.adapters.len())
+            .filter(|&index| !self.adapters[index].healthy)
+            .filter(|&index| self.adapters[index].retry_at.map_or(false, |retry_at| retry_at <= now))
+            .collect()
+    }
+
+    /// Uses the adapter again after it has been reset or connected to again
+    pub fn replace(&mut self, index: usize, central: C) {
+        let generation = self.adapters[index].generation + 1;
+        self.forward_events(index, generation, &central);
+        let adapter = &mut self.adapters[index];
+        adapter.generation = generation;
+        adapter.central = central;
+        adapter.healthy = true;
+        adapter.failures = 0;
+        adapter.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+
+    use btleplug::api::{Characteristic, CommandCallback, NotificationHandler, PeripheralProperties, RequestCallback, UUID};
+
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    struct MockPeripheral(BDAddr);
+
+    impl Peripheral for MockPeripheral {
+        fn address(&self) -> BDAddr { self.0 }
+        fn properties(&self) -> PeripheralProperties { PeripheralProperties::default() }
+        fn characteristics(&self) -> BTreeSet<Characteristic> { BTreeSet::new() }
+        fn is_connected(&self) -> bool { false }
+        fn connect(&self) -> btleplug::Result<()> { unimplemented!() }
+        fn disconnect(&self) -> btleplug::Result<()> { unimplemented!() }
+        fn discover_characteristics(&self) -> btleplug::Result<Vec<Characteristic>> { unimplemented!() }
+        fn discover_characteristics_in_range(&self, _: u16, _: u16) -> btleplug::Result<Vec<Characteristic>> { unimplemented!() }
+        fn command_async(&self, _: &Characteristic, _: &[u8], _: Option<CommandCallback>) { unimplemented!() }
+        fn command(&self, _: &Characteristic, _: &[u8]) -> btleplug::Result<()> { unimplemented!() }
+        fn request_async(&self, _: &Characteristic, _: &[u8], _: Option<RequestCallback>) { unimplemented!() }
+        fn request(&self, _: &Characteristic, _: &[u8]) -> btleplug::Result<Vec<u8>> { unimplemented!() }
+        fn read_async(&self, _: &Characteristic, _: Option<RequestCallback>) { unimplemented!() }
+        fn read(&self, _: &Characteristic) -> btleplug::Result<Vec<u8>> { unimplemented!() }
+        fn read_by_type_async(&self, _: &Characteristic, _: UUID, _: Option<RequestCallback>) { unimplemented!() }
+        fn read_by_type(&self, _: &Characteristic, _: UUID) -> btleplug::Result<Vec<u8>> { unimplemented!() }
+        fn subscribe(&self, _: &Characteristic) -> btleplug::Result<()> { unimplemented!() }
+        fn unsubscribe(&self, _: &Characteristic) -> btleplug::Result<()> { unimplemented!() }
+        fn on_notification(&self, _: NotificationHandler) { unimplemented!() }
+    }
+
+    /// Sees the one sensor, its events are sent through `events`
+    #[derive(Clone)]
+    struct MockCentral {
+        sensor: BDAddr,
+        receiver: Arc<Mutex<Option<mpsc::Receiver<CentralEvent>>>>
+    }
+
+    impl MockCentral {
+        fn new(sensor: BDAddr) -> (Self, mpsc::Sender<CentralEvent>) {
+            let (tx, rx) = mpsc::channel();
+            (MockCentral { sensor, receiver: Arc::new(Mutex::new(Some(rx))) }, tx)
+        }
+    }
+
+    impl Central<MockPeripheral> for MockCentral {
+        fn event_receiver(&self) -> Option<mpsc::Receiver<CentralEvent>> { self.receiver.lock().unwrap().take() }
+        fn start_scan(&self) -> btleplug::Result<()> { Ok(()) }
+        fn active(&self, _: bool) {}
+        fn filter_duplicates(&self, _: bool) {}
+        fn stop_scan(&self) -> btleplug::Result<()> { Ok(()) }
+        fn peripherals(&self) -> Vec<MockPeripheral> { vec![MockPeripheral(self.sensor)] }
+        fn peripheral(&self, address: BDAddr) -> Option<MockPeripheral> {
+            Some(MockPeripheral(address)).filter(|_| address == self.sensor)
+        }
+    }
+
+    fn sensor() -> BDAddr {
+        BDAddr { address: [1, 2, 3, 4, 5, 6] }
+    }
+
+    fn pool() -> (AdapterPool<MockPeripheral, MockCentral>, mpsc::Receiver<AdapterEvent>, mpsc::Sender<CentralEvent>) {
+        let (mut pool, events) = AdapterPool::new(AdapterBalance::FewestConnections);
+        let (central, sender) = MockCentral::new(sensor());
+        pool.add("hci0".to_string(), central, false);
+        (pool, events, sender)
+    }
+
+    #[test]
+    fn does_not_blame_the_adapter_for_devices_speaking_another_protocol() {
+        let (mut pool, _events, _sender) = pool();
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES * 2 {
+            assert!(pool.assign(sensor()).is_some());
+            pool.on_connected(sensor());
+            pool.release(sensor());
+        }
+
+        for _ in 1..MAX_CONSECUTIVE_FAILURES {
+            pool.assign(sensor());
+            assert_eq!(pool.on_connect_failed(sensor()), None);
+        }
+        pool.assign(sensor());
+        assert_eq!(pool.on_connect_failed(sensor()), Some(0));
+    }
+
+    #[test]
+    fn retries_an_unresponsive_adapter_it_does_not_own_after_a_back_off() {
+        let (mut pool, events, sender) = pool();
+        pool.fail(0);
+
+        assert!(pool.assign(sensor()).is_none());
+        sender.send(CentralEvent::DeviceDiscovered(sensor())).unwrap();
+        assert!(pool.receive(&events, Duration::from_secs(1)).is_none());
+        assert!(pool.due_for_retry(Instant::now()).is_empty());
+        assert_eq!(pool.due_for_retry(Instant::now() + FIRST_RETRY_DELAY), vec![0]);
+
+        let (central, sender) = MockCentral::new(sensor());
+        pool.replace(0, central);
+        assert!(pool.due_for_retry(Instant::now() + MAX_RETRY_DELAY).is_empty());
+        sender.send(CentralEvent::DeviceDiscovered(sensor())).unwrap();
+        assert!(matches!(pool.receive(&events, Duration::from_secs(1)), Some((0, Some(CentralEvent::DeviceDiscovered(_))))));
+        assert!(pool.assign(sensor()).is_some());
+
+        // Failing again soon after, the wait is longer
+        pool.fail(0);
+        assert!(pool.due_for_retry(Instant::now() + FIRST_RETRY_DELAY).is_empty());
+        assert_eq!(pool.due_for_retry(Instant::now() + FIRST_RETRY_DELAY * 2), vec![0]);
+    }
+}
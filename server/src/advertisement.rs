@@ -0,0 +1,80 @@
+use btleplug::api::PeripheralProperties;
+
+use crate::hci_monitor::{AdvertisingReport, ServiceData};
+use crate::sensor::{SensorFamily, SensorReading, TelemetryKind};
+
+/// What a peripheral broadcasts without being connected to
+#[derive(Clone, Debug, Default)]
+pub struct Advertisement {
+    /// Service data keyed by the 16-bit service UUID
+    pub service_data: ServiceData,
+    pub local_name: Option<String>,
+    /// Signal strength the service data was received with in dBm
+    pub rssi: Option<i8>
+}
+
+impl Advertisement {
+    /// btleplug 0.5 does not keep the service data and the RSSI in the properties, they come from the `ServiceDataMonitor`
+    pub fn new(properties: &PeripheralProperties, report: Option<AdvertisingReport>) -> Self {
+        let report = report.unwrap_or_default();
+        Advertisement {
+            service_data: report.service_data,
+            local_name: properties.local_name.clone(),
+            rssi: report.rssi
+        }
+    }
+
+    pub fn service_payload(&self, uuid: u16) -> Option<&[u8]> {
+        self.service_data.iter()
+            .find(|(service, _)| *service == uuid)
+            .map(|(_, data)| data.as_slice())
+    }
+}
+
+/// Readings decoded from a single advertisement
+pub struct BroadcastReadings {
+    /// Changes whenever the sensor takes new readings, the same advertisement is repeated many times
+    pub counter: Option<u32>,
+    pub readings: Vec<SensorReading>,
+    pub telemetry: Vec<(TelemetryKind, f32)>
+}
+
+/// Support for a family of sensors which broadcast their readings instead of being polled
+pub trait AdvertisementDecoder {
+    fn family(&self) -> SensorFamily;
+    fn decode(&self, advertisement: &Advertisement) -> Option<BroadcastReadings>;
+
+    /// Tells, e.g. by the name, a sensor of the family whose advertisement cannot be decoded (yet),
+    /// so that it is not connected to instead
+    fn recognizes(&self, _advertisement: &Advertisement) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_service_data_and_rssi_from_the_report() {
+        let properties = PeripheralProperties {
+            local_name: Some("ATC_123456".to_string()),
+            ..Default::default()
+        };
+        let report = AdvertisingReport {
+            service_data: vec![(0x181A, vec![1, 2, 3])],
+            rssi: Some(-70),
+            ..Default::default()
+        };
+
+        let advertisement = Advertisement::new(&properties, Some(report));
+        assert_eq!(advertisement.local_name.as_deref(), Some("ATC_123456"));
+        assert_eq!(advertisement.service_payload(0x181A), Some(&[1, 2, 3][..]));
+        assert_eq!(advertisement.service_payload(0xFCD2), None);
+        assert_eq!(advertisement.rssi, Some(-70));
+
+        let advertisement = Advertisement::new(&properties, None);
+        assert!(advertisement.service_data.is_empty());
+        assert_eq!(advertisement.rssi, None);
+    }
+}
@@ -1,11 +1,133 @@
 use btleplug::api::{Characteristic, Peripheral, UUID};
+use std::cell::Cell;
 use std::sync::mpsc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crate::driver::{ConnectedSensor, ProbeError, SensorCommand, SensorDriver, SensorDriverError};
+use crate::sensor::{SensorFamily, SensorReading};
 
 pub struct AlphaSensor<P: Peripheral> {
     pub peripheral: P,
     characteristic: Characteristic,
     data_receiver: mpsc::Receiver<Vec<u8>>,
+    protocol: AlphaProtocol,
+    format: AlphaFrameFormat,
+    /// Commands and responses are wrapped in checksummed frames
+    framed: bool,
+    sequence: Cell<u8>,
+}
+
+const HELLO_MAGIC: [u8; 4] = [0x00, 0xF0, 0x14, 0x4D];
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Capability bits announced by v2+ firmware in the hello response
+pub const CAPABILITY_FRACTIONAL_READ: u16 = 1 << 0;
+pub const CAPABILITY_FRAMED: u16 = 1 << 1;
+pub const CAPABILITY_SUPPLY_VOLTAGE: u16 = 1 << 2;
+pub const CAPABILITY_CONFIG: u16 = 1 << 3;
+
+const NAME_PREFIX: &str = "Weather";
+/// Longest argument which still fits a framed command into a single write
+const MAX_ARGUMENT_LENGTH: usize = 15;
+const PIN_LENGTH: usize = 6;
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Framed mode: `[0xA5, length, sequence, payload..., crc8]`, the checksum covers
+/// the length, sequence and payload. The payload of a response is the plain response.
+const FRAME_START: u8 = 0xA5;
+const FRAME_OVERHEAD: usize = 4;
+
+/// CRC-8 with the 0x07 polynomial and zero initial value (CRC-8/SMBUS)
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Protocol spoken by the firmware. v1 devices reply to hello with the magic only,
+/// newer ones append the version and the capabilities (u16 LE).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AlphaProtocol {
+    pub version: u8,
+    pub capabilities: u16,
+}
+
+impl AlphaProtocol {
+    const V1: AlphaProtocol = AlphaProtocol { version: 1, capabilities: 0 };
+
+    fn from_hello(data: &[u8]) -> Option<Self> {
+        if !data.starts_with(&HELLO_MAGIC) {
+            return None;
+        }
+        match data.len() {
+            4 => Some(Self::V1),
+            n if n >= 7 => Some(AlphaProtocol {
+                version: data[4],
+                capabilities: u16::from_le_bytes([data[5], data[6]]),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn supports(&self, capability: u16) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    /// The richest read frame both sides understand
+    fn negotiate(&self) -> AlphaFrameFormat {
+        if self.supports(CAPABILITY_FRACTIONAL_READ) {
+            AlphaFrameFormat::Fractional
+        } else {
+            AlphaFrameFormat::V1
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AlphaFrameFormat {
+    /// `0x66` answered with `[status, temperature i8, humidity u8, 0]`
+    V1,
+    /// `0x67` answered with `[status, temperature i16 LE, humidity u16 LE]` in tenths
+    Fractional,
+}
+
+impl AlphaFrameFormat {
+    fn command(&self) -> u8 {
+        match self {
+            AlphaFrameFormat::V1 => 0x66,
+            AlphaFrameFormat::Fractional => 0x67,
+        }
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<AlphaSensorReading, AlphaSensorPollError> {
+        let expected_length = match self {
+            AlphaFrameFormat::V1 => 4,
+            AlphaFrameFormat::Fractional => 5,
+        };
+        if data.len() != expected_length {
+            return Err(AlphaSensorPollError::UnexpectedResponse);
+        }
+        if data[0] != 0x00u8 {
+            return Err(AlphaSensorPollError::SensorError);
+        }
+
+        Ok(match self {
+            AlphaFrameFormat::V1 => AlphaSensorReading {
+                temperature: i8::from_le_bytes([data[1]]) as f32,
+                humidity: data[2] as f32,
+            },
+            AlphaFrameFormat::Fractional => AlphaSensorReading {
+                temperature: i16::from_le_bytes([data[1], data[2]]) as f32 / 10.0,
+                humidity: u16::from_le_bytes([data[3], data[4]]) as f32 / 10.0,
+            },
+        })
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -14,15 +136,22 @@ pub enum AlphaSensorPollError {
     SensorError,
     Timeout,
     SendFailed,
+    /// The framed response was corrupted on the way
+    ChecksumMismatch,
+    /// The framed response answers an earlier command
+    StaleFrame,
+    /// The firmware did not accept the command or its arguments
+    Rejected,
 }
 
 pub struct AlphaSensorReading {
-    pub temperature: i8,
-    pub humidity: u8,
+    pub temperature: f32,
+    pub humidity: f32,
 }
 
 impl<P: Peripheral> AlphaSensor<P> {
-    pub fn try_new(peripheral: P, characteristic: Characteristic) -> Option<Self> {
+    /// `allow_framed` enables the framed mode when the firmware supports it
+    pub fn try_new(peripheral: P, characteristic: Characteristic, allow_framed: bool) -> Option<Self> {
         let (tx, rx) = mpsc::channel();
         let notification_characteristic = characteristic.clone();
         peripheral.on_notification(Box::new(move |notification| {
@@ -33,18 +162,32 @@ impl<P: Peripheral> AlphaSensor<P> {
             }
         }));
 
-        if Self::check_hello(&peripheral, &characteristic, &rx) {
-            Some(AlphaSensor {
-                peripheral,
-                characteristic,
-                data_receiver: rx,
-            })
-        } else {
-            None
-        }
+        let protocol = Self::check_hello(&peripheral, &characteristic, &rx)?;
+        let format = protocol.negotiate();
+        let framed = allow_framed && protocol.supports(CAPABILITY_FRAMED);
+        println!(
+            "{} speaks Alpha protocol v{} (capabilities {:#06x}), using {:?} frames{}",
+            peripheral.address(),
+            protocol.version,
+            protocol.capabilities,
+            format,
+            if framed { " with checksums" } else { "" }
+        );
+
+        Some(AlphaSensor {
+            peripheral,
+            characteristic,
+            data_receiver: rx,
+            protocol,
+            format,
+            framed,
+            sequence: Cell::new(0),
+        })
     }
 
-    pub fn inspect(peripheral: &P) -> Option<Characteristic> {
+    /// Connects to the peripheral and finds its UART characteristic. Only a failed connection
+    /// is blamed on the adapter, a device without the characteristic does not speak the protocol.
+    pub fn inspect(peripheral: &P) -> Result<Characteristic, ProbeError> {
         println!(
             "Connecting to {} {}...",
             peripheral.address(),
@@ -53,12 +196,13 @@ impl<P: Peripheral> AlphaSensor<P> {
 
         peripheral
             .connect()
+            .map_err(|_| ProbeError::Connect)
             .and_then(|_| {
                 println!("Discovering characteristics of {}...", peripheral.address());
-                peripheral.discover_characteristics()
+                peripheral.discover_characteristics().map_err(|_| ProbeError::Protocol)
             })
             .map_or_else(
-                |_| {
+                |err| {
                     println!("Disconnecting {}...", peripheral.address());
                     if let Err(err) = peripheral.disconnect() {
                         println!(
@@ -67,13 +211,14 @@ impl<P: Peripheral> AlphaSensor<P> {
                             err
                         );
                     }
-                    None
+                    Err(err)
                 },
                 |characteristics| {
                     characteristics
                         .iter()
                         .find(|c| c.uuid == UUID::B16(0xFFE1))
                         .map(|c| c.clone())
+                        .ok_or(ProbeError::Protocol)
                 },
             )
     }
@@ -82,57 +227,239 @@ impl<P: Peripheral> AlphaSensor<P> {
         peripheral: &P,
         characteristic: &Characteristic,
         rx: &mpsc::Receiver<Vec<u8>>,
-    ) -> bool {
-        if let Err(_) = peripheral.command(characteristic, &[0x10u8]) {
-            return false;
+    ) -> Option<AlphaProtocol> {
+        if peripheral.command(characteristic, &[0x10u8]).is_err() {
+            return None;
         }
         match rx.recv_timeout(Duration::from_secs(5)) {
             Ok(data) => {
-                if data.eq(&vec![0x00u8, 0xF0u8, 0x14u8, 0x4Du8]) {
-                    true
-                } else {
+                let protocol = AlphaProtocol::from_hello(&data);
+                if protocol.is_none() {
                     println!("Hello data did not match! {:?}", data);
-                    false
                 }
+                protocol
             }
             Err(_) => {
                 println!("Hello timeout");
-                false
+                None
             }
         }
     }
 
     pub fn poll(&self) -> Result<AlphaSensorReading, AlphaSensorPollError> {
-        if let Err(_) = self.peripheral.command(&self.characteristic, &[0x66u8]) {
+        let data = self.transact(self.format.command(), &[])?;
+        self.format.parse(&data)
+    }
+
+    /// `0x20` answered with `[status, millivolts u16 LE]`
+    pub fn supply_voltage(&self) -> Result<f32, AlphaSensorPollError> {
+        let data = self.transact(0x20, &[])?;
+        if data.len() != 3 {
+            return Err(AlphaSensorPollError::UnexpectedResponse);
+        }
+        if data[0] != 0x00u8 {
+            return Err(AlphaSensorPollError::SensorError);
+        }
+        Ok(u16::from_le_bytes([data[1], data[2]]) as f32 / 1000.0)
+    }
+
+    /// Stored in the EEPROM, the BLE module advertises it after a reboot.
+    /// Has to start with "Weather", otherwise the sensor would not be recognized anymore.
+    pub fn set_name(&self, name: &str) -> Result<(), AlphaSensorPollError> {
+        self.configure(0x30, name.as_bytes())
+    }
+
+    /// Six digits, used by the BLE module after a reboot
+    pub fn set_pin(&self, pin: &str) -> Result<(), AlphaSensorPollError> {
+        self.configure(0x31, pin.as_bytes())
+    }
+
+    /// Pause after every poll, at most a minute
+    pub fn set_sleep(&self, sleep: Duration) -> Result<(), AlphaSensorPollError> {
+        let millis = sleep.as_millis().min(u16::MAX as u128) as u16;
+        self.configure(0x32, &millis.to_le_bytes())
+    }
+
+    /// The device answers first and reboots afterwards, so the connection is lost
+    pub fn reboot(&self) -> Result<(), AlphaSensorPollError> {
+        self.configure(0x33, &[])
+    }
+
+    /// Blinks the read LED for a few seconds
+    pub fn identify(&self) -> Result<(), AlphaSensorPollError> {
+        self.configure(0x34, &[])
+    }
+
+    fn configure(&self, command: u8, arguments: &[u8]) -> Result<(), AlphaSensorPollError> {
+        let data = self.transact(command, arguments)?;
+        match data.first() {
+            Some(0x00) => Ok(()),
+            Some(0x02) => Err(AlphaSensorPollError::Rejected),
+            Some(_) => Err(AlphaSensorPollError::SensorError),
+            None => Err(AlphaSensorPollError::UnexpectedResponse),
+        }
+    }
+
+    /// Sends the command and returns the plain response, unwrapped from its frame if needed.
+    /// Arguments follow the command as `[length, arguments...]` in the plain mode.
+    fn transact(&self, command: u8, arguments: &[u8]) -> Result<Vec<u8>, AlphaSensorPollError> {
+        // Late replies to the commands which timed out must not be taken for this response
+        let stale = self.data_receiver.try_iter().count();
+        if stale > 0 {
+            println!("Dropped {} stale notifications from {}", stale, self.peripheral.address());
+        }
+
+        if !self.framed {
+            let mut request = vec![command];
+            if !arguments.is_empty() {
+                request.push(arguments.len() as u8);
+                request.extend_from_slice(arguments);
+            }
+            if self.peripheral.command(&self.characteristic, &request).is_err() {
+                return Err(AlphaSensorPollError::SendFailed);
+            }
+            return self.data_receiver
+                .recv_timeout(RESPONSE_TIMEOUT)
+                .map_err(|_| AlphaSensorPollError::Timeout);
+        }
+
+        let sequence = self.sequence.get().wrapping_add(1);
+        self.sequence.set(sequence);
+
+        let mut request = vec![FRAME_START, 1 + arguments.len() as u8, sequence, command];
+        request.extend_from_slice(arguments);
+        request.push(crc8(&request[1..]));
+        if self.peripheral.command(&self.characteristic, &request).is_err() {
             return Err(AlphaSensorPollError::SendFailed);
         }
-        self.data_receiver
-            .recv_timeout(Duration::from_secs(5))
-            .map_or(Err(AlphaSensorPollError::Timeout), |data| {
-                if data.len() == 4 {
-                    if data[0] == 0x00u8 {
-                        let temperature = i8::from_le_bytes([data[1]]);
-                        let humidity = data[2];
-                        Ok(AlphaSensorReading {
-                            temperature,
-                            humidity,
-                        })
-                    } else {
-                        Err(AlphaSensorPollError::SensorError)
-                    }
-                } else {
-                    Err(AlphaSensorPollError::UnexpectedResponse)
+
+        let frame = self.receive_frame()?;
+        Self::unframe(&frame, sequence)
+    }
+
+    /// The module may split a frame into several notifications
+    fn receive_frame(&self) -> Result<Vec<u8>, AlphaSensorPollError> {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut frame = Vec::new();
+
+        while frame.len() < 2 || frame.len() < frame[1] as usize + FRAME_OVERHEAD {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let data = self.data_receiver
+                .recv_timeout(remaining)
+                .map_err(|_| AlphaSensorPollError::Timeout)?;
+            frame.extend(data);
+        }
+
+        Ok(frame)
+    }
+
+    fn unframe(frame: &[u8], sequence: u8) -> Result<Vec<u8>, AlphaSensorPollError> {
+        let length = frame[1] as usize;
+        if frame[0] != FRAME_START || frame.len() != length + FRAME_OVERHEAD {
+            return Err(AlphaSensorPollError::UnexpectedResponse);
+        }
+        if crc8(&frame[1..length + 3]) != frame[length + 3] {
+            return Err(AlphaSensorPollError::ChecksumMismatch);
+        }
+        if frame[2] != sequence {
+            return Err(AlphaSensorPollError::StaleFrame);
+        }
+
+        Ok(frame[3..length + 3].to_vec())
+    }
+}
+
+impl From<AlphaSensorPollError> for SensorDriverError {
+    fn from(err: AlphaSensorPollError) -> Self {
+        match err {
+            AlphaSensorPollError::SendFailed => SensorDriverError::SendFailed,
+            AlphaSensorPollError::Rejected => SensorDriverError::InvalidArgument("Rejected by the firmware".to_string()),
+            err => SensorDriverError::Failed(format!("{:?}", err))
+        }
+    }
+}
+
+impl<P: Peripheral> ConnectedSensor<P> for AlphaSensor<P> {
+    fn peripheral(&self) -> &P {
+        &self.peripheral
+    }
+
+    fn family(&self) -> SensorFamily {
+        SensorFamily::Alpha
+    }
+
+    fn poll(&self) -> Result<Vec<SensorReading>, SensorDriverError> {
+        let reading = AlphaSensor::poll(self)?;
+        Ok(vec![
+            SensorReading::Temperature(reading.temperature),
+            SensorReading::Humidity(reading.humidity)
+        ])
+    }
+
+    fn command(&self, command: &SensorCommand) -> Result<(), SensorDriverError> {
+        if *command != SensorCommand::Ping && !self.protocol.supports(CAPABILITY_CONFIG) {
+            return Err(SensorDriverError::Unsupported);
+        }
+
+        match command {
+            SensorCommand::Ping => match Self::check_hello(&self.peripheral, &self.characteristic, &self.data_receiver) {
+                Some(protocol) if protocol == self.protocol => Ok(()),
+                // Reflashed in the meantime, the frame format has to be negotiated again
+                Some(protocol) => Err(SensorDriverError::Failed(format!("Protocol changed to {:?}", protocol))),
+                None => Err(SensorDriverError::Failed("No hello response".to_string()))
+            },
+            SensorCommand::SetName(name) => {
+                if !name.starts_with(NAME_PREFIX) || name.len() > MAX_ARGUMENT_LENGTH || !name.is_ascii() {
+                    return Err(SensorDriverError::InvalidArgument(format!(
+                        "The name has to start with {} and have at most {} ASCII characters", NAME_PREFIX, MAX_ARGUMENT_LENGTH)));
                 }
-            })
+                Ok(self.set_name(name)?)
+            },
+            SensorCommand::SetPin(pin) => {
+                if pin.len() != PIN_LENGTH || !pin.bytes().all(|digit| digit.is_ascii_digit()) {
+                    return Err(SensorDriverError::InvalidArgument(format!("The PIN has to have {} digits", PIN_LENGTH)));
+                }
+                Ok(self.set_pin(pin)?)
+            },
+            SensorCommand::SetSleep(sleep) => {
+                if *sleep > MAX_SLEEP {
+                    return Err(SensorDriverError::InvalidArgument(format!("The sleep can be at most {}s", MAX_SLEEP.as_secs())));
+                }
+                Ok(self.set_sleep(*sleep)?)
+            },
+            SensorCommand::Reboot => Ok(self.reboot()?),
+            SensorCommand::Identify => Ok(self.identify()?)
+        }
+    }
+
+    fn supply_voltage(&self) -> Result<f32, SensorDriverError> {
+        if !self.protocol.supports(CAPABILITY_SUPPLY_VOLTAGE) {
+            return Err(SensorDriverError::Unsupported);
+        }
+        Ok(AlphaSensor::supply_voltage(self)?)
     }
 }
 
-impl<P: Peripheral> Drop for AlphaSensor<P> {
-    fn drop(&mut self) {
-        println!(
-            "Disconnecting dropped sensor {}...",
-            self.peripheral.address()
-        );
-        let _ = self.peripheral.disconnect();
+/// Sensors running the `weather` firmware, advertised as "Weather..."
+pub struct AlphaDriver {
+    /// Use the checksummed frames with the firmware which supports them
+    pub framed: bool,
+}
+
+impl<P: Peripheral + 'static> SensorDriver<P> for AlphaDriver {
+    fn family(&self) -> SensorFamily {
+        SensorFamily::Alpha
+    }
+
+    fn identify(&self, peripheral: &P) -> bool {
+        peripheral.properties().local_name.map_or(false, |name| name.contains("Weather"))
+    }
+
+    fn probe(&self, peripheral: P) -> Result<Box<dyn ConnectedSensor<P>>, ProbeError> {
+        let characteristic = AlphaSensor::inspect(&peripheral)?;
+        println!("Found characteristics in {}", peripheral.address());
+        AlphaSensor::try_new(peripheral, characteristic, self.framed)
+            .map(|sensor| Box::new(sensor) as Box<dyn ConnectedSensor<P>>)
+            .ok_or(ProbeError::Protocol)
     }
 }
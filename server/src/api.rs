@@ -1,9 +1,19 @@
-use actix_web::{get, web, HttpResponse, Responder};
-use chrono::Utc;
+use std::sync::RwLock;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use crate::{SensorStatus, SensorsState, StatePtr, database::{Database, DatabaseError}};
+use crate::calibration::{self, Calibration};
+use crate::commands::{CommandError, CommandQueue};
+use crate::config::TelemetryConfig;
+use crate::derived;
+use crate::driver::{SensorCommand, SensorDriverError};
+use crate::export::{self, ReadingsFormat, ReadingsLayout};
+use crate::ingest::{self, IngestBatch};
+use crate::sensor::{BatteryStatus, DeviceStatus, Sensor, TelemetryKind, TimestampedSensorReading, TimestampedTelemetry};
 
-fn map_database_error_to_http(err: DatabaseError) -> HttpResponse {
+pub(crate) fn map_database_error_to_http(err: DatabaseError) -> HttpResponse {
     match err {
         DatabaseError::Busy => HttpResponse::ServiceUnavailable().body("Database connection failed"),
         DatabaseError::NotFound => HttpResponse::NotFound().body("{}"),
@@ -12,15 +22,113 @@ fn map_database_error_to_http(err: DatabaseError) -> HttpResponse {
     }
 }
 
-fn map_db_call_to_http_response<R: serde::Serialize>(db_result: Result<R, DatabaseError>) -> HttpResponse {
+pub(crate) fn map_db_call_to_http_response<R: serde::Serialize>(db_result: Result<R, DatabaseError>) -> HttpResponse {
     match db_result {
         Ok(result) => HttpResponse::Ok().json(result),
         Err(err) => map_database_error_to_http(err)
     }
 }
 
-#[get("/status")]
-pub async fn status() -> impl Responder {
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<String>,
+    #[serde(default)]
+    wide: bool,
+    /// Adds dew point, heat index and absolute humidity to the long layout
+    #[serde(default)]
+    derived: bool,
+    /// Skips the sensor calibration
+    #[serde(default)]
+    raw: bool,
+    /// Keeps the readings flagged as implausible
+    #[serde(default)]
+    include_suspect: bool
+}
+
+#[derive(Deserialize)]
+pub struct LatestQuery {
+    #[serde(default)]
+    raw: bool,
+    #[serde(default)]
+    include_suspect: bool
+}
+
+pub(crate) fn without_suspect(include_suspect: bool, mut readings: Vec<TimestampedSensorReading>) -> Vec<TimestampedSensorReading> {
+    if !include_suspect {
+        readings.retain(|reading| !reading.quality.is_suspect());
+    }
+    readings
+}
+
+pub(crate) fn calibrated<D: Database>(
+    db: &D,
+    handle: &D::SensorHandle,
+    raw: bool,
+    db_result: Result<Vec<TimestampedSensorReading>, DatabaseError>)
+-> Result<Vec<TimestampedSensorReading>, DatabaseError> {
+
+    if raw {
+        return db_result;
+    }
+
+    let readings = db_result?;
+    let calibrations = db.get_calibrations(handle)?;
+    if calibrations.is_empty() {
+        return Ok(readings);
+    }
+    let kinds = db.get_reading_kinds()?;
+    Ok(calibration::calibrate_all(&calibrations, &kinds, readings))
+}
+
+fn map_readings_to_http_response<D: Database>(
+    db: &D,
+    handle: &D::SensorHandle,
+    query: &ExportQuery,
+    request: &HttpRequest,
+    db_result: Result<Vec<TimestampedSensorReading>, DatabaseError>)
+-> HttpResponse {
+
+    let accept = request.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    let format = match ReadingsFormat::negotiate(query.format.as_deref(), accept) {
+        Ok(format) => format,
+        Err(msg) => return HttpResponse::BadRequest().body(msg)
+    };
+    let layout = if query.wide { ReadingsLayout::Wide } else { ReadingsLayout::Long };
+    // The wide layout has a column for the temperature and the humidity only
+    if query.derived && layout == ReadingsLayout::Wide {
+        return HttpResponse::BadRequest().body("The derived readings are only available in the long layout");
+    }
+    let db_result = db_result.map(|readings| without_suspect(query.include_suspect, readings));
+    let db_result = calibrated(db, handle, query.raw, db_result);
+    let db_result = if query.derived {
+        db_result.map(derived::with_derived)
+    } else {
+        db_result
+    };
+
+    if format == ReadingsFormat::Json && layout == ReadingsLayout::Long {
+        return map_db_call_to_http_response(db_result);
+    }
+
+    let readings = match db_result {
+        Ok(readings) => readings,
+        Err(err) => return map_database_error_to_http(err)
+    };
+    let sensor = match db.get_sensor_by_handle(handle) {
+        Ok(sensor) => sensor,
+        Err(err) => return map_database_error_to_http(err)
+    };
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(export::stream(format, layout, sensor.address, readings))
+}
+
+//#[get("/status")]
+pub async fn status() -> HttpResponse {
     HttpResponse::Ok().body("Server is up and running!")
 }
 
@@ -28,59 +136,172 @@ pub async fn not_found() -> HttpResponse {
     HttpResponse::NotFound().body("<html><head><title>Not found</title><body><h1>404</h1></html>")
 }
 
+//#[get("/api/kinds")]
+pub async fn reading_kinds<D: Database>(db: web::Data<D>) -> HttpResponse {
+    map_db_call_to_http_response(db.get_reading_kinds()
+        .map(|mut kinds| {
+            kinds.extend(derived::derived_kinds());
+            kinds
+        }))
+}
+
 //#[get("/list")]
 pub async fn sensors_list<D: Database>(db: web::Data<D>)  -> HttpResponse {
     map_db_call_to_http_response(db.get_sensors())
 }
 
+fn latest_calibrated<D: Database>(db: &D, handle: &D::SensorHandle, kind: String, query: &LatestQuery)
+    -> Result<TimestampedSensorReading, DatabaseError> {
+
+    let latest = db.get_latest_reading(handle, kind, query.include_suspect)
+        .map(|reading| vec![reading]);
+    calibrated(db, handle, query.raw, latest)
+        .and_then(|mut readings| readings.pop().ok_or(DatabaseError::NotFound))
+}
+
 //#[get("/{id}/latest/{type}")]
 pub async fn sensor_latest_reading<D: Database>(
     request: web::Path<(D::SensorHandle, String)>,
+    query: web::Query<LatestQuery>,
     db: web::Data<D>) 
 -> HttpResponse {
     let handle = request.0.0;
     let kind = request.0.1;
+    let db = db.get_ref();
 
-    map_db_call_to_http_response(db.get_latest_reading(&handle, kind))
+    if derived::is_derived(&kind) {
+        let latest = latest_calibrated(db, &handle, "T".to_string(), &query)
+            .and_then(|temperature| latest_calibrated(db, &handle, "H".to_string(), &query)
+                .map(|humidity| (temperature, humidity)))
+            .and_then(|(temperature, humidity)| derived::latest(&kind, &temperature, &humidity)
+                .ok_or(DatabaseError::NotFound));
+        return map_db_call_to_http_response(latest);
+    }
+
+    map_db_call_to_http_response(latest_calibrated(db, &handle, kind, &query))
 }
 
-//#[get("/{id}")]
-pub async fn sensor_status<D: Database, S: SensorsState>(request: web::Path<D::SensorHandle>, db: web::Data<D>, state: web::Data<StatePtr<S>>) -> HttpResponse {
+//#[get("/{id}/calibrations")]
+pub async fn sensor_calibrations<D: Database>(request: web::Path<D::SensorHandle>, db: web::Data<D>) -> HttpResponse {
     let handle = request.0;
+    map_db_call_to_http_response(db.get_calibrations(&handle))
+}
 
-    #[derive(Serialize, Deserialize)]
-    pub struct StatusResponse {
-        status: SensorStatus,
+//#[post("/{id}/calibrations")]
+pub async fn add_sensor_calibration<D: Database>(
+    request: web::Path<D::SensorHandle>,
+    calibration: web::Json<Calibration>,
+    db: web::Data<D>)
+-> HttpResponse {
+    let handle = request.0;
+
+    match calibration::is_calibratable(&**db, &calibration.kind) {
+        Ok(true) => {},
+        Ok(false) => return HttpResponse::BadRequest().body(format!("Cannot calibrate readings of kind {}", calibration.kind)),
+        Err(err) => return map_database_error_to_http(err)
+    }
+
+    let result = db.get_sensor_by_handle(&handle)
+        .and_then(|_| db.add_calibration(&handle, &calibration));
+    match result {
+        Ok(()) => HttpResponse::Created().json(calibration.0),
+        Err(err) => map_database_error_to_http(err)
     }
+}
+
+#[derive(Deserialize)]
+pub struct TelemetryQuery {
+    kind: Option<TelemetryKind>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub status: SensorStatus,
+    pub battery: BatteryStatus,
+    pub battery_voltage: Option<TimestampedTelemetry>,
+    pub rssi: Option<TimestampedTelemetry>
+}
+
+pub(crate) fn status_of<D: Database, S: SensorsState>(
+    db: &D,
+    handle: &D::SensorHandle,
+    sensor: &Sensor,
+    state: &StatePtr<S>,
+    telemetry: &RwLock<TelemetryConfig>)
+-> StatusResponse {
+    let sensor_status = state.read().unwrap().get_status(sensor);
+    let battery_voltage = db.get_latest_telemetry(handle, TelemetryKind::BatteryVoltage).ok();
+    let battery = match &battery_voltage {
+        Some(voltage) if voltage.value.0 < telemetry.read().expect("Poisoned RwLock").low_battery_voltage => BatteryStatus::Low,
+        Some(_) => BatteryStatus::Ok,
+        None => BatteryStatus::Unknown
+    };
+    let rssi = db.get_latest_telemetry(handle, TelemetryKind::Rssi).ok();
+
+    StatusResponse { status: sensor_status, battery, battery_voltage, rssi }
+}
+
+//#[get("/{id}")]
+pub async fn sensor_status<D: Database, S: SensorsState>(
+    request: web::Path<D::SensorHandle>,
+    db: web::Data<D>,
+    state: web::Data<StatePtr<S>>,
+    telemetry: web::Data<RwLock<TelemetryConfig>>)
+-> HttpResponse {
+    let handle = request.0;
 
     match db.get_sensor_by_handle(&handle) {
-        Ok(sensor) => {
-            let state = state.read().unwrap();
-            HttpResponse::Ok().json(StatusResponse { status: state.get_status(&sensor) })
-        },
+        Ok(sensor) => HttpResponse::Ok().json(status_of(db.get_ref(), &handle, &sensor, &state, &telemetry)),
         Err(err) => map_database_error_to_http(err)
     }
 }
 
+//#[get("/{id}/telemetry")]
+pub async fn sensor_telemetry<D: Database>(
+    request: web::Path<D::SensorHandle>,
+    query: web::Query<TelemetryQuery>,
+    db: web::Data<D>)
+-> HttpResponse {
+    let handle = request.0;
+    let result = db.get_sensor_by_handle(&handle)
+        .and_then(|_| db.get_telemetry_between(&handle,
+            query.kind,
+            query.from.map(|from| from.naive_utc()),
+            query.to.map(|to| to.naive_utc())));
+    map_db_call_to_http_response(result)
+}
+
 //#[get("/{id}/readings")]
-pub async fn sensor_readings<D: Database>(request: web::Path<D::SensorHandle>, db: web::Data<D>) -> HttpResponse {
+pub async fn sensor_readings<D: Database>(
+    request: web::Path<D::SensorHandle>,
+    query: web::Query<ExportQuery>,
+    http_request: HttpRequest,
+    db: web::Data<D>)
+    -> HttpResponse {
+
     let handle = request.0;
-    map_db_call_to_http_response(db.get_readings(&handle))
+    map_readings_to_http_response(db.get_ref(), &handle, &query, &http_request, db.get_readings(&handle))
 }
 
 //#[get("/{id}/readings/after/{timestamp}")]
 pub async fn sensor_readings_after_time<D: Database>(
     request: web::Path<(D::SensorHandle, chrono::NaiveDateTime)>,
+    query: web::Query<ExportQuery>,
+    http_request: HttpRequest,
     db: web::Data<D>)
     -> HttpResponse {
 
     let handle = request.0.0;
     let date = request.0.1;
-    map_db_call_to_http_response(db.get_readings_after(&handle, date))
+    map_readings_to_http_response(db.get_ref(), &handle, &query, &http_request, db.get_readings_after(&handle, date))
 }
 
 pub async fn sensor_readings_after_time_utc<D: Database>(
     request: web::Path<(D::SensorHandle, chrono::DateTime<Utc>)>,
+    query: web::Query<ExportQuery>,
+    http_request: HttpRequest,
     db: web::Data<D>)
     -> HttpResponse {
 
@@ -92,5 +313,107 @@ pub async fn sensor_readings_after_time_utc<D: Database>(
         .unwrap()
         .naive_utc();
 
-    map_db_call_to_http_response(db.get_readings_after(&handle, time))
+    map_readings_to_http_response(db.get_ref(), &handle, &query, &http_request, db.get_readings_after(&handle, time))
+}
+
+#[derive(Deserialize)]
+pub struct SensorConfigRequest {
+    name: Option<String>,
+    pin: Option<String>,
+    sleep_ms: Option<u64>,
+    #[serde(default)]
+    reboot: bool,
+    #[serde(default)]
+    identify: bool
+}
+
+impl SensorConfigRequest {
+    /// The reboot goes last so that the other settings are stored by then
+    fn commands(&self) -> Vec<SensorCommand> {
+        let mut commands = Vec::new();
+        if self.identify {
+            commands.push(SensorCommand::Identify);
+        }
+        if let Some(name) = &self.name {
+            commands.push(SensorCommand::SetName(name.clone()));
+        }
+        if let Some(pin) = &self.pin {
+            commands.push(SensorCommand::SetPin(pin.clone()));
+        }
+        if let Some(sleep_ms) = self.sleep_ms {
+            commands.push(SensorCommand::SetSleep(std::time::Duration::from_millis(sleep_ms)));
+        }
+        if self.reboot {
+            commands.push(SensorCommand::Reboot);
+        }
+        commands
+    }
+}
+
+//#[post("/{id}/config")]
+pub async fn configure_sensor<D: Database>(
+    request: web::Path<D::SensorHandle>,
+    config: web::Json<SensorConfigRequest>,
+    db: web::Data<D>,
+    queue: web::Data<CommandQueue>)
+-> HttpResponse {
+    let handle = request.0;
+
+    let sensor = match db.get_sensor_by_handle(&handle) {
+        Ok(sensor) => sensor,
+        Err(err) => return map_database_error_to_http(err)
+    };
+
+    let commands = config.commands();
+    if commands.is_empty() {
+        return HttpResponse::BadRequest().body("Nothing to configure");
+    }
+
+    match queue.submit(sensor.address, commands).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(CommandError::Offline) => HttpResponse::Conflict().body("The sensor is not connected"),
+        Err(CommandError::Driver(SensorDriverError::Unsupported)) =>
+            HttpResponse::NotImplemented().body("The sensor firmware does not support remote configuration"),
+        Err(CommandError::Driver(SensorDriverError::InvalidArgument(message))) => HttpResponse::BadRequest().body(message),
+        Err(CommandError::Driver(err)) => HttpResponse::BadGateway().body(format!("The sensor did not respond: {:?}", err)),
+        Err(CommandError::Dropped) => HttpResponse::ServiceUnavailable().body("BLE is not running")
+    }
+}
+
+//#[get("/discovered")]
+pub async fn discovered_devices<D: Database>(db: web::Data<D>) -> HttpResponse {
+    map_db_call_to_http_response(db.get_devices(Some(DeviceStatus::Pending)))
+}
+
+#[derive(Deserialize)]
+pub struct DevicesQuery {
+    status: Option<DeviceStatus>
+}
+
+//#[get("/list")]
+pub async fn devices_list<D: Database>(query: web::Query<DevicesQuery>, db: web::Data<D>) -> HttpResponse {
+    map_db_call_to_http_response(db.get_devices(query.status))
+}
+
+fn set_device_status<D: Database>(db: &D, address: &str, device_status: DeviceStatus) -> HttpResponse {
+    match db.set_device_status(address, device_status) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => map_database_error_to_http(err)
+    }
+}
+
+//#[post("/{address}/adopt")]
+pub async fn adopt_device<D: Database>(request: web::Path<String>, db: web::Data<D>) -> HttpResponse {
+    set_device_status(&**db, &request.0, DeviceStatus::Adopted)
+}
+
+//#[post("/{address}/ignore")]
+pub async fn ignore_device<D: Database>(request: web::Path<String>, db: web::Data<D>) -> HttpResponse {
+    set_device_status(&**db, &request.0, DeviceStatus::Ignored)
+}
+
+//#[post("/api/ingest")]
+pub async fn ingest<D: Database>(batch: web::Json<IngestBatch>, db: web::Data<D>) -> HttpResponse {
+    // A busy database maps to 503, which the collector retries later
+    map_db_call_to_http_response(ingest::store(&**db, batch.into_inner()))
 }
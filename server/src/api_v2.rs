@@ -0,0 +1,224 @@
+use std::sync::RwLock;
+
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{SensorsState, StatePtr, database::{Database, DatabaseError}};
+use crate::api::{self, StatusResponse};
+use crate::compare::{self, Buckets, ComparedSeries, Comparison};
+use crate::config::TelemetryConfig;
+use crate::derived;
+use crate::sensor::{Sensor, TimestampedSensorReading};
+
+/// A sensor with everything the dashboard shows about it, so that one request is enough
+#[derive(Serialize)]
+pub struct SensorResource<H> {
+    pub id: H,
+    #[serde(flatten)]
+    pub sensor: Sensor,
+    #[serde(flatten)]
+    pub status: StatusResponse,
+    /// Latest reading of every kind
+    pub latest: Vec<TimestampedSensorReading>
+}
+
+#[derive(Deserialize)]
+pub struct SensorsQuery {
+    #[serde(default)]
+    raw: bool,
+    #[serde(default)]
+    include_suspect: bool,
+    /// Adds the dew point, heat index and absolute humidity derived from the latest readings
+    #[serde(default)]
+    derived: bool
+}
+
+fn latest_readings<D: Database>(db: &D, handle: &D::SensorHandle, query: &SensorsQuery)
+    -> Result<Vec<TimestampedSensorReading>, DatabaseError> {
+
+    let mut latest = api::calibrated(db, handle, query.raw, db.get_latest_readings(handle, query.include_suspect))?;
+    if query.derived {
+        let latest_of = |symbol: &str| latest.iter().find(|reading| reading.reading.symbol() == symbol);
+        let derived: Vec<TimestampedSensorReading> = match (latest_of("T"), latest_of("H")) {
+            (Some(temperature), Some(humidity)) => derived::derived_kinds().iter()
+                .filter_map(|kind| derived::latest(&kind.symbol, temperature, humidity))
+                .collect(),
+            _ => Vec::new()
+        };
+        latest.extend(derived);
+    }
+    Ok(latest)
+}
+
+/// Unknown ids are answered with 404 here, v1 keeps answering them as it always did
+fn find_sensor<D: Database<SensorHandle=i32>>(db: &D, handle: i32) -> Result<Sensor, DatabaseError> {
+    db.get_sensors_with_handles()?
+        .into_iter()
+        .find(|(id, _)| *id == handle)
+        .map(|(_, sensor)| sensor)
+        .ok_or(DatabaseError::NotFound)
+}
+
+fn sensor_resource<D: Database, S: SensorsState>(
+    db: &D,
+    handle: D::SensorHandle,
+    sensor: Sensor,
+    query: &SensorsQuery,
+    state: &StatePtr<S>,
+    telemetry: &RwLock<TelemetryConfig>)
+-> Result<SensorResource<D::SensorHandle>, DatabaseError> {
+
+    let latest = latest_readings(db, &handle, query)?;
+    let status = api::status_of(db, &handle, &sensor, state, telemetry);
+    Ok(SensorResource { id: handle, sensor, status, latest })
+}
+
+//#[get("/sensors")]
+pub async fn sensors<D: Database, S: SensorsState>(
+    query: web::Query<SensorsQuery>,
+    db: web::Data<D>,
+    state: web::Data<StatePtr<S>>,
+    telemetry: web::Data<RwLock<TelemetryConfig>>)
+-> HttpResponse
+where D::SensorHandle: Serialize {
+    let db = db.get_ref();
+    let result = db.get_sensors_with_handles()
+        .and_then(|sensors| sensors.into_iter()
+            .map(|(handle, sensor)| sensor_resource(db, handle, sensor, &query, &state, &telemetry))
+            .collect::<Result<Vec<_>, DatabaseError>>());
+    api::map_db_call_to_http_response(result)
+}
+
+//#[get("/sensors/{id}")]
+pub async fn sensor<D: Database<SensorHandle=i32>, S: SensorsState>(
+    request: web::Path<i32>,
+    query: web::Query<SensorsQuery>,
+    db: web::Data<D>,
+    state: web::Data<StatePtr<S>>,
+    telemetry: web::Data<RwLock<TelemetryConfig>>)
+-> HttpResponse {
+    let handle = request.0;
+    let db = db.get_ref();
+    let result = find_sensor(db, handle)
+        .and_then(|sensor| sensor_resource(db, handle, sensor, &query, &state, &telemetry));
+    api::map_db_call_to_http_response(result)
+}
+
+/// Keeps the responses of the comparison small enough to chart
+const MAX_COMPARED_SERIES: usize = 32;
+const MAX_COMPARED_BUCKETS: usize = 5000;
+/// A year, longer buckets would overflow the bucket arithmetic long before they made sense
+pub const MAX_BUCKET_SECS: i64 = 366 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct CompareQuery {
+    /// Comma separated sensor ids
+    sensors: String,
+    /// Comma separated kind symbols, derived kinds included
+    kinds: String,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default = "default_bucket_secs")]
+    bucket_secs: i64,
+    /// Sensor the others are compared to, e.g. the outdoor one
+    reference: Option<i32>,
+    #[serde(default)]
+    raw: bool,
+    #[serde(default)]
+    include_suspect: bool
+}
+
+fn default_bucket_secs() -> i64 {
+    300
+}
+
+fn comma_separated(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+fn compared_readings<D: Database<SensorHandle=i32>>(
+    db: &D,
+    handle: i32,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    kinds: &[&str],
+    query: &CompareQuery)
+-> Result<Vec<TimestampedSensorReading>, DatabaseError> {
+
+    find_sensor(db, handle)?;
+    let readings = db.get_readings_between(&handle, Some(from.naive_utc()), Some(to.naive_utc()))
+        .map(|readings| api::without_suspect(query.include_suspect, readings));
+    let readings = api::calibrated(db, &handle, query.raw, readings)?;
+    if kinds.iter().any(|kind| derived::is_derived(kind)) {
+        return Ok(derived::with_derived(readings));
+    }
+    Ok(readings)
+}
+
+//#[get("/compare")]
+pub async fn compare<D: Database<SensorHandle=i32>>(query: web::Query<CompareQuery>, db: web::Data<D>) -> HttpResponse {
+    let sensors = match comma_separated(&query.sensors).map(str::parse).collect::<Result<Vec<i32>, _>>() {
+        Ok(sensors) => sensors,
+        Err(_) => return HttpResponse::BadRequest().body("The sensors must be comma separated ids")
+    };
+    let kinds: Vec<&str> = comma_separated(&query.kinds).collect();
+    if sensors.is_empty() || kinds.is_empty() {
+        return HttpResponse::BadRequest().body("At least one sensor and one kind are needed");
+    }
+    if sensors.len() * kinds.len() > MAX_COMPARED_SERIES {
+        return HttpResponse::BadRequest().body(format!("At most {} series can be compared", MAX_COMPARED_SERIES));
+    }
+    if let Some(reference) = query.reference {
+        if !sensors.contains(&reference) {
+            return HttpResponse::BadRequest().body("The reference has to be one of the sensors");
+        }
+    }
+
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or_else(|| to - Duration::days(1));
+    if from >= to || query.bucket_secs <= 0 {
+        return HttpResponse::BadRequest().body("The range and the buckets must not be empty");
+    }
+    if query.bucket_secs > MAX_BUCKET_SECS {
+        return HttpResponse::BadRequest().body(format!("The buckets must not be longer than {} seconds", MAX_BUCKET_SECS));
+    }
+    let buckets = Buckets::covering(from, to, query.bucket_secs);
+    if buckets.count > MAX_COMPARED_BUCKETS {
+        return HttpResponse::BadRequest().body(format!("At most {} buckets, use longer ones", MAX_COMPARED_BUCKETS));
+    }
+
+    let db = db.get_ref();
+    let mut series = Vec::with_capacity(sensors.len() * kinds.len());
+    for &sensor in &sensors {
+        let readings = match compared_readings(db, sensor, buckets.start, to, &kinds, &query) {
+            Ok(readings) => readings,
+            Err(err) => return api::map_database_error_to_http(err)
+        };
+        series.extend(kinds.iter().map(|kind| ComparedSeries {
+            sensor,
+            kind: kind.to_string(),
+            values: buckets.means(&readings, kind),
+            difference: None
+        }));
+    }
+
+    if let Some(reference) = query.reference {
+        let references: Vec<(String, Vec<_>)> = series.iter()
+            .filter(|compared| compared.sensor == reference)
+            .map(|compared| (compared.kind.clone(), compared.values.clone()))
+            .collect();
+        for compared in series.iter_mut().filter(|compared| compared.sensor != reference) {
+            compared.difference = references.iter()
+                .find(|(kind, _)| *kind == compared.kind)
+                .map(|(_, values)| compare::difference(&compared.values, values));
+        }
+    }
+
+    HttpResponse::Ok().json(Comparison {
+        bucket_secs: query.bucket_secs,
+        timestamps: buckets.timestamps(),
+        reference: query.reference,
+        series
+    })
+}
@@ -0,0 +1,165 @@
+use std::marker::PhantomData;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{error, http::{header, Method}, web, Error};
+use chrono::{DateTime, Utc};
+use futures::future::{self, Either, Ready};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::{Database, DatabaseError};
+
+/// What an API token may do, every scope includes the ones before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum TokenScope {
+    /// Readings, sensors and their status
+    Read,
+    /// Calibrations, sensor configuration and ingestion from the collectors
+    Write,
+    /// Device adoption
+    Admin
+}
+
+impl TokenScope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+            TokenScope::Admin => "admin"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(TokenScope::Read),
+            "write" => Some(TokenScope::Write),
+            "admin" => Some(TokenScope::Admin),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub scope: TokenScope,
+    pub created: DateTime<Utc>
+}
+
+/// 256 random bits, hex encoded
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// The tokens are random, so a plain SHA-256 is enough to keep them out of the database
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn bearer_token(request: &ServiceRequest) -> Option<&str> {
+    request.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Requires a bearer token with the scope the route needs: `read` for GET and HEAD requests,
+/// none at all when the reads are anonymous, and `write` for the others.
+pub struct Authorize<D> {
+    read: Option<TokenScope>,
+    write: TokenScope,
+    db: PhantomData<D>
+}
+
+impl<D> Authorize<D> {
+    pub fn new(read: Option<TokenScope>, write: TokenScope) -> Self {
+        Authorize { read, write, db: PhantomData }
+    }
+}
+
+impl<S, B, D> Transform<S> for Authorize<D>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    D: Database + 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthorizeMiddleware<S, D>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(AuthorizeMiddleware { service, read: self.read, write: self.write, db: PhantomData })
+    }
+}
+
+pub struct AuthorizeMiddleware<S, D> {
+    service: S,
+    read: Option<TokenScope>,
+    write: TokenScope,
+    db: PhantomData<D>
+}
+
+impl<S, D> AuthorizeMiddleware<S, D> {
+    fn required_scope(&self, method: &Method) -> Option<TokenScope> {
+        match *method {
+            Method::GET | Method::HEAD => self.read,
+            _ => Some(self.write)
+        }
+    }
+}
+
+impl<S, B, D> Service for AuthorizeMiddleware<S, D>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    D: Database + 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let required = match self.required_scope(request.method()) {
+            Some(required) => required,
+            None => return Either::Left(self.service.call(request))
+        };
+        match authorize::<D>(&request, required) {
+            Ok(()) => Either::Left(self.service.call(request)),
+            Err(err) => Either::Right(future::err(err))
+        }
+    }
+}
+
+fn authorize<D: Database + 'static>(request: &ServiceRequest, required: TokenScope) -> Result<(), Error> {
+    let token = bearer_token(request)
+        .ok_or_else(|| error::ErrorUnauthorized("Missing bearer token"))?;
+    let db = request.app_data::<web::Data<D>>()
+        .ok_or_else(|| error::ErrorInternalServerError("No database"))?;
+
+    match db.get_token_scope(&hash_token(token)) {
+        Ok(scope) if scope >= required => Ok(()),
+        Ok(_) => Err(error::ErrorForbidden(format!("The token lacks the {} scope", required.name()))),
+        Err(DatabaseError::NotFound) => Err(error::ErrorUnauthorized("Invalid token")),
+        Err(DatabaseError::Busy) => Err(error::ErrorServiceUnavailable("Database connection failed")),
+        Err(err) => Err(error::ErrorInternalServerError(format!("{:?}", err)))
+    }
+}
@@ -0,0 +1,176 @@
+use btleplug::api::{Characteristic, Peripheral};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::alpha_sensor::AlphaSensor;
+use crate::driver::{ConnectedSensor, ProbeError, SensorCommand, SensorDriver, SensorDriverError};
+use crate::sensor::{SensorFamily, SensorReading};
+
+/// Sensor reporting an arbitrary list of measurements over the same UART service as the Alpha one.
+/// A read is answered with a `[status, count]` header followed by `count` notifications,
+/// each `[kind symbol, decimal exponent, mantissa as i32 little endian]`.
+pub struct BetaSensor<P: Peripheral> {
+    pub peripheral: P,
+    characteristic: Characteristic,
+    data_receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum BetaSensorPollError {
+    UnexpectedResponse,
+    SensorError,
+    Timeout,
+    SendFailed,
+}
+
+const MEASUREMENT_LENGTH: usize = 6;
+
+impl<P: Peripheral> BetaSensor<P> {
+    pub fn try_new(peripheral: P, characteristic: Characteristic) -> Option<Self> {
+        let (tx, rx) = mpsc::channel();
+        let notification_characteristic = characteristic.clone();
+        peripheral.on_notification(Box::new(move |notification| {
+            if notification.uuid == notification_characteristic.uuid {
+                tx.send(notification.value).expect("Send failure");
+            } else {
+                println!("Unexpected notification uuid: {}", notification.uuid);
+            }
+        }));
+
+        if Self::check_hello(&peripheral, &characteristic, &rx) {
+            Some(BetaSensor {
+                peripheral,
+                characteristic,
+                data_receiver: rx,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Late replies to the commands which timed out must not be taken for the next response
+    fn drop_stale(peripheral: &P, rx: &mpsc::Receiver<Vec<u8>>) {
+        let stale = rx.try_iter().count();
+        if stale > 0 {
+            println!("Dropped {} stale notifications from {}", stale, peripheral.address());
+        }
+    }
+
+    fn check_hello(
+        peripheral: &P,
+        characteristic: &Characteristic,
+        rx: &mpsc::Receiver<Vec<u8>>,
+    ) -> bool {
+        Self::drop_stale(peripheral, rx);
+        if peripheral.command(characteristic, &[0x10u8]).is_err() {
+            return false;
+        }
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(data) => {
+                if data.eq(&vec![0x00u8, 0xF0u8, 0x14u8, 0x42u8]) {
+                    true
+                } else {
+                    println!("Hello data did not match! {:?}", data);
+                    false
+                }
+            }
+            Err(_) => {
+                println!("Hello timeout");
+                false
+            }
+        }
+    }
+
+    fn receive(&self) -> Result<Vec<u8>, BetaSensorPollError> {
+        self.data_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .map_err(|_| BetaSensorPollError::Timeout)
+    }
+
+    pub fn poll(&self) -> Result<Vec<SensorReading>, BetaSensorPollError> {
+        Self::drop_stale(&self.peripheral, &self.data_receiver);
+        if self.peripheral.command(&self.characteristic, &[0x66u8]).is_err() {
+            return Err(BetaSensorPollError::SendFailed);
+        }
+
+        let header = self.receive()?;
+        if header.len() != 2 {
+            return Err(BetaSensorPollError::UnexpectedResponse);
+        }
+        if header[0] != 0x00u8 {
+            return Err(BetaSensorPollError::SensorError);
+        }
+
+        (0..header[1])
+            .map(|_| self.receive().and_then(|data| Self::parse_measurement(&data)))
+            .collect()
+    }
+
+    fn parse_measurement(data: &[u8]) -> Result<SensorReading, BetaSensorPollError> {
+        if data.len() != MEASUREMENT_LENGTH || !data[0].is_ascii_alphabetic() {
+            return Err(BetaSensorPollError::UnexpectedResponse);
+        }
+
+        let symbol = (data[0] as char).to_string();
+        let exponent = i8::from_le_bytes([data[1]]);
+        let mantissa = i32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+        let value = (mantissa as f64 * 10f64.powi(exponent as i32)) as f32;
+
+        Ok(SensorReading::from_symbol(&symbol, value))
+    }
+}
+
+impl From<BetaSensorPollError> for SensorDriverError {
+    fn from(err: BetaSensorPollError) -> Self {
+        match err {
+            BetaSensorPollError::SendFailed => SensorDriverError::SendFailed,
+            err => SensorDriverError::Failed(format!("{:?}", err))
+        }
+    }
+}
+
+impl<P: Peripheral> ConnectedSensor<P> for BetaSensor<P> {
+    fn peripheral(&self) -> &P {
+        &self.peripheral
+    }
+
+    fn family(&self) -> SensorFamily {
+        SensorFamily::Beta
+    }
+
+    fn poll(&self) -> Result<Vec<SensorReading>, SensorDriverError> {
+        Ok(BetaSensor::poll(self)?)
+    }
+
+    fn command(&self, command: &SensorCommand) -> Result<(), SensorDriverError> {
+        match command {
+            SensorCommand::Ping => match Self::check_hello(&self.peripheral, &self.characteristic, &self.data_receiver) {
+                true => Ok(()),
+                false => Err(SensorDriverError::Failed("No hello response".to_string()))
+            },
+            _ => Err(SensorDriverError::Unsupported)
+        }
+    }
+}
+
+/// Sensors running the `beta` firmware, advertised as "AirBeta..."
+pub struct BetaDriver;
+
+impl<P: Peripheral + 'static> SensorDriver<P> for BetaDriver {
+    fn family(&self) -> SensorFamily {
+        SensorFamily::Beta
+    }
+
+    fn identify(&self, peripheral: &P) -> bool {
+        peripheral.properties().local_name.map_or(false, |name| name.contains("AirBeta"))
+    }
+
+    fn probe(&self, peripheral: P) -> Result<Box<dyn ConnectedSensor<P>>, ProbeError> {
+        // Same UART service as the Alpha sensors
+        let characteristic = AlphaSensor::inspect(&peripheral)?;
+        println!("Found characteristics in {}", peripheral.address());
+        BetaSensor::try_new(peripheral, characteristic)
+            .map(|sensor| Box::new(sensor) as Box<dyn ConnectedSensor<P>>)
+            .ok_or(ProbeError::Protocol)
+    }
+}
@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{Database, DatabaseError};
+use crate::sensor::{ReadingKind, TimestampedSensorReading};
+
+fn default_gain() -> f32 {
+    1.0
+}
+
+/// Linear correction of one reading kind: `calibrated = raw * gain + offset`.
+/// Applies to every reading taken at or after `effective_from` until superseded by a newer one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Calibration {
+    pub kind: String,
+    #[serde(default)]
+    pub offset: f32,
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    pub effective_from: DateTime<Utc>
+}
+
+impl Calibration {
+    pub fn apply(&self, value: f32) -> f32 {
+        value * self.gain + self.offset
+    }
+}
+
+/// Only the stored kinds can be calibrated, the derived ones follow their inputs
+pub fn is_calibratable<D: Database>(db: &D, kind: &str) -> Result<bool, DatabaseError> {
+    db.get_reading_kinds()
+        .map(|kinds| kinds.iter().any(|registered| registered.symbol == kind))
+}
+
+/// The calibrations are ordered as the database returns them, by `effective_from` and then by
+/// when they were added, so of two taking effect at the same moment the later one wins
+fn find<'a>(calibrations: &'a [Calibration], kind: &str, timestamp: &DateTime<Utc>) -> Option<&'a Calibration> {
+    calibrations.iter()
+        .rev()
+        .find(|calibration| calibration.kind == kind && calibration.effective_from <= *timestamp)
+}
+
+/// A calibrated value is not more precise than the raw one, e.g. 21.3 rather than 21.299999
+fn round(value: f32, precision: u8) -> f32 {
+    let factor = 10f32.powi(precision as i32);
+    (value * factor).round() / factor
+}
+
+/// `kinds` gives the precision the calibrated values are rounded to
+pub fn calibrate(calibrations: &[Calibration], kinds: &[ReadingKind], reading: TimestampedSensorReading) -> TimestampedSensorReading {
+    let calibration = find(calibrations, reading.reading.symbol(), &reading.timestamp);
+
+    match (calibration, reading.reading.value()) {
+        (Some(calibration), Some(value)) => {
+            let precision = kinds.iter()
+                .find(|kind| kind.symbol == reading.reading.symbol())
+                .map(|kind| kind.precision);
+            let calibrated = calibration.apply(value);
+            TimestampedSensorReading {
+                timestamp: reading.timestamp,
+                reading: reading.reading.with_value(precision.map_or(calibrated, |precision| round(calibrated, precision))),
+                quality: reading.quality
+            }
+        },
+        _ => reading
+    }
+}
+
+pub fn calibrate_all(calibrations: &[Calibration], kinds: &[ReadingKind], readings: Vec<TimestampedSensorReading>)
+    -> Vec<TimestampedSensorReading> {
+
+    if calibrations.is_empty() {
+        return readings;
+    }
+
+    readings.into_iter()
+        .map(|reading| calibrate(calibrations, kinds, reading))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::sensor::{ReadingQuality, SensorReading};
+
+    #[test]
+    fn rounds_calibrated_values_to_the_kind_precision() {
+        let timestamp = Utc.ymd(2021, 3, 21).and_hms(9, 45, 0);
+        let calibrations = vec![
+            Calibration { kind: "T".to_string(), offset: -0.7, gain: 1.0, effective_from: timestamp },
+            Calibration { kind: "P".to_string(), offset: 0.0, gain: 1.013, effective_from: timestamp }
+        ];
+        let kinds = vec![ReadingKind { symbol: "T".to_string(), name: "temperature".to_string(), unit: "°C".to_string(), precision: 1 }];
+        let reading = |reading| TimestampedSensorReading { timestamp, reading, quality: ReadingQuality::Good };
+
+        let calibrated = calibrate_all(&calibrations, &kinds, vec![
+            reading(SensorReading::Temperature(22.0)),
+            reading(SensorReading::Measurement("P".to_string(), 1000.0))
+        ]);
+
+        assert_eq!(calibrated[0].reading.value(), Some(21.3));
+        assert!(serde_json::to_string(&calibrated[0]).unwrap().contains(r#""value":21.3,"#));
+        // Kinds without a registered precision are left as calibrated
+        assert_eq!(calibrated[1].reading.value(), Some(1000.0 * 1.013));
+    }
+
+    #[test]
+    fn the_latest_added_of_the_same_moment_wins() {
+        let timestamp = Utc.ymd(2021, 3, 21).and_hms(9, 45, 0);
+        let calibrations = vec![
+            Calibration { kind: "T".to_string(), offset: -2.0, gain: 1.0, effective_from: timestamp - chrono::Duration::days(1) },
+            Calibration { kind: "T".to_string(), offset: -1.0, gain: 1.0, effective_from: timestamp },
+            Calibration { kind: "T".to_string(), offset: 0.5, gain: 1.0, effective_from: timestamp }
+        ];
+
+        let calibrated = calibrate(&calibrations, &[], TimestampedSensorReading {
+            timestamp,
+            reading: SensorReading::Temperature(20.0),
+            quality: ReadingQuality::Good
+        });
+
+        assert_eq!(calibrated.reading.value(), Some(20.5));
+    }
+}
@@ -0,0 +1,352 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use structopt::StructOpt;
+
+use crate::auth::{self, TokenScope};
+use crate::calibration::{self, Calibration};
+use crate::database::{Database, DatabaseError};
+use crate::derived;
+use crate::export::{self, ReadingsFormat, ReadingsLayout};
+use crate::import::{self, ImportFormat};
+use crate::sensor::{ReadingKind, Sensor, SensorFamily};
+
+#[derive(StructOpt)]
+#[structopt(name = "server", about = "Air sensor collector and REST API server")]
+pub struct Options {
+    /// Path to the TOML configuration file
+    #[structopt(short, long, parse(from_os_str), default_value = "./airsensor.toml")]
+    pub config: PathBuf,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Discover and poll the sensors and serve the REST API (default)
+    Run,
+    /// Serve the REST API and the UI from the database, without BLE
+    Serve,
+    /// Discover and poll the sensors without serving the REST API
+    Collect,
+    /// Inspect the sensors known to the database
+    Sensors(SensorsCommand),
+    /// Export or import readings
+    Readings(ReadingsCommand),
+    /// Inspect or register the kinds of readings
+    Kinds(KindsCommand),
+    /// Manage the API tokens
+    Tokens(TokensCommand),
+    /// Delete the readings and telemetry older than the given age
+    Prune {
+        /// Age such as 90m, 12h, 30d or 8w
+        #[structopt(long, parse(try_from_str = parse_age))]
+        older_than: Duration
+    },
+    /// Rebuild the database file to reclaim the unused space
+    Vacuum,
+    /// Apply the pending schema migrations
+    Migrate
+}
+
+#[derive(StructOpt)]
+pub enum SensorsCommand {
+    /// List the sensors stored in the database
+    List,
+    /// List the calibrations of a sensor
+    Calibrations {
+        /// Sensor id or address
+        #[structopt(long)]
+        sensor: String
+    },
+    /// Correct the readings of a sensor taken since the given moment
+    Calibrate {
+        /// Sensor id or address
+        #[structopt(long)]
+        sensor: String,
+        /// Symbol of a stored reading kind, e.g. T or H
+        #[structopt(long)]
+        kind: String,
+        #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+        offset: f32,
+        #[structopt(long, default_value = "1")]
+        gain: f32,
+        /// RFC 3339 timestamp, all the readings are corrected when omitted
+        #[structopt(long)]
+        from: Option<DateTime<Utc>>
+    },
+    /// Remove a calibration of a sensor, the readings are corrected by the previous one again
+    Uncalibrate {
+        /// Sensor id or address
+        #[structopt(long)]
+        sensor: String,
+        #[structopt(long)]
+        kind: String,
+        /// Moment the calibration takes effect, as listed by `sensors calibrations`
+        #[structopt(long)]
+        from: Option<DateTime<Utc>>
+    }
+}
+
+#[derive(StructOpt)]
+pub enum ReadingsCommand {
+    /// Write the readings of a sensor to a file or the standard output
+    Export(ExportOptions),
+    /// Merge the readings from an exported file or another airsensor database
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// csv, ndjson or sqlite, guessed from the file extension when omitted
+        #[structopt(long)]
+        format: Option<String>,
+        /// Family of the sensors created for a csv or ndjson file, which does not tell it.
+        /// The sensors already stored keep theirs.
+        #[structopt(long, default_value = "Alpha", parse(try_from_str = parse_family))]
+        family: SensorFamily
+    }
+}
+
+#[derive(StructOpt)]
+pub enum KindsCommand {
+    /// List the reading kinds stored in the database
+    List,
+    /// Add a new kind of reading so that sensors and imports can store it
+    Register {
+        /// Single letter symbol
+        #[structopt(long)]
+        symbol: String,
+        #[structopt(long)]
+        name: String,
+        #[structopt(long, default_value = "")]
+        unit: String,
+        /// Number of meaningful decimal places
+        #[structopt(long, default_value = "0")]
+        precision: u8
+    }
+}
+
+#[derive(StructOpt)]
+pub enum TokensCommand {
+    /// Create a token and print it, it cannot be shown again
+    Create {
+        #[structopt(long)]
+        name: String,
+        /// read, write or admin, each including the previous ones
+        #[structopt(long, parse(try_from_str = parse_scope), default_value = "read")]
+        scope: TokenScope
+    },
+    /// List the names and scopes of the tokens
+    List,
+    /// Delete a token, the requests made with it are rejected from then on
+    Revoke {
+        #[structopt(long)]
+        name: String
+    }
+}
+
+#[derive(StructOpt)]
+pub struct ExportOptions {
+    /// Sensor id or address
+    #[structopt(long)]
+    sensor: String,
+    /// Only readings taken at or after this RFC 3339 timestamp
+    #[structopt(long)]
+    from: Option<DateTime<Utc>>,
+    /// Only readings taken before this RFC 3339 timestamp
+    #[structopt(long)]
+    to: Option<DateTime<Utc>>,
+    /// csv, ndjson or json
+    #[structopt(long, default_value = "csv")]
+    format: String,
+    /// One row per sample with temperature and humidity side by side
+    #[structopt(long)]
+    wide: bool,
+    /// Skip the sensor calibration
+    #[structopt(long)]
+    raw: bool,
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>
+}
+
+fn parse_age(age: &str) -> Result<Duration, String> {
+    let split = age.len() - age.chars().last().map_or(0, |unit| unit.len_utf8());
+    let (amount, unit) = age.split_at(split);
+    let amount: i64 = amount.parse()
+        .map_err(|_| format!("Invalid age: {}", age))?;
+
+    match unit {
+        "s" => Ok(Duration::seconds(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        "h" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        "w" => Ok(Duration::weeks(amount)),
+        _ => Err(format!("Invalid age unit in {}, expected one of s, m, h, d, w", age))
+    }
+}
+
+fn parse_scope(scope: &str) -> Result<TokenScope, String> {
+    TokenScope::from_name(scope).ok_or(format!("Invalid scope {}, expected one of read, write, admin", scope))
+}
+
+fn parse_family(family: &str) -> Result<SensorFamily, String> {
+    SensorFamily::from_name(family).ok_or(format!("Invalid sensor family {}, expected one of Alpha, Beta, Xiaomi", family))
+}
+
+fn db_error(err: DatabaseError) -> String {
+    format!("Database error: {:?}", err)
+}
+
+/// Accepts both the numeric sensor id and the sensor address.
+fn resolve_sensor<D: Database<SensorHandle = i32>>(db: &D, sensor: &str) -> Result<(i32, Sensor), String> {
+    let handle = match sensor.parse::<i32>() {
+        Ok(handle) => handle,
+        Err(_) => db.get_sensor_by_addr(sensor.to_string())
+            .map_err(|_| format!("No sensor with address {}", sensor))?
+    };
+
+    db.get_sensor_by_handle(&handle)
+        .map(|found| (handle, found))
+        .map_err(|_| format!("No sensor with id {}", handle))
+}
+
+pub fn execute<D: Database<SensorHandle = i32>>(command: Command, db: &D) -> Result<(), String> {
+    match command {
+        Command::Run | Command::Serve | Command::Collect => Err("The server commands are handled by the server itself".to_string()),
+        Command::Sensors(SensorsCommand::List) => list_sensors(db),
+        Command::Sensors(SensorsCommand::Calibrations { sensor }) => list_calibrations(db, &sensor),
+        Command::Sensors(SensorsCommand::Calibrate { sensor, kind, offset, gain, from }) => {
+            if !calibration::is_calibratable(db, &kind).map_err(db_error)? {
+                return Err(format!("Cannot calibrate readings of kind {}", kind));
+            }
+            let (handle, _) = resolve_sensor(db, &sensor)?;
+            let effective_from = from.unwrap_or_else(|| Utc.timestamp(0, 0));
+            db.add_calibration(&handle, &Calibration { kind, offset, gain, effective_from }).map_err(db_error)
+        },
+        Command::Sensors(SensorsCommand::Uncalibrate { sensor, kind, from }) => {
+            let (handle, _) = resolve_sensor(db, &sensor)?;
+            let effective_from = from.unwrap_or_else(|| Utc.timestamp(0, 0));
+            let deleted = match db.delete_calibrations(&handle, &kind, effective_from.naive_utc()) {
+                Err(DatabaseError::NotFound) => return Err(format!("No calibration of {} from {}", kind, effective_from)),
+                result => result.map_err(db_error)?
+            };
+            println!("Removed {} calibration(s) of {} from {}", deleted, kind, effective_from);
+            Ok(())
+        },
+        Command::Readings(ReadingsCommand::Export(options)) => export_readings(db, options),
+        Command::Readings(ReadingsCommand::Import { file, format, family }) => import_readings(db, &file, format, family),
+        Command::Kinds(KindsCommand::List) => list_kinds(db),
+        Command::Kinds(KindsCommand::Register { symbol, name, unit, precision }) => {
+            if symbol.chars().count() != 1 || derived::is_derived(&symbol) || symbol == "?" {
+                return Err(format!("Invalid reading kind symbol: {}", symbol));
+            }
+            match db.register_reading_kind(&ReadingKind { symbol: symbol.clone(), name, unit, precision }).map_err(db_error)? {
+                true => Ok(()),
+                false => Err(format!("Reading kind {} already exists", symbol))
+            }
+        },
+        Command::Tokens(TokensCommand::Create { name, scope }) => {
+            let token = auth::generate_token();
+            match db.add_token(&name, &auth::hash_token(&token), scope, Utc::now().naive_utc()).map_err(db_error)? {
+                true => {
+                    println!("{}", token);
+                    Ok(())
+                },
+                false => Err(format!("Token {} already exists", name))
+            }
+        },
+        Command::Tokens(TokensCommand::List) => {
+            for token in db.get_tokens().map_err(db_error)? {
+                println!("{}\t{}\t{}", token.name, token.scope.name(), token.created);
+            }
+            Ok(())
+        },
+        Command::Tokens(TokensCommand::Revoke { name }) => match db.revoke_token(&name) {
+            Err(DatabaseError::NotFound) => Err(format!("No token named {}", name)),
+            result => result.map_err(db_error)
+        },
+        Command::Prune { older_than } => {
+            let threshold = Utc::now() - older_than;
+            let deleted = db.delete_readings_before(threshold.naive_utc()).map_err(db_error)?;
+            let deleted_telemetry = db.delete_telemetry_before(threshold.naive_utc()).map_err(db_error)?;
+            println!("Deleted {} readings and {} telemetry samples older than {}", deleted, deleted_telemetry, threshold);
+            Ok(())
+        },
+        Command::Vacuum => db.vacuum().map_err(db_error),
+        Command::Migrate => db.migrate().map_err(db_error)
+    }
+}
+
+fn list_sensors<D: Database<SensorHandle = i32>>(db: &D) -> Result<(), String> {
+    let sensors = db.get_sensors_with_handles().map_err(db_error)?;
+    for (handle, sensor) in sensors {
+        println!("{}\t{:?}\t{}\t{}", handle, sensor.family, sensor.address, sensor.name.unwrap_or_default());
+    }
+    Ok(())
+}
+
+fn list_kinds<D: Database<SensorHandle = i32>>(db: &D) -> Result<(), String> {
+    for kind in db.get_reading_kinds().map_err(db_error)? {
+        println!("{}\t{}\t{}\t{}", kind.symbol, kind.name, kind.unit, kind.precision);
+    }
+    Ok(())
+}
+
+fn list_calibrations<D: Database<SensorHandle = i32>>(db: &D, sensor: &str) -> Result<(), String> {
+    let (handle, _) = resolve_sensor(db, sensor)?;
+    for calibration in db.get_calibrations(&handle).map_err(db_error)? {
+        println!("{}\t{}\tgain {}\toffset {}", calibration.effective_from, calibration.kind, calibration.gain, calibration.offset);
+    }
+    Ok(())
+}
+
+fn export_readings<D: Database<SensorHandle = i32>>(db: &D, options: ExportOptions) -> Result<(), String> {
+    let format = ReadingsFormat::from_name(&options.format)
+        .ok_or(format!("Unsupported format: {}", options.format))?;
+    let layout = if options.wide { ReadingsLayout::Wide } else { ReadingsLayout::Long };
+
+    let (handle, sensor) = resolve_sensor(db, &options.sensor)?;
+    let mut readings = db.get_readings_between(&handle,
+            options.from.map(|from| from.naive_utc()),
+            options.to.map(|to| to.naive_utc()))
+        .map_err(db_error)?;
+    if !options.raw {
+        let calibrations = db.get_calibrations(&handle).map_err(db_error)?;
+        let kinds = db.get_reading_kinds().map_err(db_error)?;
+        readings = calibration::calibrate_all(&calibrations, &kinds, readings);
+    }
+
+    let mut writer: Box<dyn Write> = match options.output {
+        Some(path) => Box::new(File::create(&path)
+            .map_err(|err| format!("Could not create {}: {}", path.display(), err))?),
+        None => Box::new(io::stdout())
+    };
+
+    for chunk in export::rows(format, layout, sensor.address, readings) {
+        let chunk = chunk.map_err(|err| err.to_string())?;
+        writer.write_all(&chunk).map_err(|err| err.to_string())?;
+    }
+    writer.flush().map_err(|err| err.to_string())
+}
+
+fn import_readings<D: Database<SensorHandle = i32>>(db: &D, path: &Path, format: Option<String>, family: SensorFamily) -> Result<(), String> {
+    let format = match format {
+        Some(format) => ImportFormat::from_name(&format)
+            .ok_or(format!("Unsupported import format: {}", format))?,
+        None => ImportFormat::from_path(path)
+            .ok_or("Could not determine the import format, pass --format")?
+    };
+
+    let readings = import::read(path, format, family)?;
+    let report = import::merge(db, readings).map_err(db_error)?;
+
+    for conflict in report.conflicts.iter() {
+        println!("Conflict: {} {} {} stored {:?}, imported {:?}",
+            conflict.address, conflict.timestamp, conflict.kind, conflict.existing, conflict.incoming);
+    }
+    println!("Imported {} readings, {} duplicates, {} conflicts, {} skipped, {} new sensors",
+        report.imported, report.duplicates, report.conflicts.len(), report.skipped, report.created_sensors);
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+
+use crate::driver::{SensorCommand, SensorDriverError};
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The sensor is not connected at the moment
+    Offline,
+    Driver(SensorDriverError),
+    /// The BLE loop went away before answering
+    Dropped
+}
+
+/// Commands for one sensor, executed in order until the first failure
+pub struct CommandRequest {
+    pub address: String,
+    pub commands: Vec<SensorCommand>,
+    reply: oneshot::Sender<Result<(), CommandError>>
+}
+
+impl CommandRequest {
+    pub fn respond(self, result: Result<(), CommandError>) {
+        // Nobody to tell when the HTTP request was cancelled meanwhile
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Hands the commands from the HTTP handlers over to the BLE loop which owns the connections
+#[derive(Clone, Default)]
+pub struct CommandQueue {
+    /// None while no BLE loop takes the commands, e.g. when only the API is served
+    pending: Arc<Mutex<Option<VecDeque<CommandRequest>>>>
+}
+
+impl CommandQueue {
+    /// Fails right away with `Dropped` when the BLE loop is not running
+    pub async fn submit(&self, address: String, commands: Vec<SensorCommand>) -> Result<(), CommandError> {
+        let (reply, response) = oneshot::channel();
+        match self.pending.lock().expect("Poisoned mutex").as_mut() {
+            Some(pending) => pending.push_back(CommandRequest { address, commands, reply }),
+            None => return Err(CommandError::Dropped)
+        }
+
+        response.await.unwrap_or(Err(CommandError::Dropped))
+    }
+
+    /// Accepts the commands from now on, called by the BLE loop when it starts
+    pub fn open(&self) {
+        self.pending.lock().expect("Poisoned mutex").get_or_insert_with(VecDeque::new);
+    }
+
+    pub fn take_all(&self) -> Vec<CommandRequest> {
+        self.pending.lock().expect("Poisoned mutex")
+            .as_mut()
+            .map(|pending| pending.drain(..).collect())
+            .unwrap_or_default()
+    }
+
+    /// Stops accepting the commands, returns the ones not taken yet
+    pub fn close(&self) -> Vec<CommandRequest> {
+        self.pending.lock().expect("Poisoned mutex")
+            .take()
+            .map(|pending| pending.into_iter().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn rejects_commands_while_closed() {
+        let queue = CommandQueue::default();
+        assert!(matches!(block_on(queue.submit("AA".to_string(), vec![SensorCommand::Ping])), Err(CommandError::Dropped)));
+
+        queue.open();
+        queue.close();
+        assert!(matches!(block_on(queue.submit("AA".to_string(), vec![SensorCommand::Ping])), Err(CommandError::Dropped)));
+        assert!(queue.take_all().is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::sensor::{ReadingValue, TimestampedSensorReading};
+
+/// Time buckets shared by all the compared series, aligned to multiples of the bucket length
+/// so that the same range always gives the same buckets
+pub struct Buckets {
+    pub start: DateTime<Utc>,
+    pub length: Duration,
+    pub count: usize
+}
+
+impl Buckets {
+    /// The length has to be positive and at most `api_v2::MAX_BUCKET_SECS`
+    pub fn covering(from: DateTime<Utc>, to: DateTime<Utc>, length_secs: i64) -> Self {
+        let start = Utc.timestamp(from.timestamp().div_euclid(length_secs) * length_secs, 0);
+        let span = (to - start).num_seconds();
+        let count = (span + length_secs - 1).div_euclid(length_secs).max(0) as usize;
+        Buckets { start, length: Duration::seconds(length_secs), count }
+    }
+
+    pub fn timestamps(&self) -> Vec<DateTime<Utc>> {
+        (0..self.count).map(|index| self.start + self.length * index as i32).collect()
+    }
+
+    fn index(&self, timestamp: DateTime<Utc>) -> Option<usize> {
+        let offset = (timestamp - self.start).num_seconds();
+        if offset < 0 {
+            return None;
+        }
+        let index = (offset / self.length.num_seconds()) as usize;
+        if index < self.count { Some(index) } else { None }
+    }
+
+    /// Mean of the readings of a kind in every bucket, `None` for the buckets without any
+    pub fn means(&self, readings: &[TimestampedSensorReading], kind: &str) -> Vec<Option<ReadingValue>> {
+        let mut sums = vec![(0.0f64, 0u32); self.count];
+        for reading in readings.iter().filter(|reading| reading.reading.symbol() == kind) {
+            if let (Some(index), Some(value)) = (self.index(reading.timestamp), reading.reading.value()) {
+                sums[index].0 += value as f64;
+                sums[index].1 += 1;
+            }
+        }
+        sums.into_iter()
+            .map(|(sum, count)| match count {
+                0 => None,
+                _ => Some(ReadingValue(round(sum / count as f64)))
+            })
+            .collect()
+    }
+}
+
+fn round(value: f64) -> f32 {
+    ((value * 100.0).round() / 100.0) as f32
+}
+
+/// Bucket by bucket difference, `None` where either series has no value
+pub fn difference(values: &[Option<ReadingValue>], reference: &[Option<ReadingValue>]) -> Vec<Option<ReadingValue>> {
+    values.iter().zip(reference)
+        .map(|(value, reference)| match (value, reference) {
+            (Some(value), Some(reference)) => Some(ReadingValue(round(value.0 as f64 - reference.0 as f64))),
+            _ => None
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct ComparedSeries<H> {
+    pub sensor: H,
+    pub kind: String,
+    pub values: Vec<Option<ReadingValue>>,
+    /// Values minus the ones of the reference sensor, when one was requested
+    pub difference: Option<Vec<Option<ReadingValue>>>
+}
+
+#[derive(Serialize)]
+pub struct Comparison<H> {
+    pub bucket_secs: i64,
+    /// Start of every bucket, the values of all the series are aligned to them
+    pub timestamps: Vec<DateTime<Utc>>,
+    pub reference: Option<H>,
+    pub series: Vec<ComparedSeries<H>>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::{ReadingQuality, SensorReading};
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 7, 25).and_hms(10, minute, 0)
+    }
+
+    fn temperature(minute: u32, value: f32) -> TimestampedSensorReading {
+        TimestampedSensorReading { timestamp: at(minute), reading: SensorReading::Temperature(value), quality: ReadingQuality::Good }
+    }
+
+    #[test]
+    fn aligns_buckets_to_their_length() {
+        let buckets = Buckets::covering(at(7), at(21), 600);
+        assert_eq!(buckets.timestamps(), vec![at(0), at(10), at(20)]);
+    }
+
+    #[test]
+    fn covers_with_the_longest_buckets() {
+        let buckets = Buckets::covering(at(0), at(30), crate::api_v2::MAX_BUCKET_SECS);
+        assert_eq!(buckets.count, 1);
+        assert!(buckets.start <= at(0));
+    }
+
+    #[test]
+    fn averages_each_bucket() {
+        let buckets = Buckets::covering(at(0), at(30), 600);
+        let readings = vec![
+            temperature(1, 21.0),
+            temperature(9, 22.0),
+            temperature(25, 20.5),
+            temperature(30, 30.0),
+            TimestampedSensorReading { timestamp: at(2), reading: SensorReading::Humidity(50.0), quality: ReadingQuality::Good }
+        ];
+        assert_eq!(buckets.means(&readings, "T"), vec![Some(ReadingValue(21.5)), None, Some(ReadingValue(20.5))]);
+        assert_eq!(buckets.means(&readings, "H"), vec![Some(ReadingValue(50.0)), None, None]);
+    }
+
+    #[test]
+    fn differences_only_where_both_have_values() {
+        let indoor = vec![Some(ReadingValue(21.5)), Some(ReadingValue(22.0)), None];
+        let outdoor = vec![Some(ReadingValue(11.25)), None, Some(ReadingValue(9.0))];
+        assert_eq!(difference(&indoor, &outdoor), vec![Some(ReadingValue(10.25)), None, None]);
+    }
+}
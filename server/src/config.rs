@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::adapters::AdapterBalance;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub database: String,
+    pub http: HttpConfig,
+    pub ble: BleConfig,
+    pub telemetry: TelemetryConfig,
+    /// Plausibility limits keyed by the reading kind symbol
+    pub validation: HashMap<String, ValidationLimits>,
+    /// How long the sensors and the HTTP requests in progress are waited for on SIGINT/SIGTERM
+    pub shutdown_timeout_secs: u64,
+    /// Central server the readings collected here are sent to
+    pub forward: Option<ForwardConfig>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub bind: String,
+    pub app_dir: String,
+    /// Serve the readings without a token, the changes always need one
+    pub anonymous_read: bool,
+    /// Serve HTTPS on the bind address instead of plain HTTP
+    pub tls: Option<TlsConfig>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, the server certificate first
+    pub cert: String,
+    /// PEM private key, PKCS#8 or RSA
+    pub key: String,
+    /// How often the files are checked for a renewed certificate
+    #[serde(default = "TlsConfig::default_reload_check_secs")]
+    pub reload_check_secs: u64,
+    /// Plain HTTP listener redirecting to HTTPS, e.g. "0.0.0.0:80"
+    pub redirect_bind: Option<String>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BleConfig {
+    pub inspect_interval_secs: u64,
+    pub poll_interval_secs: u64,
+    /// Talk to the Alpha sensors in checksummed frames when their firmware supports it
+    pub alpha_framed: bool,
+    /// Only store the readings of the devices adopted over the API, otherwise the pending ones are stored too
+    pub adopted_only: bool,
+    /// Names of the adapters to use, e.g. "hci0", all of them when empty
+    pub adapters: Vec<String>,
+    /// Adapters dedicated to the server, which may be power cycled on start and when they stop responding
+    pub owned_adapters: Vec<String>,
+    pub balance: AdapterBalance
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Supply voltage below which the sensor status reports a low battery
+    pub low_battery_voltage: f32
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ForwardConfig {
+    /// Base URL of the central server, e.g. "http://central:80"
+    pub url: String,
+    pub token: String,
+    /// Name the central server logs the batches under
+    #[serde(default = "ForwardConfig::default_collector")]
+    pub collector: String,
+    #[serde(default = "ForwardConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// Readings sent in one request at most
+    #[serde(default = "ForwardConfig::default_batch_size")]
+    pub batch_size: i64
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ValidationLimits {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    /// Largest believable change between two consecutive readings, per minute
+    pub max_change_per_minute: Option<f32>
+}
+
+fn default_validation() -> HashMap<String, ValidationLimits> {
+    let mut validation = HashMap::new();
+    validation.insert("T".to_string(), ValidationLimits {
+        min: Some(-40.0),
+        max: Some(80.0),
+        max_change_per_minute: Some(3.0)
+    });
+    validation.insert("H".to_string(), ValidationLimits {
+        min: Some(1.0),
+        max: Some(100.0),
+        max_change_per_minute: Some(10.0)
+    });
+    validation
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database: "./database.sqlite3".to_string(),
+            http: HttpConfig::default(),
+            ble: BleConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            validation: default_validation(),
+            shutdown_timeout_secs: 10,
+            forward: None
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            bind: "0.0.0.0:80".to_string(),
+            app_dir: "./app/".to_string(),
+            anonymous_read: true,
+            tls: None
+        }
+    }
+}
+
+impl Default for BleConfig {
+    fn default() -> Self {
+        BleConfig {
+            inspect_interval_secs: 1,
+            poll_interval_secs: 5 * 60,
+            alpha_framed: true,
+            adopted_only: false,
+            adapters: Vec::new(),
+            owned_adapters: Vec::new(),
+            balance: AdapterBalance::FewestConnections
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            // ATmega328p at 8MHz is specified down to 2.7V
+            low_battery_voltage: 3.0
+        }
+    }
+}
+
+impl TlsConfig {
+    fn default_reload_check_secs() -> u64 {
+        60
+    }
+}
+
+impl ForwardConfig {
+    fn default_collector() -> String {
+        fs::read_to_string("/etc/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_else(|_| "collector".to_string())
+    }
+
+    fn default_interval_secs() -> u64 {
+        60
+    }
+
+    fn default_batch_size() -> i64 {
+        500
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file.
+    /// A missing file is not an error, the defaults are used instead.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content)
+                .map_err(|err| format!("Invalid config {}: {}", path.display(), err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(format!("Could not read config {}: {}", path.display(), err))
+        }
+    }
+
+    /// The limits of the kinds missing from `[validation]` keep their defaults
+    fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        let mut config: Config = toml::from_str(content)?;
+        let mut validation = default_validation();
+        validation.extend(config.validation);
+        config.validation = validation;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_default_limits_of_unconfigured_kinds() {
+        let config = Config::parse("[validation.T]\nmin = -10\n\n[validation.P]\nmax = 1100\n").unwrap();
+
+        let temperature = &config.validation["T"];
+        assert_eq!((temperature.min, temperature.max, temperature.max_change_per_minute), (Some(-10.0), None, None));
+        assert_eq!(config.validation["H"].max, Some(100.0));
+        assert_eq!(config.validation["P"].max, Some(1100.0));
+    }
+}
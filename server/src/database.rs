@@ -1,5 +1,7 @@
 use chrono::NaiveDateTime;
-use crate::sensor::{Sensor, SensorReading, TimestampedSensorReading};
+use crate::auth::{ApiToken, TokenScope};
+use crate::calibration::Calibration;
+use crate::sensor::{DeviceStatus, DiscoveredDevice, ReadingKind, ReadingQuality, Sensor, SensorReading, TelemetryKind, TimestampedSensorReading, TimestampedTelemetry};
 
 #[derive(Debug, Clone)]
 pub enum DatabaseError {
@@ -11,20 +13,78 @@ pub enum DatabaseError {
 
 pub trait Database {
     type SensorHandle;
-    fn get_sensor_handle(&self, sensor: &Sensor) -> Result<Self::SensorHandle, DatabaseError>;
     fn get_sensor_by_addr(&self, addr: String) -> Result<Self::SensorHandle, DatabaseError>;
     fn get_sensor_by_handle(&self, handle: &Self::SensorHandle) -> Result<Sensor, DatabaseError>;
     fn create_sensor_if_not_exists(&self, sensor: &Sensor) -> Result<bool, DatabaseError>;
+    fn set_sensor_name(&self, handle: &Self::SensorHandle, name: Option<String>) -> Result<(), DatabaseError>;
     fn get_sensors(&self) -> Result<Vec<Sensor>, DatabaseError>;
+    fn get_sensors_with_handles(&self) -> Result<Vec<(Self::SensorHandle, Sensor)>, DatabaseError>;
     fn add_reading(&self,
         sensor: &Self::SensorHandle,
         timestamp: NaiveDateTime,
-        reading: &SensorReading)
+        reading: &SensorReading,
+        quality: ReadingQuality)
+        -> Result<(), DatabaseError>;
+    fn add_readings(&self,
+        sensor: &Self::SensorHandle,
+        readings: &[TimestampedSensorReading])
         -> Result<(), DatabaseError>;
     fn get_readings(&self, handle: &Self::SensorHandle)
         -> Result<Vec<TimestampedSensorReading>, DatabaseError>;
     fn get_readings_after(&self, handle: &Self::SensorHandle, timestamp: NaiveDateTime)
         -> Result<Vec<TimestampedSensorReading>, DatabaseError>;
-    fn get_latest_reading(&self, handle: &Self::SensorHandle, kind: String)
+    fn get_readings_between(&self,
+        handle: &Self::SensorHandle,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>)
+        -> Result<Vec<TimestampedSensorReading>, DatabaseError>;
+    fn get_latest_reading(&self, handle: &Self::SensorHandle, kind: String, include_suspect: bool)
         -> Result<TimestampedSensorReading, DatabaseError>;
+    /// Latest reading of every kind the sensor reported
+    fn get_latest_readings(&self, handle: &Self::SensorHandle, include_suspect: bool)
+        -> Result<Vec<TimestampedSensorReading>, DatabaseError>;
+    fn get_reading_kinds(&self) -> Result<Vec<ReadingKind>, DatabaseError>;
+    /// Adds a new kind of reading, returns false when the symbol is already taken
+    fn register_reading_kind(&self, kind: &ReadingKind) -> Result<bool, DatabaseError>;
+    fn get_calibrations(&self, handle: &Self::SensorHandle) -> Result<Vec<Calibration>, DatabaseError>;
+    fn add_calibration(&self, handle: &Self::SensorHandle, calibration: &Calibration) -> Result<(), DatabaseError>;
+    /// Removes the calibrations of the kind taking effect at the moment, NotFound when there are none
+    fn delete_calibrations(&self, handle: &Self::SensorHandle, kind: &str, effective_from: NaiveDateTime) -> Result<usize, DatabaseError>;
+    fn add_telemetry(&self,
+        handle: &Self::SensorHandle,
+        timestamp: NaiveDateTime,
+        kind: TelemetryKind,
+        value: f32)
+        -> Result<(), DatabaseError>;
+    fn get_telemetry_between(&self,
+        handle: &Self::SensorHandle,
+        kind: Option<TelemetryKind>,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>)
+        -> Result<Vec<TimestampedTelemetry>, DatabaseError>;
+    fn get_latest_telemetry(&self, handle: &Self::SensorHandle, kind: TelemetryKind)
+        -> Result<TimestampedTelemetry, DatabaseError>;
+    /// Remembers a device found while scanning, a new one is pending
+    fn record_device(&self, sensor: &Sensor, seen: NaiveDateTime) -> Result<(), DatabaseError>;
+    fn get_device_status(&self, address: &str) -> Result<DeviceStatus, DatabaseError>;
+    fn set_device_status(&self, address: &str, status: DeviceStatus) -> Result<(), DatabaseError>;
+    fn get_devices(&self, status: Option<DeviceStatus>) -> Result<Vec<DiscoveredDevice>, DatabaseError>;
+    /// Readings stored after the row id, oldest first, with their row ids
+    fn get_readings_since(&self, after_id: i32, limit: i64)
+        -> Result<Vec<(i32, Self::SensorHandle, TimestampedSensorReading)>, DatabaseError>;
+    /// Telemetry stored after the row id, oldest first, with the row ids
+    fn get_telemetry_since(&self, after_id: i32, limit: i64)
+        -> Result<Vec<(i32, Self::SensorHandle, TimestampedTelemetry)>, DatabaseError>;
+    /// Last readings and telemetry row ids forwarded to the target, zeros when nothing was forwarded yet
+    fn get_forward_cursor(&self, target: &str) -> Result<(i32, i32), DatabaseError>;
+    fn set_forward_cursor(&self, target: &str, readings_id: i32, telemetry_id: i32) -> Result<(), DatabaseError>;
+    /// Stores the hash of a new API token, returns false when the name is already taken
+    fn add_token(&self, name: &str, hash: &str, scope: TokenScope, created: NaiveDateTime) -> Result<bool, DatabaseError>;
+    fn get_token_scope(&self, hash: &str) -> Result<TokenScope, DatabaseError>;
+    fn get_tokens(&self) -> Result<Vec<ApiToken>, DatabaseError>;
+    fn revoke_token(&self, name: &str) -> Result<(), DatabaseError>;
+    fn delete_readings_before(&self, timestamp: NaiveDateTime) -> Result<usize, DatabaseError>;
+    fn delete_telemetry_before(&self, timestamp: NaiveDateTime) -> Result<usize, DatabaseError>;
+    fn vacuum(&self) -> Result<(), DatabaseError>;
+    fn migrate(&self) -> Result<(), DatabaseError>;
 }
@@ -0,0 +1,192 @@
+use chrono::{DateTime, Utc};
+
+use crate::sensor::{ReadingKind, ReadingQuality, SensorReading, TimestampedSensorReading};
+
+/// Symbols of the readings computed from a temperature and humidity pair
+pub const DERIVED_KINDS: [&str; 3] = ["D", "I", "A"];
+
+pub fn is_derived(kind: &str) -> bool {
+    DERIVED_KINDS.contains(&kind)
+}
+
+pub fn derived_kinds() -> Vec<ReadingKind> {
+    let kind = |symbol: &str, name: &str, unit: &str| ReadingKind {
+        symbol: symbol.to_string(),
+        name: name.to_string(),
+        unit: unit.to_string(),
+        precision: 2
+    };
+
+    vec![
+        kind("D", "dew point", "°C"),
+        kind("I", "heat index", "°C"),
+        kind("A", "absolute humidity", "g/m³")
+    ]
+}
+
+fn round(value: f32) -> f32 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Dew point in Celsius using the Magnus formula
+pub fn dew_point(temperature: f32, humidity: f32) -> Option<f32> {
+    if humidity <= 0.0 {
+        return None;
+    }
+
+    let (a, b) = (17.62, 243.12);
+    let gamma = (humidity / 100.0).ln() + a * temperature / (b + temperature);
+    Some(b * gamma / (a - gamma))
+}
+
+/// Heat index (apparent temperature) in Celsius as defined by the NOAA
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 1.8 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut index = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh - 0.000_001_99 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= ((13.0 - rh) / 4.0) * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += ((rh - 85.0) / 10.0) * ((87.0 - t) / 5.0);
+        }
+        index
+    };
+
+    (fahrenheit - 32.0) / 1.8
+}
+
+/// Absolute humidity in grams of water vapour per cubic meter of air
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let saturation_pressure = 6.112 * (17.67 * temperature / (temperature + 243.5)).exp();
+    saturation_pressure * humidity * 2.1674 / (273.15 + temperature)
+}
+
+pub fn derive(kind: &str, temperature: f32, humidity: f32) -> Option<SensorReading> {
+    match kind {
+        "D" => dew_point(temperature, humidity).map(|value| SensorReading::DewPoint(round(value))),
+        "I" => Some(SensorReading::HeatIndex(round(heat_index(temperature, humidity)))),
+        "A" => Some(SensorReading::AbsoluteHumidity(round(absolute_humidity(temperature, humidity)))),
+        _ => None
+    }
+}
+
+fn derive_all(timestamp: DateTime<Utc>, temperature: f32, humidity: f32, quality: ReadingQuality)
+    -> impl Iterator<Item = TimestampedSensorReading> {
+
+    DERIVED_KINDS.iter()
+        .filter_map(move |kind| derive(kind, temperature, humidity))
+        .map(move |reading| TimestampedSensorReading { timestamp, reading, quality })
+}
+
+/// Adds the derived readings after every temperature and humidity pair taken at the same moment.
+/// A derived reading is as suspect as the worse of its inputs.
+pub fn with_derived(readings: Vec<TimestampedSensorReading>) -> Vec<TimestampedSensorReading> {
+    let mut result = Vec::with_capacity(readings.len() * 2);
+    let mut temperature: Option<(DateTime<Utc>, f32, ReadingQuality)> = None;
+    let mut humidity: Option<(DateTime<Utc>, f32, ReadingQuality)> = None;
+
+    for reading in readings {
+        let timestamp = reading.timestamp;
+        match reading.reading {
+            SensorReading::Temperature(value) => temperature = Some((timestamp, value, reading.quality)),
+            SensorReading::Humidity(value) => humidity = Some((timestamp, value, reading.quality)),
+            _ => {}
+        }
+        result.push(reading);
+
+        if let (Some((temperature_time, t, t_quality)), Some((humidity_time, h, h_quality))) = (temperature, humidity) {
+            if temperature_time == timestamp && humidity_time == timestamp {
+                result.extend(derive_all(timestamp, t, h, t_quality.max(h_quality)));
+                temperature = None;
+                humidity = None;
+            }
+        }
+    }
+
+    result
+}
+
+/// Computes a derived reading from the latest temperature and humidity.
+/// The older timestamp of the two is reported.
+pub fn latest(kind: &str, temperature: &TimestampedSensorReading, humidity: &TimestampedSensorReading) -> Option<TimestampedSensorReading> {
+    let t = temperature.reading.value()?;
+    let h = humidity.reading.value()?;
+
+    derive(kind, t, h).map(|reading| TimestampedSensorReading {
+        timestamp: temperature.timestamp.min(humidity.timestamp),
+        reading,
+        quality: temperature.quality.max(humidity.quality)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "{} != {} ± {}", actual, expected, tolerance);
+    }
+
+    fn fahrenheit(celsius: f32) -> f32 {
+        celsius * 1.8 + 32.0
+    }
+
+    fn celsius(fahrenheit: f32) -> f32 {
+        (fahrenheit - 32.0) / 1.8
+    }
+
+    #[test]
+    fn dew_point_matches_reference_values() {
+        assert_close(dew_point(20.0, 50.0).unwrap(), 9.3, 0.05);
+        assert_close(dew_point(25.0, 60.0).unwrap(), 16.7, 0.05);
+        assert_close(dew_point(-10.0, 80.0).unwrap(), -12.8, 0.05);
+        // Saturated air condenses at its own temperature
+        assert_close(dew_point(15.0, 100.0).unwrap(), 15.0, 0.001);
+        assert_eq!(dew_point(20.0, 0.0), None);
+    }
+
+    /// Values of the NOAA heat index table, which is rounded to whole degrees Fahrenheit
+    #[test]
+    fn heat_index_matches_noaa_table() {
+        for &(t, rh, expected) in &[(80.0, 40.0, 80.0), (90.0, 70.0, 106.0), (100.0, 40.0, 109.0), (84.0, 90.0, 98.0)] {
+            assert_close(fahrenheit(heat_index(celsius(t), rh)), expected, 0.5);
+        }
+        // Below 80 °F the simple formula gives about the air temperature
+        assert_close(heat_index(20.0, 50.0), 19.4, 0.05);
+    }
+
+    #[test]
+    fn absolute_humidity_matches_reference_values() {
+        assert_close(absolute_humidity(0.0, 100.0), 4.85, 0.01);
+        assert_close(absolute_humidity(20.0, 50.0), 8.65, 0.02);
+        assert_close(absolute_humidity(25.0, 100.0), 23.0, 0.05);
+    }
+
+    #[test]
+    fn derives_only_from_pairs_taken_at_the_same_moment() {
+        let at = |minute| Utc.ymd(2021, 3, 1).and_hms(12, minute, 0);
+        let reading = |minute, reading| TimestampedSensorReading { timestamp: at(minute), reading, quality: ReadingQuality::Good };
+        let readings = with_derived(vec![
+            reading(0, SensorReading::Temperature(20.0)),
+            reading(1, SensorReading::Humidity(50.0)),
+            reading(2, SensorReading::Temperature(20.0)),
+            TimestampedSensorReading { timestamp: at(2), reading: SensorReading::Humidity(50.0), quality: ReadingQuality::OutOfRange }
+        ]);
+
+        let symbols: Vec<&str> = readings.iter().map(|reading| reading.reading.symbol()).collect();
+        assert_eq!(symbols, vec!["T", "H", "T", "H", "D", "I", "A"]);
+        assert!(readings[4..].iter().all(|derived| derived.timestamp == at(2) && derived.quality == ReadingQuality::OutOfRange));
+        assert_eq!(readings[4].reading.value(), Some(9.26));
+    }
+}
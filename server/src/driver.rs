@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use btleplug::api::Peripheral;
+
+use crate::advertisement::{Advertisement, AdvertisementDecoder, BroadcastReadings};
+use crate::sensor::{SensorFamily, SensorReading};
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum SensorDriverError {
+    /// The command could not be sent, the connection has to be established again
+    SendFailed,
+    /// The sensor does not implement the command
+    Unsupported,
+    /// The sensor refused the command arguments
+    InvalidArgument(String),
+    /// Anything the driver does not recover from by itself, e.g. a timeout or a malformed response
+    Failed(String)
+}
+
+/// Why a peripheral was not taken as a sensor of the family
+#[derive(Debug, Eq, PartialEq)]
+pub enum ProbeError {
+    /// The adapter could not connect to it
+    Connect,
+    /// Connected, but the device did not speak the family protocol
+    Protocol
+}
+
+/// Requests a sensor may understand besides the regular poll
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorCommand {
+    /// Checks that the sensor still responds
+    Ping,
+    /// Advertised name, used after a reboot
+    SetName(String),
+    /// Pairing PIN, used after a reboot
+    SetPin(String),
+    /// Pause after every poll, longer saves the battery
+    SetSleep(Duration),
+    Reboot,
+    /// Blinks a LED so the device can be told apart from the others
+    Identify
+}
+
+/// Sensor connected and recognized by its driver
+pub trait ConnectedSensor<P: Peripheral> {
+    fn peripheral(&self) -> &P;
+    fn family(&self) -> SensorFamily;
+    fn poll(&self) -> Result<Vec<SensorReading>, SensorDriverError>;
+
+    fn command(&self, _command: &SensorCommand) -> Result<(), SensorDriverError> {
+        Err(SensorDriverError::Unsupported)
+    }
+
+    /// Supply voltage in volts, for the sensors which can measure it
+    fn supply_voltage(&self) -> Result<f32, SensorDriverError> {
+        Err(SensorDriverError::Unsupported)
+    }
+
+    /// Called before the sensor is dropped, unless the peripheral is gone already
+    fn disconnect(&self) {
+        println!("Disconnecting sensor {}...", self.peripheral().address());
+        let _ = self.peripheral().disconnect();
+    }
+}
+
+/// Support for one sensor family
+pub trait SensorDriver<P: Peripheral> {
+    fn family(&self) -> SensorFamily;
+    /// Tells from the advertised properties, without connecting, whether the peripheral may be handled
+    fn identify(&self, peripheral: &P) -> bool;
+    /// Connects to the peripheral and checks that it speaks the family protocol
+    fn probe(&self, peripheral: P) -> Result<Box<dyn ConnectedSensor<P>>, ProbeError>;
+}
+
+/// Drivers and advertisement decoders of all the supported families, asked in the order of registration
+pub struct DriverRegistry<P: Peripheral> {
+    drivers: Vec<Box<dyn SensorDriver<P>>>,
+    decoders: Vec<Box<dyn AdvertisementDecoder>>
+}
+
+impl<P: Peripheral> DriverRegistry<P> {
+    pub fn new() -> Self {
+        DriverRegistry { drivers: Vec::new(), decoders: Vec::new() }
+    }
+
+    pub fn register(&mut self, driver: Box<dyn SensorDriver<P>>) {
+        self.drivers.push(driver);
+    }
+
+    pub fn register_decoder(&mut self, decoder: Box<dyn AdvertisementDecoder>) {
+        self.decoders.push(decoder);
+    }
+
+    pub fn decode(&self, advertisement: &Advertisement) -> Option<(SensorFamily, BroadcastReadings)> {
+        self.decoders.iter()
+            .find_map(|decoder| decoder.decode(advertisement)
+                .map(|readings| (decoder.family(), readings)))
+    }
+
+    /// Family of a broadcasting sensor recognized without decoding its advertisement
+    pub fn recognize(&self, advertisement: &Advertisement) -> Option<SensorFamily> {
+        self.decoders.iter()
+            .find(|decoder| decoder.recognizes(advertisement))
+            .map(|decoder| decoder.family())
+    }
+
+    pub fn identify(&self, peripheral: &P) -> Option<&dyn SensorDriver<P>> {
+        self.drivers.iter()
+            .find(|driver| driver.identify(peripheral))
+            .map(|driver| driver.as_ref())
+    }
+}
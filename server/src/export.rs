@@ -0,0 +1,262 @@
+use std::iter;
+use std::pin::Pin;
+
+use actix_web::{Error, error::ErrorInternalServerError, web::Bytes};
+use chrono::{DateTime, Utc};
+use futures::{Stream, stream};
+use serde::Serialize;
+
+use crate::sensor::{ReadingQuality, ReadingValue, SensorReading, TimestampedSensorReading};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadingsFormat {
+    Json,
+    Csv,
+    Ndjson
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadingsLayout {
+    /// One row per reading: timestamp, sensor, kind, value, quality
+    Long,
+    /// One row per sample with temperature and humidity side by side
+    Wide
+}
+
+impl ReadingsFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "json" => Some(ReadingsFormat::Json),
+            "csv" => Some(ReadingsFormat::Csv),
+            "ndjson" | "jsonl" => Some(ReadingsFormat::Ndjson),
+            _ => None
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime.trim().to_lowercase().as_ref() {
+            "application/json" => Some(ReadingsFormat::Json),
+            "text/csv" => Some(ReadingsFormat::Csv),
+            "application/x-ndjson" | "application/jsonl" => Some(ReadingsFormat::Ndjson),
+            _ => None
+        }
+    }
+
+    /// Picks the format from the explicit `format=` parameter, falling back to the
+    /// recognized entry of the Accept header with the highest `q=` weight (the first one
+    /// of equal weights) and finally to JSON.
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> Result<Self, String> {
+        if let Some(name) = format {
+            return Self::from_name(name).ok_or(format!("Unsupported format: {}", name));
+        }
+
+        let mut best: Option<(f32, Self)> = None;
+        for entry in accept.unwrap_or("").split(',') {
+            let mut parameters = entry.split(';');
+            let format = match parameters.next().and_then(Self::from_mime) {
+                Some(format) => format,
+                None => continue
+            };
+            let weight = parameters
+                .filter_map(|parameter| {
+                    let mut pair = parameter.splitn(2, '=');
+                    match (pair.next().map(str::trim), pair.next()) {
+                        (Some("q"), Some(value)) => value.trim().parse::<f32>().ok(),
+                        _ => None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            // q=0 marks a format as not acceptable
+            if weight > 0.0 && best.map_or(true, |(best_weight, _)| weight > best_weight) {
+                best = Some((weight, format));
+            }
+        }
+        Ok(best.map_or(ReadingsFormat::Json, |(_, format)| format))
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ReadingsFormat::Json => "application/json",
+            ReadingsFormat::Csv => "text/csv; charset=utf-8",
+            ReadingsFormat::Ndjson => "application/x-ndjson"
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExportRecord<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub sensor: &'a str,
+    pub kind: &'a str,
+    pub value: Option<ReadingValue>,
+    pub quality: ReadingQuality
+}
+
+#[derive(Serialize)]
+pub struct WideExportRecord<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub sensor: &'a str,
+    pub temperature: Option<ReadingValue>,
+    pub humidity: Option<ReadingValue>
+}
+
+/// Temperature and humidity taken at the same moment
+pub struct WideSample {
+    pub timestamp: DateTime<Utc>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>
+}
+
+impl<'a> ExportRecord<'a> {
+    pub fn new(sensor: &'a str, reading: &'a TimestampedSensorReading) -> Self {
+        ExportRecord {
+            timestamp: reading.timestamp,
+            sensor,
+            kind: reading.reading.symbol(),
+            value: reading.reading.value().map(ReadingValue),
+            quality: reading.quality
+        }
+    }
+}
+
+impl<'a> WideExportRecord<'a> {
+    pub fn new(sensor: &'a str, sample: &WideSample) -> Self {
+        WideExportRecord {
+            timestamp: sample.timestamp,
+            sensor,
+            temperature: sample.temperature.map(ReadingValue),
+            humidity: sample.humidity.map(ReadingValue)
+        }
+    }
+}
+
+/// Pivots readings taken at the same moment into a single sample.
+/// Readings are expected to be ordered by time, as returned by the database.
+pub fn pivot(readings: Vec<TimestampedSensorReading>) -> Vec<WideSample> {
+    let mut samples: Vec<WideSample> = Vec::new();
+
+    for reading in readings {
+        let needs_new_sample = samples.last().map(|sample| sample.timestamp) != Some(reading.timestamp);
+        if needs_new_sample {
+            samples.push(WideSample {
+                timestamp: reading.timestamp,
+                temperature: None,
+                humidity: None
+            });
+        }
+
+        let sample = samples.last_mut().unwrap();
+        match reading.reading {
+            SensorReading::Temperature(temperature) => sample.temperature = Some(temperature),
+            SensorReading::Humidity(humidity) => sample.humidity = Some(humidity),
+            _ => {}
+        }
+    }
+
+    samples
+}
+
+fn csv_header(layout: ReadingsLayout) -> Bytes {
+    match layout {
+        ReadingsLayout::Long => Bytes::from_static(b"timestamp,sensor,kind,value,quality\n"),
+        ReadingsLayout::Wide => Bytes::from_static(b"timestamp,sensor,temperature,humidity\n")
+    }
+}
+
+fn csv_row<R: Serialize>(record: &R) -> Result<Bytes, Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+
+    writer.serialize(record).map_err(ErrorInternalServerError)?;
+    writer.into_inner()
+        .map(Bytes::from)
+        .map_err(ErrorInternalServerError)
+}
+
+fn ndjson_row<R: Serialize>(record: &R) -> Result<Bytes, Error> {
+    let mut line = serde_json::to_vec(record).map_err(ErrorInternalServerError)?;
+    line.push(b'\n');
+    Ok(Bytes::from(line))
+}
+
+pub type ReadingsRows = Box<dyn Iterator<Item = Result<Bytes, Error>>>;
+pub type ReadingsStream = Pin<Box<dyn Stream<Item = Result<Bytes, Error>>>>;
+
+/// Lazily encodes readings chunk by chunk, one row per chunk for the line based formats.
+/// The readings themselves are already loaded, only their encoding is deferred.
+pub fn rows(
+    format: ReadingsFormat,
+    layout: ReadingsLayout,
+    sensor: String,
+    readings: Vec<TimestampedSensorReading>)
+-> ReadingsRows {
+
+    match (format, layout) {
+        (ReadingsFormat::Json, ReadingsLayout::Long) => {
+            Box::new(iter::once_with(move || {
+                serde_json::to_vec(&readings)
+                    .map(Bytes::from)
+                    .map_err(ErrorInternalServerError)
+            }))
+        },
+        (ReadingsFormat::Json, ReadingsLayout::Wide) => {
+            Box::new(iter::once_with(move || {
+                let samples = pivot(readings);
+                let records: Vec<WideExportRecord> = samples.iter()
+                    .map(|sample| WideExportRecord::new(&sensor, sample))
+                    .collect();
+                serde_json::to_vec(&records)
+                    .map(Bytes::from)
+                    .map_err(ErrorInternalServerError)
+            }))
+        },
+        (ReadingsFormat::Csv, ReadingsLayout::Long) => {
+            let rows = readings.into_iter()
+                .map(move |reading| csv_row(&ExportRecord::new(&sensor, &reading)));
+            Box::new(iter::once(Ok(csv_header(layout))).chain(rows))
+        },
+        (ReadingsFormat::Csv, ReadingsLayout::Wide) => {
+            let rows = pivot(readings).into_iter()
+                .map(move |sample| csv_row(&WideExportRecord::new(&sensor, &sample)));
+            Box::new(iter::once(Ok(csv_header(layout))).chain(rows))
+        },
+        (ReadingsFormat::Ndjson, ReadingsLayout::Long) => {
+            Box::new(readings.into_iter()
+                .map(move |reading| ndjson_row(&ExportRecord::new(&sensor, &reading))))
+        },
+        (ReadingsFormat::Ndjson, ReadingsLayout::Wide) => {
+            Box::new(pivot(readings).into_iter()
+                .map(move |sample| ndjson_row(&WideExportRecord::new(&sensor, &sample))))
+        }
+    }
+}
+
+/// Same as `rows`, but suitable for a chunked response body, which is encoded as it is sent
+pub fn stream(
+    format: ReadingsFormat,
+    layout: ReadingsLayout,
+    sensor: String,
+    readings: Vec<TimestampedSensorReading>)
+-> ReadingsStream {
+    Box::pin(stream::iter(rows(format, layout, sensor, readings)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_by_the_accept_weights() {
+        let negotiate = |accept| ReadingsFormat::negotiate(None, Some(accept)).unwrap();
+
+        assert_eq!(negotiate("text/csv, application/x-ndjson"), ReadingsFormat::Csv);
+        assert_eq!(negotiate("text/csv;q=0.5, application/x-ndjson"), ReadingsFormat::Ndjson);
+        assert_eq!(negotiate("text/html, text/csv; charset=utf-8; q=0.9, application/json;q=0.8"), ReadingsFormat::Csv);
+        assert_eq!(negotiate("text/csv;q=0, */*"), ReadingsFormat::Json);
+        assert_eq!(negotiate("text/csv;q=bad"), ReadingsFormat::Csv);
+        assert_eq!(ReadingsFormat::negotiate(Some("ndjson"), Some("text/csv")), Ok(ReadingsFormat::Ndjson));
+        assert_eq!(ReadingsFormat::negotiate(None, None), Ok(ReadingsFormat::Json));
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use actix_web::client::Client;
+use actix_web::rt::{System, SystemRunner};
+
+use crate::config::ForwardConfig;
+use crate::database::Database;
+use crate::ingest::{IngestBatch, IngestReading, IngestSensor, IngestTelemetry};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Failed attempts double the wait up to this many forwarding intervals
+const MAX_BACKOFF_FACTOR: u32 = 16;
+
+/// Sends the readings stored by the collector to the central server.
+/// The local database is the buffer: a cursor per server remembers what was accepted,
+/// so nothing is lost while the server or the network is down.
+pub struct Forwarder {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>
+}
+
+impl Forwarder {
+    pub fn start<D: Database<SensorHandle=i32> + Send + 'static>(db: D, config: ForwardConfig) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut sys = System::new("forwarder");
+            let interval = Duration::from_secs(config.interval_secs);
+            let mut backoff = 1;
+            let mut next = Instant::now();
+
+            while !stopped.load(Ordering::Relaxed) {
+                if Instant::now() < next {
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+
+                match forward_batch(&mut sys, &db, &config) {
+                    // A full batch means there is a backlog, the next one is sent right away
+                    Ok(sent) if sent >= config.batch_size as usize => next = Instant::now(),
+                    Ok(_) => {
+                        backoff = 1;
+                        next = Instant::now() + interval;
+                    },
+                    Err(err) => {
+                        println!("Forwarding to {} failed: {}", config.url, err);
+                        next = Instant::now() + interval * backoff;
+                        backoff = (backoff * 2).min(MAX_BACKOFF_FACTOR);
+                    }
+                }
+            }
+        });
+        Forwarder { stop, thread }
+    }
+
+    /// Waits for the batch in progress, which takes at most the request timeout
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.thread.join().is_err() {
+            println!("Forwarder panicked");
+        }
+    }
+}
+
+/// Sends the readings and telemetry stored since the cursor, returns how many rows were sent
+fn forward_batch<D: Database<SensorHandle=i32>>(sys: &mut SystemRunner, db: &D, config: &ForwardConfig) -> Result<usize, String> {
+    let (readings_cursor, telemetry_cursor) = db.get_forward_cursor(&config.url)
+        .map_err(|err| format!("Could not read the cursor: {:?}", err))?;
+    let readings = db.get_readings_since(readings_cursor, config.batch_size)
+        .map_err(|err| format!("Could not read the readings: {:?}", err))?;
+    let telemetry = db.get_telemetry_since(telemetry_cursor, config.batch_size)
+        .map_err(|err| format!("Could not read the telemetry: {:?}", err))?;
+    if readings.is_empty() && telemetry.is_empty() {
+        return Ok(0);
+    }
+
+    let next_readings_cursor = readings.last().map_or(readings_cursor, |(id, _, _)| *id);
+    let next_telemetry_cursor = telemetry.last().map_or(telemetry_cursor, |(id, _, _)| *id);
+    let sent = readings.len().max(telemetry.len());
+
+    let mut sensors: BTreeMap<i32, IngestSensor> = BTreeMap::new();
+    for (_, handle, reading) in readings {
+        let value = match reading.reading.value() {
+            Some(value) => value,
+            None => continue
+        };
+        ingest_sensor(db, &mut sensors, handle)?.readings.push(IngestReading {
+            timestamp: reading.timestamp,
+            kind: reading.reading.symbol().to_string(),
+            value,
+            quality: reading.quality
+        });
+    }
+    for (_, handle, telemetry) in telemetry {
+        ingest_sensor(db, &mut sensors, handle)?.telemetry.push(IngestTelemetry {
+            timestamp: telemetry.timestamp,
+            kind: telemetry.kind,
+            value: telemetry.value.0
+        });
+    }
+
+    let batch = IngestBatch {
+        collector: config.collector.clone(),
+        sensors: sensors.into_iter().map(|(_, sensor)| sensor).collect()
+    };
+    sys.block_on(send(format!("{}/api/ingest", config.url.trim_end_matches('/')), config.token.clone(), batch))?;
+    db.set_forward_cursor(&config.url, next_readings_cursor, next_telemetry_cursor)
+        .map_err(|err| format!("Could not store the cursor: {:?}", err))?;
+    println!("Forwarded {} rows to {}", sent, config.url);
+    Ok(sent)
+}
+
+async fn send(url: String, token: String, batch: IngestBatch) -> Result<(), String> {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).finish();
+    let response = client.post(url)
+        .bearer_auth(token)
+        .send_json(&batch)
+        .await
+        .map_err(|err| format!("{}", err))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Rejected with {}", response.status()))
+    }
+}
+
+fn ingest_sensor<'a, D: Database<SensorHandle=i32>>(db: &D, sensors: &'a mut BTreeMap<i32, IngestSensor>, handle: i32)
+    -> Result<&'a mut IngestSensor, String> {
+    match sensors.entry(handle) {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(entry) => {
+            let sensor = db.get_sensor_by_handle(&handle)
+                .map_err(|err| format!("Could not read sensor {}: {:?}", handle, err))?;
+            Ok(entry.insert(IngestSensor {
+                address: sensor.address,
+                family: sensor.family,
+                name: sensor.name,
+                readings: Vec::new(),
+                telemetry: Vec::new()
+            }))
+        }
+    }
+}
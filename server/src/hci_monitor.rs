@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use btleplug::api::BDAddr;
+
+/// Service data of an advertisement, keyed by the 16-bit service UUID
+pub type ServiceData = Vec<(u16, Vec<u8>)>;
+
+const HCI_EVENT_PACKET: u8 = 0x04;
+const LE_META_EVENT: u8 = 0x3E;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const AD_SERVICE_DATA_16: u8 = 0x16;
+/// Reported by controllers which could not measure the signal strength
+const RSSI_UNAVAILABLE: i8 = 127;
+
+/// Addresses kept before the ones not heard of for a while are forgotten, the phones around change theirs often
+const MAX_ADDRESSES: usize = 1024;
+const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+/// What btleplug 0.5 leaves out of the peripheral properties
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AdvertisingReport {
+    pub address: BDAddr,
+    pub service_data: ServiceData,
+    /// Signal strength the advertisement was received with in dBm
+    pub rssi: Option<i8>
+}
+
+/// btleplug 0.5 parses the service data of the advertising reports, but drops it before it gets
+/// to the peripheral properties, and ignores their RSSI. The reports are read once more from a raw
+/// HCI socket of every adapter in use and the ones with service data are kept here until the
+/// advertisement is handled.
+#[derive(Clone, Default)]
+pub struct ServiceDataMonitor {
+    latest: Arc<Mutex<HashMap<BDAddr, (Instant, AdvertisingReport)>>>,
+    /// Adapters with a running listener, which ends when the adapter is unplugged
+    listening: Arc<Mutex<HashSet<String>>>
+}
+
+impl ServiceDataMonitor {
+    /// The last report of the peripheral which had service data
+    pub fn report(&self, address: BDAddr) -> Option<AdvertisingReport> {
+        self.latest.lock().expect("Poisoned mutex")
+            .get(&address)
+            .map(|(_, report)| report.clone())
+    }
+
+    fn store(&self, reports: Vec<AdvertisingReport>) {
+        let now = Instant::now();
+        let mut latest = self.latest.lock().expect("Poisoned mutex");
+        for report in reports.into_iter().filter(|report| !report.service_data.is_empty()) {
+            latest.insert(report.address, (now, report));
+        }
+        if latest.len() > MAX_ADDRESSES {
+            latest.retain(|_, (seen_at, _)| now.duration_since(*seen_at) < FORGET_AFTER);
+        }
+    }
+
+    /// Starts reading the advertising reports of the adapter, unless they are read already
+    #[cfg(target_os = "linux")]
+    pub fn listen(&self, adapter: &str) {
+        let device = match adapter.strip_prefix("hci").and_then(|index| index.parse().ok()) {
+            Some(device) => device,
+            None => return println!("Not reading the service data of {}, not an HCI device", adapter)
+        };
+        if !self.listening.lock().expect("Poisoned mutex").insert(adapter.to_string()) {
+            return;
+        }
+
+        let socket = match socket::HciSocket::open(device) {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("Not reading the service data of {}: {}", adapter, err);
+                self.listening.lock().expect("Poisoned mutex").remove(adapter);
+                return;
+            }
+        };
+        let monitor = self.clone();
+        let adapter = adapter.to_string();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 260];
+            loop {
+                match socket.read(&mut buffer) {
+                    Ok(length) => monitor.store(parse_event(&buffer[..length])),
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                    Err(err) => {
+                        println!("Stopped reading the service data of {}: {}", adapter, err);
+                        break;
+                    }
+                }
+            }
+            monitor.listening.lock().expect("Poisoned mutex").remove(&adapter);
+        });
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn listen(&self, _adapter: &str) {}
+}
+
+/// Reports of an LE Advertising Report event as read from the socket, packet type included.
+/// The reports follow each other, the way the kernel reads them too.
+pub fn parse_event(packet: &[u8]) -> Vec<AdvertisingReport> {
+    let mut reports = Vec::new();
+    let parameters = match packet {
+        [HCI_EVENT_PACKET, LE_META_EVENT, length, LE_ADVERTISING_REPORT, rest @ ..] if rest.len() + 1 == *length as usize => rest,
+        _ => return reports
+    };
+    let (&count, mut rest) = match parameters.split_first() {
+        Some(split) => split,
+        None => return reports
+    };
+
+    for _ in 0..count {
+        // Event type, address type, address, data length, data, RSSI
+        if rest.len() < 9 {
+            break;
+        }
+        let data_length = rest[8] as usize;
+        if rest.len() < 9 + data_length + 1 {
+            break;
+        }
+        let mut address = BDAddr::default();
+        address.address.copy_from_slice(&rest[2..8]);
+        let rssi = rest[9 + data_length] as i8;
+        reports.push(AdvertisingReport {
+            address,
+            service_data: parse_service_data(&rest[9..9 + data_length]),
+            rssi: Some(rssi).filter(|&rssi| rssi != RSSI_UNAVAILABLE)
+        });
+        rest = &rest[9 + data_length + 1..];
+    }
+    reports
+}
+
+/// The 16-bit UUID service data structures of the advertising data
+fn parse_service_data(mut data: &[u8]) -> ServiceData {
+    let mut service_data = Vec::new();
+    while let Some((&length, rest)) = data.split_first() {
+        let length = length as usize;
+        if length == 0 || rest.len() < length {
+            break;
+        }
+        let (structure, next) = rest.split_at(length);
+        if structure[0] == AD_SERVICE_DATA_16 && structure.len() >= 3 {
+            let uuid = u16::from_le_bytes([structure[1], structure[2]]);
+            service_data.push((uuid, structure[3..].to_vec()));
+        }
+        data = next;
+    }
+    service_data
+}
+
+#[cfg(target_os = "linux")]
+mod socket {
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::RawFd;
+
+    const BTPROTO_HCI: i32 = 1;
+    const SOL_HCI: i32 = 0;
+    const HCI_FILTER: i32 = 2;
+    const HCI_CHANNEL_RAW: u16 = 0;
+
+    #[repr(C)]
+    struct SockaddrHci {
+        family: libc::sa_family_t,
+        device: u16,
+        channel: u16
+    }
+
+    #[repr(C)]
+    struct HciFilter {
+        type_mask: u32,
+        event_mask: [u32; 2],
+        opcode: u16
+    }
+
+    /// Raw HCI socket receiving only the LE meta events of one adapter
+    pub struct HciSocket(RawFd);
+
+    impl HciSocket {
+        pub fn open(device: u16) -> io::Result<Self> {
+            let fd = unsafe { libc::socket(libc::AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = HciSocket(fd);
+
+            let address = SockaddrHci {
+                family: libc::AF_BLUETOOTH as libc::sa_family_t,
+                device,
+                channel: HCI_CHANNEL_RAW
+            };
+            let bound = unsafe {
+                libc::bind(fd, &address as *const SockaddrHci as *const libc::sockaddr, mem::size_of::<SockaddrHci>() as libc::socklen_t)
+            };
+            if bound < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let event = super::LE_META_EVENT as usize;
+            let mut filter = HciFilter {
+                type_mask: 1 << super::HCI_EVENT_PACKET,
+                event_mask: [0, 0],
+                opcode: 0
+            };
+            filter.event_mask[event >> 5] |= 1 << (event & 31);
+            let filtered = unsafe {
+                libc::setsockopt(fd, SOL_HCI, HCI_FILTER, &filter as *const HciFilter as *const libc::c_void, mem::size_of::<HciFilter>() as libc::socklen_t)
+            };
+            if filtered < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+
+        pub fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            let length = unsafe { libc::read(self.0, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if length < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(length as usize)
+        }
+    }
+
+    impl Drop for HciSocket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.0); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(text: &str) -> BDAddr {
+        let mut address = BDAddr::default();
+        for (index, byte) in text.split(':').rev().enumerate() {
+            address.address[index] = u8::from_str_radix(byte, 16).unwrap();
+        }
+        address
+    }
+
+    #[test]
+    fn parses_service_data_of_advertising_report() {
+        // A4:C1:38:12:34:56 advertising the flags and the ATC1441 service data
+        let packet = [
+            0x04, 0x3E, 0x20, 0x02, 0x01,
+            0x00, 0x00, 0x56, 0x34, 0x12, 0x38, 0xC1, 0xA4, 0x14,
+            0x02, 0x01, 0x06,
+            0x10, 0x16, 0x1A, 0x18, 0xA4, 0xC1, 0x38, 0x12, 0x34, 0x56, 0x00, 0xEA, 0x2D, 0x57, 0x0B, 0x86, 0x12,
+            0xC4
+        ];
+
+        let reports = parse_event(&packet);
+
+        assert_eq!(reports, vec![AdvertisingReport {
+            address: address("A4:C1:38:12:34:56"),
+            service_data: vec![(0x181A, packet[21..34].to_vec())],
+            rssi: Some(-60)
+        }]);
+    }
+
+    #[test]
+    fn parses_consecutive_reports() {
+        // A BTHome report, then one with the manufacturer data only and no RSSI measured
+        let packet = [
+            0x04, 0x3E, 0x21, 0x02, 0x02,
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07,
+            0x06, 0x16, 0xD2, 0xFC, 0x40, 0x01, 0x57,
+            0xC0,
+            0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+            0x03, 0xFF, 0xFF, 0xFF,
+            0x7F
+        ];
+
+        let reports = parse_event(&packet);
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].service_data, vec![(0xFCD2, vec![0x40, 0x01, 0x57])]);
+        assert_eq!(reports[0].rssi, Some(-64));
+        assert_eq!(reports[1].address, address("00:00:00:00:00:02"));
+        assert!(reports[1].service_data.is_empty());
+        assert_eq!(reports[1].rssi, None);
+    }
+
+    #[test]
+    fn ignores_other_and_truncated_events() {
+        // Connection complete, a wrong parameter length and a report cut in its data
+        assert!(parse_event(&[0x04, 0x3E, 0x02, 0x01, 0x00]).is_empty());
+        assert!(parse_event(&[0x04, 0x3E, 0x05, 0x02, 0x01, 0x00]).is_empty());
+        assert!(parse_event(&[0x04, 0x3E, 0x0C, 0x02, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x06]).is_empty());
+        assert!(parse_service_data(&[0x05, 0x16, 0x1A]).is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Deserialize;
+
+use crate::database::{Database, DatabaseError};
+use crate::sensor::{ReadingQuality, Sensor, SensorFamily, SensorReading, TimestampedSensorReading};
+use crate::sqlite_database::SqliteDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    Csv,
+    Ndjson,
+    Sqlite
+}
+
+impl ImportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "csv" => Some(ImportFormat::Csv),
+            "ndjson" | "jsonl" => Some(ImportFormat::Ndjson),
+            "sqlite" | "sqlite3" | "db" => Some(ImportFormat::Sqlite),
+            _ => None
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Self::from_name)
+    }
+}
+
+pub struct ImportedReading {
+    pub sensor: Sensor,
+    pub timestamp: NaiveDateTime,
+    pub reading: SensorReading,
+    pub quality: ReadingQuality
+}
+
+/// The same sensor, timestamp and kind is already stored with a different value.
+/// The stored value is kept.
+pub struct Conflict {
+    pub address: String,
+    pub timestamp: NaiveDateTime,
+    pub kind: String,
+    pub existing: Option<f32>,
+    pub incoming: Option<f32>
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub duplicates: usize,
+    pub skipped: usize,
+    pub created_sensors: usize,
+    pub conflicts: Vec<Conflict>
+}
+
+#[derive(Deserialize)]
+struct ImportRecord {
+    timestamp: DateTime<Utc>,
+    sensor: String,
+    kind: String,
+    value: Option<f32>,
+    #[serde(default)]
+    quality: ReadingQuality
+}
+
+impl ImportRecord {
+    fn into_reading(self, family: &SensorFamily) -> ImportedReading {
+        let reading = match self.value {
+            Some(value) => SensorReading::from_symbol(&self.kind, value),
+            None => SensorReading::Unknown
+        };
+
+        ImportedReading {
+            sensor: Sensor {
+                family: family.clone(),
+                address: self.sensor,
+                name: None
+            },
+            timestamp: self.timestamp.naive_utc(),
+            reading,
+            quality: self.quality
+        }
+    }
+}
+
+/// Reads the readings from a file in the export format or from another airsensor database.
+/// The export format does not tell the family of the sensors, it's given by `family`.
+pub fn read(path: &Path, format: ImportFormat, family: SensorFamily) -> Result<Vec<ImportedReading>, String> {
+    match format {
+        ImportFormat::Csv => {
+            let file = open(path)?;
+            csv::Reader::from_reader(file)
+                .deserialize()
+                .map(|record| record
+                    .map(|record: ImportRecord| record.into_reading(&family))
+                    .map_err(|err| err.to_string()))
+                .collect()
+        },
+        ImportFormat::Ndjson => {
+            let file = open(path)?;
+            BufReader::new(file)
+                .lines()
+                .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|line| line
+                    .map_err(|err| err.to_string())
+                    .and_then(|line| serde_json::from_str::<ImportRecord>(&line).map_err(|err| err.to_string()))
+                    .map(|record| record.into_reading(&family)))
+                .collect()
+        },
+        ImportFormat::Sqlite => read_sqlite(path)
+    }
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    File::open(path)
+        .map_err(|err| format!("Could not open {}: {}", path.display(), err))
+}
+
+/// The other database may come from an older build, so it's brought up to date on a copy
+/// instead of migrating the file that's being imported.
+fn read_sqlite(path: &Path) -> Result<Vec<ImportedReading>, String> {
+    let copy: PathBuf = std::env::temp_dir().join(format!("airsensor-import-{}.sqlite3", std::process::id()));
+    fs::copy(path, &copy)
+        .map_err(|err| format!("Could not copy {}: {}", path.display(), err))?;
+
+    let result = read_sqlite_copy(&copy);
+    let _ = fs::remove_file(&copy);
+    result
+}
+
+fn read_sqlite_copy(path: &Path) -> Result<Vec<ImportedReading>, String> {
+    let source = SqliteDatabase::open(&path.to_string_lossy());
+    source.migrate().map_err(|err| format!("Could not migrate the imported database: {:?}", err))?;
+
+    let mut readings = Vec::new();
+    let sensors = source.get_sensors_with_handles()
+        .map_err(|err| format!("Could not read sensors: {:?}", err))?;
+
+    for (handle, sensor) in sensors {
+        let sensor_readings = source.get_readings(&handle)
+            .map_err(|err| format!("Could not read readings of {}: {:?}", sensor.address, err))?;
+
+        readings.extend(sensor_readings.into_iter().map(|reading| ImportedReading {
+            sensor: sensor.clone(),
+            timestamp: reading.timestamp.naive_utc(),
+            reading: reading.reading,
+            quality: reading.quality
+        }));
+    }
+
+    Ok(readings)
+}
+
+/// Merges the readings into the database. Sensors are matched by their address, a stored sensor
+/// keeps its family and name, and readings already present for the same sensor, timestamp and
+/// kind are not inserted again.
+pub fn merge<D: Database>(db: &D, readings: Vec<ImportedReading>) -> Result<ImportReport, DatabaseError> {
+    let mut report = ImportReport::default();
+    let mut by_sensor: BTreeMap<String, (Sensor, Vec<ImportedReading>)> = BTreeMap::new();
+    let registered: HashSet<String> = db.get_reading_kinds()?
+        .into_iter()
+        .map(|kind| kind.symbol)
+        .collect();
+
+    for reading in readings {
+        // Kinds unknown to this database have to be registered before they can be imported
+        let unknown = match &reading.reading {
+            SensorReading::Unknown => true,
+            other => !registered.contains(other.symbol())
+        };
+        if unknown {
+            report.skipped += 1;
+            continue;
+        }
+
+        by_sensor.entry(reading.sensor.address.clone())
+            .or_insert_with(|| (reading.sensor.clone(), Vec::new()))
+            .1
+            .push(reading);
+    }
+
+    for (address, (sensor, readings)) in by_sensor {
+        if db.create_sensor_if_not_exists(&sensor)? {
+            report.created_sensors += 1;
+        }
+        let handle = db.get_sensor_by_addr(address.clone())?;
+
+        // Only the stored readings in the span of the incoming ones may be duplicates
+        let from = readings.iter().map(|reading| reading.timestamp).min();
+        let to = readings.iter().map(|reading| reading.timestamp).max()
+            .map(|to| to + Duration::seconds(1));
+        let mut known: HashMap<(NaiveDateTime, String), Option<f32>> = db.get_readings_between(&handle, from, to)?
+            .into_iter()
+            .map(|existing| ((existing.timestamp.naive_utc(), existing.reading.symbol().to_string()), existing.reading.value()))
+            .collect();
+
+        let mut to_add = Vec::new();
+        for imported in readings {
+            let key = (imported.timestamp, imported.reading.symbol().to_string());
+            let incoming = imported.reading.value();
+
+            match known.get(&key) {
+                Some(existing) if *existing == incoming => report.duplicates += 1,
+                Some(existing) => report.conflicts.push(Conflict {
+                    address: address.clone(),
+                    timestamp: imported.timestamp,
+                    kind: key.1.clone(),
+                    existing: *existing,
+                    incoming
+                }),
+                None => {
+                    known.insert(key, incoming);
+                    to_add.push(TimestampedSensorReading {
+                        timestamp: DateTime::<Utc>::from_utc(imported.timestamp, Utc),
+                        reading: imported.reading,
+                        quality: imported.quality
+                    });
+                }
+            }
+        }
+
+        db.add_readings(&handle, &to_add)?;
+        report.imported += to_add.len();
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn temp_path(name: &str, extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("airsensor-import-test-{}-{}.{}", name, std::process::id(), extension))
+    }
+
+    fn at(minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2021, 3, 1).and_hms(12, minute, 0)
+    }
+
+    fn reading(address: &str, minute: u32, reading: SensorReading) -> ImportedReading {
+        ImportedReading {
+            sensor: Sensor { family: SensorFamily::Beta, address: address.to_string(), name: None },
+            timestamp: at(minute),
+            reading,
+            quality: ReadingQuality::Good
+        }
+    }
+
+    fn stored_values(db: &SqliteDatabase, address: &str) -> Vec<(NaiveDateTime, String, Option<f32>)> {
+        let handle = db.get_sensor_by_addr(address.to_string()).unwrap();
+        db.get_readings(&handle).unwrap()
+            .into_iter()
+            .map(|stored| (stored.timestamp.naive_utc(), stored.reading.symbol().to_string(), stored.reading.value()))
+            .collect()
+    }
+
+    #[test]
+    fn imports_csv_rows_with_the_given_family_for_new_sensors_only() {
+        let csv = temp_path("csv", "csv");
+        let path = temp_path("csv", "sqlite3");
+        File::create(&csv).unwrap()
+            .write_all(b"timestamp,sensor,kind,value,quality\n\
+                2021-03-01T12:00:00Z,AA:BB,T,21.5,good\n\
+                2021-03-01T12:00:00Z,AA:BB,H,40,good\n\
+                2021-03-01T12:00:00Z,CC:DD,T,19,good\n").unwrap();
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        db.create_sensor_if_not_exists(&Sensor { family: SensorFamily::Alpha, address: "CC:DD".to_string(), name: None }).unwrap();
+
+        let readings = read(&csv, ImportFormat::Csv, SensorFamily::Beta).unwrap();
+        let report = merge(&db, readings).unwrap();
+
+        assert_eq!((report.imported, report.created_sensors), (3, 1));
+        let families = db.get_sensors().unwrap()
+            .into_iter()
+            .map(|sensor| (sensor.address, sensor.family))
+            .collect::<Vec<_>>();
+        assert_eq!(families, vec![
+            ("CC:DD".to_string(), SensorFamily::Alpha),
+            ("AA:BB".to_string(), SensorFamily::Beta)
+        ]);
+        assert_eq!(stored_values(&db, "AA:BB"), vec![
+            (at(0), "T".to_string(), Some(21.5)),
+            (at(0), "H".to_string(), Some(40.0))
+        ]);
+        let _ = fs::remove_file(csv);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn counts_duplicates_and_keeps_stored_values_on_conflict() {
+        let path = temp_path("merge", "sqlite3");
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        merge(&db, vec![
+            reading("CC", 0, SensorReading::Temperature(20.0)),
+            reading("CC", 1, SensorReading::Temperature(20.5))
+        ]).unwrap();
+
+        let report = merge(&db, vec![
+            reading("CC", 0, SensorReading::Temperature(20.0)),
+            reading("CC", 1, SensorReading::Temperature(25.0)),
+            reading("CC", 1, SensorReading::Humidity(45.0)),
+            reading("CC", 2, SensorReading::Temperature(21.0))
+        ]).unwrap();
+
+        assert_eq!((report.imported, report.duplicates, report.created_sensors), (2, 1, 0));
+        assert_eq!(report.conflicts.len(), 1);
+        let conflict = &report.conflicts[0];
+        assert_eq!((conflict.timestamp, conflict.kind.as_str(), conflict.existing, conflict.incoming),
+            (at(1), "T", Some(20.5), Some(25.0)));
+        assert_eq!(stored_values(&db, "CC"), vec![
+            (at(0), "T".to_string(), Some(20.0)),
+            (at(1), "T".to_string(), Some(20.5)),
+            (at(1), "H".to_string(), Some(45.0)),
+            (at(2), "T".to_string(), Some(21.0))
+        ]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn skips_unregistered_and_unknown_kinds() {
+        let path = temp_path("skip", "sqlite3");
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+
+        let report = merge(&db, vec![
+            reading("DD", 0, SensorReading::Measurement("Z".to_string(), 1.0)),
+            reading("DD", 0, SensorReading::Unknown),
+            reading("DD", 0, SensorReading::Temperature(19.0))
+        ]).unwrap();
+
+        assert_eq!((report.imported, report.skipped), (1, 2));
+        assert_eq!(stored_values(&db, "DD"), vec![(at(0), "T".to_string(), Some(19.0))]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn lists_older_imported_readings_in_time_order() {
+        let path = temp_path("order", "sqlite3");
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        merge(&db, vec![
+            reading("EE", 2, SensorReading::Temperature(22.0)),
+            reading("EE", 2, SensorReading::Humidity(42.0))
+        ]).unwrap();
+
+        merge(&db, vec![
+            reading("EE", 0, SensorReading::Temperature(20.0)),
+            reading("EE", 0, SensorReading::Humidity(40.0)),
+            reading("EE", 1, SensorReading::Temperature(21.0))
+        ]).unwrap();
+
+        assert_eq!(stored_values(&db, "EE"), vec![
+            (at(0), "T".to_string(), Some(20.0)),
+            (at(0), "H".to_string(), Some(40.0)),
+            (at(1), "T".to_string(), Some(21.0)),
+            (at(2), "T".to_string(), Some(22.0)),
+            (at(2), "H".to_string(), Some(42.0))
+        ]);
+        let handle = db.get_sensor_by_addr("EE".to_string()).unwrap();
+        let samples = crate::export::pivot(db.get_readings_between(&handle, None, None).unwrap());
+        assert_eq!(samples.iter().map(|sample| (sample.timestamp.naive_utc(), sample.temperature, sample.humidity)).collect::<Vec<_>>(), vec![
+            (at(0), Some(20.0), Some(40.0)),
+            (at(1), Some(21.0), None),
+            (at(2), Some(22.0), Some(42.0))
+        ]);
+        let _ = fs::remove_file(path);
+    }
+}
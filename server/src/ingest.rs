@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{Database, DatabaseError};
+use crate::import::{self, ImportedReading};
+use crate::sensor::{ReadingKind, ReadingQuality, Sensor, SensorFamily, SensorReading, TelemetryKind};
+
+/// Readings pushed by a remote collector to `POST /api/ingest`
+#[derive(Serialize, Deserialize)]
+pub struct IngestBatch {
+    /// Name of the collector, only logged
+    pub collector: String,
+    pub sensors: Vec<IngestSensor>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IngestSensor {
+    pub address: String,
+    pub family: SensorFamily,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub readings: Vec<IngestReading>,
+    #[serde(default)]
+    pub telemetry: Vec<IngestTelemetry>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IngestReading {
+    pub timestamp: DateTime<Utc>,
+    pub kind: String,
+    pub value: f32,
+    /// Validated by the collector, which knows the previous readings
+    #[serde(default)]
+    pub quality: ReadingQuality
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IngestTelemetry {
+    pub timestamp: DateTime<Utc>,
+    pub kind: TelemetryKind,
+    pub value: f32
+}
+
+#[derive(Default, Serialize)]
+pub struct IngestReport {
+    pub imported: usize,
+    /// Already stored, e.g. sent again after the response got lost
+    pub duplicates: usize,
+    pub conflicts: usize,
+    pub skipped: usize,
+    pub telemetry: usize
+}
+
+/// Kinds reported by a sensor are registered on first sight, their metadata can be corrected later
+pub fn ensure_kind_registered<D: Database>(db: &D, reading: &SensorReading) {
+    if let SensorReading::Measurement(symbol, _) = reading {
+        let kind = ReadingKind {
+            symbol: symbol.clone(),
+            name: symbol.clone(),
+            unit: String::new(),
+            precision: 2
+        };
+        match db.register_reading_kind(&kind) {
+            Ok(true) => println!("Registered new reading kind {}", symbol),
+            Ok(false) => {},
+            Err(err) => println!("Could not register reading kind {}: {:?}", symbol, err)
+        }
+    }
+}
+
+/// Stores the batch, a batch sent again is not stored twice
+pub fn store<D: Database>(db: &D, batch: IngestBatch) -> Result<IngestReport, DatabaseError> {
+    let mut report = IngestReport::default();
+    let mut readings = Vec::new();
+    let mut kinds = HashSet::new();
+
+    for sensor in batch.sensors.iter() {
+        let domain_sensor = Sensor {
+            family: sensor.family.clone(),
+            address: sensor.address.clone(),
+            name: sensor.name.clone()
+        };
+        db.create_sensor_if_not_exists(&domain_sensor)?;
+        let handle = db.get_sensor_by_addr(sensor.address.clone())?;
+        // A collector that doesn't know the name keeps the one given here
+        if sensor.name.is_some() && db.get_sensor_by_handle(&handle)?.name != sensor.name {
+            db.set_sensor_name(&handle, sensor.name.clone())?;
+        }
+
+        for reading in sensor.readings.iter() {
+            let domain_reading = SensorReading::from_symbol(&reading.kind, reading.value);
+            if kinds.insert(reading.kind.clone()) {
+                ensure_kind_registered(db, &domain_reading);
+            }
+            readings.push(ImportedReading {
+                sensor: domain_sensor.clone(),
+                timestamp: reading.timestamp.naive_utc(),
+                reading: domain_reading,
+                quality: reading.quality
+            });
+        }
+
+        report.telemetry += store_telemetry(db, &handle, &sensor.telemetry)?;
+    }
+
+    let merged = import::merge(db, readings)?;
+    report.imported = merged.imported;
+    report.duplicates = merged.duplicates;
+    report.conflicts = merged.conflicts.len();
+    report.skipped = merged.skipped;
+
+    println!("Ingested {} readings and {} telemetry from {}", report.imported, report.telemetry, batch.collector);
+    Ok(report)
+}
+
+fn store_telemetry<D: Database>(db: &D, handle: &D::SensorHandle, telemetry: &[IngestTelemetry]) -> Result<usize, DatabaseError> {
+    let from = telemetry.iter().map(|telemetry| telemetry.timestamp.naive_utc()).min();
+    let to = telemetry.iter().map(|telemetry| telemetry.timestamp.naive_utc()).max();
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to + Duration::seconds(1)),
+        _ => return Ok(0)
+    };
+
+    let mut known: HashSet<(NaiveDateTime, &'static str)> = db.get_telemetry_between(handle, None, Some(from), Some(to))?
+        .into_iter()
+        .map(|existing| (existing.timestamp.naive_utc(), existing.kind.name()))
+        .collect();
+
+    let mut stored = 0;
+    for incoming in telemetry {
+        if known.insert((incoming.timestamp.naive_utc(), incoming.kind.name())) {
+            db.add_telemetry(handle, incoming.timestamp.naive_utc(), incoming.kind, incoming.value)?;
+            stored += 1;
+        }
+    }
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sqlite_database::SqliteDatabase;
+
+    use super::*;
+
+    fn batch(name: Option<&str>) -> IngestBatch {
+        IngestBatch {
+            collector: "test".to_string(),
+            sensors: vec![IngestSensor {
+                address: "AA:BB".to_string(),
+                family: SensorFamily::Beta,
+                name: name.map(str::to_string),
+                readings: vec![],
+                telemetry: vec![]
+            }]
+        }
+    }
+
+    #[test]
+    fn keeps_the_central_name_of_a_sensor_sent_without_one() {
+        let path = std::env::temp_dir().join(format!("airsensor-ingest-{}.sqlite3", std::process::id()));
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        store(&db, batch(Some("Kitchen"))).unwrap();
+
+        store(&db, batch(None)).unwrap();
+        assert_eq!(db.get_sensors().unwrap()[0].name, Some("Kitchen".to_string()));
+
+        store(&db, batch(Some("Bedroom"))).unwrap();
+        assert_eq!(db.get_sensors().unwrap()[0].name, Some("Bedroom".to_string()));
+        let _ = std::fs::remove_file(path);
+    }
+}
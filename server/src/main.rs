@@ -12,25 +12,57 @@ use btleplug::corebluetooth::{adapter::Adapter, manager::Manager};
 use btleplug::winrtble::{adapter::Adapter, manager::Manager};
 
 #[cfg(any(target_os = "windows", target_os = "macos"))]
-fn get_central(manager: &Manager) -> Adapter {
+fn get_centrals(manager: &Manager, _config: &BleConfig) -> Vec<(String, Adapter, bool)> {
     let adapters = manager.adapters().unwrap();
-    adapters.into_iter().nth(0).expect("No BLE adapters");
+    adapters.into_iter()
+        .take(1)
+        .map(|adapter| ("default".to_string(), adapter, false))
+        .collect()
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn reset_central(_manager: &Manager, _name: &str, _owned: bool) -> Option<Adapter> {
+    None
 }
 
 #[cfg(target_os = "linux")]
-fn get_central(manager: &Manager) -> ConnectedAdapter {
-    let adapters = manager.adapters().unwrap();
-    let adapter = adapters.into_iter().nth(0).expect("No BLE adapters");
-    manager.down(&adapter).expect("Failed to put adapter down");
-    manager.up(&adapter).expect("Failed to put adapter up");
-    adapter.connect().unwrap()
+fn connect_adapter(manager: &Manager, adapter: &btleplug::bluez::adapter::Adapter, owned: bool) -> Option<ConnectedAdapter> {
+    if owned {
+        manager.down(adapter).map_err(|err| println!("Failed to put {} down: {:?}", adapter.name, err)).ok()?;
+        manager.up(adapter).map_err(|err| println!("Failed to put {} up: {:?}", adapter.name, err)).ok()?;
+    }
+    adapter.connect()
+        .map_err(|err| println!("Failed to connect to {}: {:?}", adapter.name, err))
+        .ok()
+}
+
+#[cfg(target_os = "linux")]
+fn get_centrals(manager: &Manager, config: &BleConfig) -> Vec<(String, ConnectedAdapter, bool)> {
+    let adapters = manager.adapters().expect("Failed to list the BLE adapters");
+    adapters.into_iter()
+        .filter(|adapter| config.adapters.is_empty() || config.adapters.contains(&adapter.name))
+        .filter_map(|adapter| {
+            let owned = config.owned_adapters.contains(&adapter.name);
+            connect_adapter(manager, &adapter, owned)
+                .map(|central| (adapter.name.clone(), central, owned))
+        })
+        .collect()
+}
+
+/// Connects to the adapter again, an owned one is power cycled first
+#[cfg(target_os = "linux")]
+fn reset_central(manager: &Manager, name: &str, owned: bool) -> Option<ConnectedAdapter> {
+    let adapters = manager.adapters().ok()?;
+    let adapter = adapters.into_iter().find(|adapter| adapter.name == name)?;
+    connect_adapter(manager, &adapter, owned)
 }
 
 use std::time::Instant;
-use actix_web::{web, App, HttpResponse, HttpServer, Scope, rt::System, middleware::Logger};
+use actix_web::{web, App, HttpServer, Scope, rt::System, middleware::Logger};
+use std::collections::HashMap;
 use std::vec::Vec;
 use std::sync::{mpsc, Arc, Mutex, RwLock};
-use std::sync::atomic::{Ordering, AtomicBool};
+use std::path::Path;
 use std::thread;
 use std::time::Duration;
 use chrono::Utc;
@@ -38,9 +70,27 @@ use chrono::Utc;
 mod sensor;
 use sensor::*;
 
+mod adapters;
+use adapters::AdapterPool;
+
+mod advertisement;
+use advertisement::Advertisement;
+
 mod alpha_sensor;
 use alpha_sensor::*;
 
+mod beta_sensor;
+use beta_sensor::*;
+
+mod xiaomi_sensor;
+use xiaomi_sensor::XiaomiDecoder;
+
+mod hci_monitor;
+use hci_monitor::ServiceDataMonitor;
+
+mod driver;
+use driver::{ConnectedSensor, DriverRegistry, ProbeError, SensorCommand, SensorDriverError};
+
 mod database;
 use database::{Database, DatabaseError};
 
@@ -49,12 +99,70 @@ use sqlite_database::SqliteDatabase;
 
 pub mod schema;
 mod api;
+mod api_v2;
+mod auth;
+mod calibration;
+mod cli;
+mod compare;
+mod commands;
+mod config;
+mod derived;
+mod export;
+mod forward;
+mod import;
+mod ingest;
+mod openapi;
+mod routes;
+mod rssi;
+mod signals;
+mod tls;
+mod validation;
+
+use auth::TokenScope;
+use commands::{CommandError, CommandQueue};
+use forward::Forwarder;
+use config::{BleConfig, Config, HttpConfig, TelemetryConfig};
+use signals::Signals;
+use validation::Validator;
+use structopt::StructOpt;
+
+pub enum Inspection {
+    /// Not a sensor to connect to
+    Skipped,
+    Connected,
+    /// Connected, but the device did not speak the protocol
+    Rejected,
+    /// The adapter could not connect to the device
+    Unreachable
+}
+
+struct PassiveSensor {
+    sensor: Sensor,
+    counter: Option<u32>,
+    stored_at: Instant,
+    /// Last advertisement, repeated ones included
+    seen_at: Instant
+}
 
+/// Poll intervals without an advertisement after which a broadcasting sensor is considered gone
+const PASSIVE_SENSOR_TIMEOUT_POLLS: u32 = 3;
 
 struct BleMaster<P: Peripheral, D: Database, S: SensorsState> {
     to_inspect: Mutex<Vec<P>>,
-    sensors: Mutex<Vec<AlphaSensor<P>>>,
+    sensors: Mutex<Vec<Box<dyn ConnectedSensor<P>>>>,
+    drivers: DriverRegistry<P>,
+    service_data: ServiceDataMonitor,
+    /// Sensors which broadcast their readings, keyed by the address
+    passive: Mutex<HashMap<String, PassiveSensor>>,
+    /// When the devices were last recorded in the database, so that the advertisements do not write on every update
+    recorded: Mutex<HashMap<String, Instant>>,
+    signal: Mutex<HashMap<BDAddr, i8>>,
+    /// Adapter every sensor to connect to is assigned to, the signal strength is read on it
+    adapters: Mutex<HashMap<BDAddr, String>>,
+    poll_interval: Duration,
+    adopted_only: bool,
     state: StatePtr<S>,
+    validator: Validator,
     db: D
 }
 
@@ -105,12 +213,48 @@ impl SensorsState for AppState {
 
 impl<P: Peripheral, D: Database, S: SensorsState> BleMaster<P, D, S> {
 
-    pub fn new(db: D, state: StatePtr<S>) -> Self {
+    pub fn new(db: D, state: StatePtr<S>, validator: Validator, drivers: DriverRegistry<P>, service_data: ServiceDataMonitor, poll_interval: Duration, adopted_only: bool) -> Self {
         BleMaster::<P, D, S> {
             db,
             state,
+            validator,
+            drivers,
+            service_data,
+            poll_interval,
+            adopted_only,
+            passive: Mutex::new(HashMap::new()),
+            recorded: Mutex::new(HashMap::new()),
+            signal: Mutex::new(HashMap::new()),
+            adapters: Mutex::new(HashMap::new()),
             to_inspect: Mutex::new(Vec::<P>::new()),
-            sensors: Mutex::new(Vec::<AlphaSensor<P>>::new())
+            sensors: Mutex::new(Vec::<Box<dyn ConnectedSensor<P>>>::new())
+        }
+    }
+
+    /// Applies the settings reloaded on SIGHUP
+    pub fn reconfigure(&mut self, validator: Validator, poll_interval: Duration, adopted_only: bool) {
+        self.validator = validator;
+        self.poll_interval = poll_interval;
+        self.adopted_only = adopted_only;
+    }
+
+    /// Disconnects all the sensors, the ones left when the deadline passes are dropped without waiting for them
+    pub fn shutdown(&mut self, deadline: Instant) {
+        self.to_inspect.lock().expect("Poisoned mutex").clear();
+        let sensors: Vec<_> = self.sensors.lock().expect("Poisoned mutex").drain(..).collect();
+        let passive: Vec<_> = self.passive.lock().expect("Poisoned mutex").drain().map(|(_, passive)| passive.sensor).collect();
+
+        let mut state = self.state.write().expect("Poisoned RwLock");
+        for sensor in sensors {
+            let _ = state.remove(&Self::domain_sensor(sensor.as_ref()));
+            if Instant::now() < deadline {
+                sensor.disconnect();
+            } else {
+                println!("Shutdown timeout, not waiting for {} to disconnect", sensor.peripheral().address());
+            }
+        }
+        for sensor in passive {
+            let _ = state.remove(&sensor);
         }
     }
 
@@ -124,115 +268,338 @@ impl<P: Peripheral, D: Database, S: SensorsState> BleMaster<P, D, S> {
             let mut data = self.sensors.lock().expect("Poisoned mutex");
             let mut state = self.state.write().expect("Poisoned RwLock");
             data.iter()
-                .filter(|sensor| !is_not_lost(&sensor.peripheral))
-                .map(Self::sensor_from_alpha)
+                .filter(|sensor| !is_not_lost(sensor.peripheral()))
+                .map(|sensor| Self::domain_sensor(sensor.as_ref()))
                 .for_each(|sensor| {
                     match state.remove(&sensor) {
                         Ok(()) => println!("{:?} gone offline!", sensor.name),
                         Err(()) => println!("Could not remove {:?}!", sensor.name)
                     };
                 });
-            data.retain(|sensor| is_not_lost(&sensor.peripheral));
+            data.retain(|sensor| is_not_lost(sensor.peripheral()));
         }
         {
             let mut data = self.to_inspect.lock().expect("Poisoned mutex");
             data.retain(is_not_lost);
         }
+        {
+            let mut passive = self.passive.lock().expect("Poisoned mutex");
+            if let Some(lost) = passive.remove(&address.to_string()) {
+                let _ = self.state.write().expect("Poisoned RwLock").remove(&lost.sensor);
+                println!("{:?} stopped broadcasting", lost.sensor.name);
+            }
+        }
     }
 
-    pub fn on_discovered(&mut self, peripheral: P) {
+    pub fn on_discovered(&mut self, peripheral: P, adapter: &str) {
+        self.adapters.lock().expect("Poisoned mutex").insert(peripheral.address(), adapter.to_string());
         let mut to_inspect = self.to_inspect.lock().expect("Poisoned mutex");
         to_inspect.push(peripheral);
     }
 
-    pub fn pop_and_inspect(&mut self) {
+    pub fn pop_and_inspect(&mut self) -> Option<(BDAddr, Inspection)> {
         let mut to_inspect = self.to_inspect.lock().expect("Poisoned mutex");
 
-        if let Some(peripheral) = to_inspect.pop() {
-            self.inspect(peripheral)
-        }
+        to_inspect.pop()
+            .map(|peripheral| (peripheral.address(), self.inspect(peripheral)))
+    }
+
+    /// Whether any driver may handle the peripheral, only those are worth an adapter connection
+    pub fn identifies(&self, peripheral: &P) -> bool {
+        self.drivers.identify(peripheral).is_some()
+    }
+
+    /// Family of a broadcasting sensor whose advertisement could not be decoded
+    pub fn recognizes(&self, peripheral: &P) -> Option<SensorFamily> {
+        self.drivers.recognize(&self.advertisement(peripheral))
     }
 
-    fn sensor_from_alpha(alpha: &AlphaSensor<P>) -> Sensor {
-        let properties = alpha.peripheral.properties();
+    fn advertisement(&self, peripheral: &P) -> Advertisement {
+        Advertisement::new(&peripheral.properties(), self.service_data.report(peripheral.address()))
+    }
+
+    fn domain_sensor(sensor: &dyn ConnectedSensor<P>) -> Sensor {
+        let properties = sensor.peripheral().properties();
         Sensor {
-            family: SensorFamily::Alpha,
+            family: sensor.family(),
             address: properties.address.to_string(),
             name: properties.local_name
         }
     }
 
-    pub fn inspect(&self, peripheral: P) {
-        if peripheral.properties().local_name.map_or(true, |name| !name.contains("Weather")) {
-            println!("Ignoring {}", peripheral.address());
-            return
+    pub fn inspect(&self, peripheral: P) -> Inspection {
+        let driver = match self.drivers.identify(&peripheral) {
+            Some(driver) => driver,
+            None => {
+                println!("Ignoring {}", peripheral.address());
+                return Inspection::Skipped
+            }
+        };
+
+        let properties = peripheral.properties();
+        let sensor = Sensor {
+            family: driver.family(),
+            address: properties.address.to_string(),
+            name: properties.local_name
+        };
+        if !self.admit(&sensor) {
+            println!("Not adopted {}", peripheral.address());
+            return Inspection::Skipped
         }
 
-        println!("Inspecting {}...", peripheral.address());
+        println!("Inspecting {} ({:?})...", peripheral.address(), driver.family());
 
-        if let Some(characteristic) = AlphaSensor::inspect(&peripheral) {
-            println!("Found characteristics in {}", peripheral.address());
-            let mut sensors = self.sensors.lock().expect("Poisoned mutex");
-            if let Some(sensor) = AlphaSensor::try_new(peripheral.clone(), characteristic) {
-                let domain_sensor = Self::sensor_from_alpha(&sensor);
-                sensors.push(sensor);
+        match driver.probe(peripheral.clone()) {
+            Ok(sensor) => {
+                let domain_sensor = Self::domain_sensor(sensor.as_ref());
+                self.sensors.lock().expect("Poisoned mutex").push(sensor);
 
                 let mut state = self.state.write().unwrap();
                 state.add(domain_sensor);
-            } else {
+                Inspection::Connected
+            },
+            Err(err) => {
                 let _ = peripheral.disconnect();
+                match err {
+                    ProbeError::Connect => Inspection::Unreachable,
+                    ProbeError::Protocol => Inspection::Rejected
+                }
+            }
+        }
+    }
+
+    /// Remembers the device for the adoption and tells whether its readings may be stored
+    fn admit(&self, sensor: &Sensor) -> bool {
+        {
+            let now = Instant::now();
+            let mut recorded = self.recorded.lock().expect("Poisoned mutex");
+            let due = match recorded.get(&sensor.address) {
+                Some(at) => now.duration_since(*at) >= self.poll_interval,
+                None => true
+            };
+            if due {
+                match self.db.record_device(sensor, Utc::now().naive_utc()) {
+                    Ok(()) => { recorded.insert(sensor.address.clone(), now); },
+                    Err(err) => println!("Could not record device {}: {:?}", sensor.address, err)
+                }
+            }
+        }
+
+        match self.db.get_device_status(&sensor.address) {
+            Ok(DeviceStatus::Adopted) => true,
+            Ok(DeviceStatus::Pending) => !self.adopted_only,
+            Ok(DeviceStatus::Ignored) => false,
+            Err(err) => {
+                println!("Could not get the status of device {}: {:?}", sensor.address, err);
+                false
+            }
+        }
+    }
+
+    /// Runs the commands queued by the HTTP handlers on the connected sensors
+    pub fn execute_commands(&self, queue: &CommandQueue) {
+        for request in queue.take_all() {
+            let sensors = self.sensors.lock().expect("Poisoned mutex");
+            let sensor = sensors.iter()
+                .find(|sensor| sensor.peripheral().address().to_string() == request.address);
+
+            let result = match sensor {
+                Some(sensor) => request.commands.iter()
+                    .try_for_each(|command| {
+                        println!("Sending {:?} to {}", command, request.address);
+                        sensor.command(command)
+                    })
+                    .map_err(CommandError::Driver),
+                None => Err(CommandError::Offline)
+            };
+            request.respond(result);
+        }
+    }
+
+    /// Stores the readings of a polled or broadcasting sensor, creating the sensor on first sight
+    fn store_readings(&self, sensor_data: &Sensor, readings: &[SensorReading], now: chrono::DateTime<Utc>) -> D::SensorHandle {
+        let name_str = sensor_data.name.clone().unwrap_or_else(|| "???".to_string());
+        let summary: Vec<String> = readings.iter()
+            .map(|reading| format!("{}: {:?}", reading.symbol(), reading.value()))
+            .collect();
+        println!("[{}] {}", name_str, summary.join(", "));
+
+        self.db.create_sensor_if_not_exists(sensor_data)
+            .expect("Could not ensure that the sensor exists in the database");
+        let handle = self.db.get_sensor_by_addr(sensor_data.address.clone()).expect("Failed to get handle to just added sensor");
+        // Renamed remotely and rebooted since
+        if self.db.get_sensor_by_handle(&handle).ok().map(|stored| stored.name) != Some(sensor_data.name.clone()) {
+            if let Err(err) = self.db.set_sensor_name(&handle, sensor_data.name.clone()) {
+                println!("Could not update the name of {}: {:?}", sensor_data.address, err);
             }
         }
+
+        for reading in readings.iter() {
+            if let SensorReading::Unknown = reading {
+                continue;
+            }
+            ingest::ensure_kind_registered(&self.db, reading);
+
+            let previous = self.db.get_latest_reading(&handle, reading.symbol().to_string(), false).ok();
+            let quality = self.validator.check(now, reading, previous.as_ref());
+            if quality.is_suspect() {
+                println!("[{}] Suspect reading {:?}: {:?}", name_str, reading, quality);
+            }
+
+            loop {
+                match self.db.add_reading(&handle, now.naive_utc(), reading, quality) {
+                    Ok(_) => break,
+                    Err(DatabaseError::Busy) => thread::sleep(Duration::from_secs(1)),
+                    Err(err) => panic!("Could not insert reading {:?} due to {:?}", reading, err)
+                }
+            }
+        }
+
+        handle
     }
 
-    pub fn try_poll_sensor(&self, sensor: &AlphaSensor<P>) -> bool {
+    /// Handles the readings broadcast in the advertisement of the peripheral.
+    /// Returns false when the peripheral does not broadcast any, so it may be connected to instead.
+    pub fn on_advertisement(&self, peripheral: &P) -> bool {
+        let advertisement = self.advertisement(peripheral);
+        let (family, mut broadcast) = match self.drivers.decode(&advertisement) {
+            Some(decoded) => decoded,
+            None => return false
+        };
+        if let Some(rssi) = advertisement.rssi {
+            broadcast.telemetry.push((TelemetryKind::Rssi, rssi as f32));
+        }
+
+        let sensor = Sensor {
+            family,
+            address: peripheral.address().to_string(),
+            name: advertisement.local_name
+        };
+        let now = Instant::now();
+
+        let mut passive = self.passive.lock().expect("Poisoned mutex");
+        if !self.admit(&sensor) {
+            if let Some(previous) = passive.remove(&sensor.address) {
+                let _ = self.state.write().expect("Poisoned RwLock").remove(&previous.sensor);
+            }
+            return true;
+        }
+        let fresh = match passive.get_mut(&sensor.address) {
+            // The same advertisement is repeated many times, only new readings are stored once per poll interval
+            Some(previous) => {
+                previous.seen_at = now;
+                (broadcast.counter.is_none() || broadcast.counter != previous.counter)
+                    && now.duration_since(previous.stored_at) >= self.poll_interval
+            },
+            None => true
+        };
+        if !fresh {
+            return true;
+        }
+
+        {
+            let mut state = self.state.write().expect("Poisoned RwLock");
+            if let Some(previous) = passive.get(&sensor.address) {
+                // The name may come later in the scan response
+                let _ = state.remove(&previous.sensor);
+            }
+            state.add(sensor.clone());
+        }
+
+        let stored_at = Utc::now();
+        let handle = self.store_readings(&sensor, &broadcast.readings, stored_at);
+        self.store_telemetry(&handle, stored_at, broadcast.telemetry);
+        passive.insert(sensor.address.clone(), PassiveSensor { sensor, counter: broadcast.counter, stored_at: now, seen_at: now });
+        true
+    }
+
+    /// btleplug does not report the broadcasting sensors as lost, so the ones not heard of
+    /// for a few poll intervals go offline until they are seen again
+    pub fn expire_passive(&self) {
+        let now = Instant::now();
+        let timeout = self.poll_interval * PASSIVE_SENSOR_TIMEOUT_POLLS;
+        let mut passive = self.passive.lock().expect("Poisoned mutex");
+        let silent: Vec<String> = passive.iter()
+            .filter(|(_, sensor)| now.duration_since(sensor.seen_at) > timeout)
+            .map(|(address, _)| address.clone())
+            .collect();
+
+        for address in silent {
+            if let Some(lost) = passive.remove(&address) {
+                let _ = self.state.write().expect("Poisoned RwLock").remove(&lost.sensor);
+                println!("{:?} stopped broadcasting", lost.sensor.name);
+            }
+        }
+    }
+
+    /// Signal strength of the sensors polled since the last call
+    pub fn take_signal_strengths(&self) -> Vec<(BDAddr, i8)> {
+        self.signal.lock().expect("Poisoned mutex").drain().collect()
+    }
+
+    /// Telemetry is best effort, a sensor which cannot report it is still polled
+    fn record_telemetry(&self, handle: &D::SensorHandle, now: chrono::DateTime<Utc>, sensor: &dyn ConnectedSensor<P>) {
+        let mut telemetry = Vec::new();
+        let adapter = self.adapters.lock().expect("Poisoned mutex").get(&sensor.peripheral().address()).cloned();
+        if let Some(rssi) = adapter.and_then(|adapter| rssi::read(&adapter, sensor.peripheral().address())) {
+            telemetry.push((TelemetryKind::Rssi, rssi as f32));
+            self.signal.lock().expect("Poisoned mutex").insert(sensor.peripheral().address(), rssi);
+        }
+        match sensor.supply_voltage() {
+            Ok(voltage) => telemetry.push((TelemetryKind::BatteryVoltage, voltage)),
+            Err(SensorDriverError::Unsupported) => {},
+            Err(err) => println!("Could not read supply voltage: {:?}", err)
+        }
+        self.store_telemetry(handle, now, telemetry);
+    }
+
+    fn store_telemetry(&self, handle: &D::SensorHandle, now: chrono::DateTime<Utc>, telemetry: Vec<(TelemetryKind, f32)>) {
+        for (kind, value) in telemetry {
+            if let Err(err) = self.db.add_telemetry(handle, now.naive_utc(), kind, value) {
+                println!("Could not store {} telemetry: {:?}", kind.name(), err);
+            }
+        }
+    }
+
+    pub fn try_poll_sensor(&self, sensor: &dyn ConnectedSensor<P>) -> bool {
+        // Ignored or no longer adopted since connected
+        let sensor_data = Self::domain_sensor(sensor);
+        if !self.admit(&sensor_data) {
+            println!("{} is not adopted", sensor_data.address);
+            let _ = self.state.write().expect("Poisoned RwLock").remove(&sensor_data);
+            sensor.disconnect();
+            return false;
+        }
+
         println!("Polling sensor...");
         match sensor.poll() {
-            Ok(reading) => {
+            Ok(readings) => {
                 println!("Polling ok");
-                let now = Utc::now().naive_utc();
-                let properties = sensor.peripheral.properties();
-                let name_str = match properties.local_name.clone() {
-                    Some(s) => s,
-                    None => "???".to_string()
-                };
-                println!("[{}] Temperature: {}C, Humidity: {}%", name_str, reading.temperature, reading.humidity);
-
-                let sensor_data = Sensor {
-                    family: SensorFamily::Alpha,
-                    address: properties.address.to_string(),
-                    name: properties.local_name
-                };
-
-                self.db.create_sensor_if_not_exists(&sensor_data)
-                    .expect("Could not ensure that the sensor exists in the database");
-                let handle = self.db.get_sensor_handle(&sensor_data).expect("Failed to get handle to just added sensor");
-
-                for reading in [
-                    SensorReading::Temperature(reading.temperature as i32),
-                    SensorReading::Humidity(reading.humidity)
-                ].iter() {
-                    loop {
-                        match self.db.add_reading(&handle, now, reading) {
-                            Ok(_) => break,
-                            Err(DatabaseError::Busy) => thread::sleep(Duration::from_secs(1)),
-                            Err(err) => panic!("Could not insert reading {:?} due to {:?}", reading, err)
-                        }
-                    }
-                }
-
+                let now = Utc::now();
+                let handle = self.store_readings(&sensor_data, &readings, now);
+                self.record_telemetry(&handle, now, sensor);
                 true
             }
-            Err(AlphaSensorPollError::SendFailed) => {
+            Err(SensorDriverError::SendFailed) => {
                 println!("Polling err");
+                sensor.disconnect();
                 let mut to_inspect = self.to_inspect.lock().unwrap();
-                to_inspect.push(sensor.peripheral.clone());
+                to_inspect.push(sensor.peripheral().clone());
                 println!("Could not communicate with sensor");
                 false
             }
             Err(err) => {
                 println!("Could not poll sensor data! {:?}", err);
-                true
+                // A sensor which does not even answer the ping is connected again
+                match sensor.command(&SensorCommand::Ping) {
+                    Ok(()) | Err(SensorDriverError::Unsupported) => true,
+                    Err(err) => {
+                        println!("Sensor does not respond ({:?}), reconnecting", err);
+                        sensor.disconnect();
+                        self.to_inspect.lock().unwrap().push(sensor.peripheral().clone());
+                        false
+                    }
+                }
             }
         }
     }
@@ -240,65 +607,116 @@ impl<P: Peripheral, D: Database, S: SensorsState> BleMaster<P, D, S> {
 
 type StatePtr<S> = Arc<RwLock<Box<S>>>;
 
-fn build_http<D: Database<SensorHandle=i32> + Send + Clone + 'static, S: SensorsState + Sync + Send + 'static>(db: D, state: StatePtr<S>) -> actix_web::dev::Server {
+fn build_http<D: Database<SensorHandle=i32> + Send + Clone + 'static, S: SensorsState + Sync + Send + 'static>(
+    db: D,
+    state: StatePtr<S>,
+    config: HttpConfig,
+    telemetry: web::Data<RwLock<TelemetryConfig>>,
+    commands: CommandQueue,
+    shutdown_timeout: Duration)
+-> Result<Vec<actix_web::dev::Server>, String> {
+    let config_bind = config.bind.clone();
+    let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
     let (tx, rx) = mpsc::channel();
 
     thread::spawn(move || {
         let sys = System::new("http-server");
+        let bind = config.bind.clone();
+        let redirect_bind = config.tls.as_ref().and_then(|tls| tls.redirect_bind.clone());
+        let read = if config.anonymous_read { None } else { Some(TokenScope::Read) };
 
         let srv = HttpServer::new(move || {
-                let sensors_scope: Scope = web::scope("/api/sensors")
-                    .service(web::resource("/list")
-                        .route(web::get().to(api::sensors_list::<D>))
-                    )
-                    .service(web::resource("/{id}/readings")
-                        .route(web::get().to(api::sensor_readings::<D>))
-                    )
-                    .service(web::resource("/{id}")
-                        .route(web::get().to(api::sensor_status::<D, S>))
-                    )
-                    .service(web::resource("/{id}/readings/after/{timestamp}")
-                        .route(web::get()
-                            .to(api::sensor_readings_after_time::<D>)
-                            .to(api::sensor_readings_after_time_utc::<D>)
-                        )
-                    )
-                    .service(web::resource("/{id}/latest/{kind}")
-                        .route(web::get().to(api::sensor_latest_reading::<D>))
-                    )
-                    .default_service(web::route().to(|| HttpResponse::NotFound()));
-
                 let frontend_scope: Scope = web::scope("/")
-                    .service(actix_files::Files::new("", "./app/")
+                    .service(actix_files::Files::new("", &config.app_dir)
                         .use_etag(true)
                         .index_file("index.html")
                         .default_handler(web::route().to(api::not_found)));
 
                 App::new()
-                    .service(api::status)
-                    .service(sensors_scope)
+                    .configure(|cfg| routes::configure::<D, S>(cfg, read))
                     .service(frontend_scope)
                     .wrap(Logger::default())
                     .data(db.clone())
                     .data(state.clone())
-            })
-            .bind("0.0.0.0:80")?
-            .shutdown_timeout(60)
-            .run();
+                    .app_data(telemetry.clone())
+                    .data(commands.clone())
+            });
+        let srv = match tls {
+            Some(tls) => srv.bind_rustls(&bind, tls)?,
+            None => srv.bind(&bind)?
+        };
+        let mut servers = vec![srv
+            // Stopped by the main loop once the sensors are disconnected
+            .disable_signals()
+            .shutdown_timeout(shutdown_timeout.as_secs())
+            .run()];
+
+        if let Some(redirect_bind) = redirect_bind {
+            let https_port = bind.rsplit(':').next()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(443);
+            servers.push(tls::redirect_server(&redirect_bind, https_port)?);
+        }
 
-        let _ = tx.send(srv);
+        let _ = tx.send(servers);
         sys.run()
     });
 
-    rx.recv().unwrap()
+    rx.recv().map_err(|_| format!("Could not start the HTTP server on {}", config_bind))
 }
 
-async fn wait_for_keyboard_interrupt(mut wait_action: Box<dyn FnMut()>) -> () {
-    let stop = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop)).expect("Failed to register SIGINT hook");
+/// Moves the sensors of an adapter which stopped responding to the other ones, an owned adapter is reset first.
+/// The others are tried again later, see `retry_adapter`.
+fn fail_over<P, C, D, S>(adapters: &mut AdapterPool<P, C>, master: &mut BleMaster<P, D, S>, index: usize, reset: impl Fn(&str, bool) -> Option<C>)
+where
+    P: Peripheral,
+    C: Central<P> + 'static,
+    D: Database,
+    S: SensorsState
+{
+    let orphans = adapters.fail(index);
+    for addr in orphans.iter() {
+        master.on_disconnect(*addr);
+    }
 
-    while !stop.load(Ordering::Relaxed) {
-        wait_action();
+    if adapters.is_owned(index) {
+        println!("Resetting adapter {}...", adapters.name(index));
+        match reset(adapters.name(index), true) {
+            Some(central) => {
+                adapters.replace(index, central);
+                if !adapters.start_scan(index) {
+                    adapters.fail(index);
+                }
+            },
+            None => println!("Could not reset adapter {}", adapters.name(index))
+        }
+    }
+
+    for addr in orphans {
+        if let Some((assigned, peripheral)) = adapters.assign(addr) {
+            master.on_discovered(peripheral, adapters.name(assigned));
+        }
+    }
+}
+
+/// Connects to an adapter which stopped responding once more, e.g. one used by another program
+/// for a while, which would otherwise never be used again
+fn retry_adapter<P, C>(adapters: &mut AdapterPool<P, C>, index: usize, reset: impl Fn(&str, bool) -> Option<C>)
+where
+    P: Peripheral,
+    C: Central<P> + 'static
+{
+    println!("Retrying adapter {}...", adapters.name(index));
+    match reset(adapters.name(index), adapters.is_owned(index)) {
+        Some(central) => {
+            adapters.replace(index, central);
+            if adapters.start_scan(index) {
+                println!("Using adapter {} again", adapters.name(index));
+            } else {
+                adapters.postpone(index);
+            }
+        },
+        None => adapters.postpone(index)
     }
 }
 
@@ -306,69 +724,252 @@ async fn wait_for_keyboard_interrupt(mut wait_action: Box<dyn FnMut()>) -> () {
 async fn main() -> Result<(), String> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    let database = SqliteDatabase::new("./database.sqlite3");
+    let options = cli::Options::from_args();
+    let config = Config::load(&options.config)?;
+
+    match options.command {
+        None | Some(cli::Command::Run) => run(&options.config, config, Mode::Full).await,
+        Some(cli::Command::Serve) => run(&options.config, config, Mode::ApiOnly).await,
+        Some(cli::Command::Collect) => run(&options.config, config, Mode::CollectorOnly).await,
+        Some(cli::Command::Migrate) => cli::execute(cli::Command::Migrate, &SqliteDatabase::open(&config.database)),
+        // The other commands read or write the schema, which has to be up to date after an upgrade
+        Some(command) => cli::execute(command, &SqliteDatabase::new(&config.database))
+    }
+}
+
+/// Parts of the server started by `run`
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Full,
+    /// Serves the REST API and the UI from the database, without touching BLE
+    ApiOnly,
+    /// Polls the sensors without the HTTP server
+    CollectorOnly
+}
+
+async fn run(config_path: &Path, config: Config, mode: Mode) -> Result<(), String> {
+    let signals = Signals::register();
+    let database = SqliteDatabase::new(&config.database);
 
     let app_state = Arc::new(RwLock::new(Box::new(AppState::new())));
 
-    let srv = build_http(database.clone(), app_state.clone());
-    let manager = Manager::new().unwrap();
-    let central = get_central(&manager);
+    let commands = CommandQueue::default();
+    let telemetry = web::Data::new(RwLock::new(config.telemetry.clone()));
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let servers = match mode {
+        Mode::CollectorOnly => Vec::new(),
+        _ => build_http(database.clone(), app_state.clone(), config.http.clone(), telemetry.clone(), commands.clone(), shutdown_timeout)?
+    };
+
+    // Forwarding only makes sense where the readings are collected
+    let forwarder = match (&mode, &config.forward) {
+        (Mode::ApiOnly, _) | (_, None) => None,
+        (_, Some(forward)) => {
+            println!("Forwarding the readings to {}", forward.url);
+            Some(Forwarder::start(database.clone(), forward.clone()))
+        }
+    };
+
+    let result = match mode {
+        Mode::ApiOnly => {
+            println!("Serving the API only, without BLE...");
+            while !signals.stop_requested() {
+                if let Some(reloaded) = reload_config(&signals, config_path) {
+                    *telemetry.write().expect("Poisoned RwLock") = reloaded.telemetry;
+                }
+                // Yields to the HTTP workers of this thread instead of blocking them
+                actix_web::rt::time::delay_for(Duration::from_secs(1)).await;
+            }
+            Ok(())
+        },
+        _ => collect(&signals, config_path, &config, database, app_state, &commands, &telemetry)
+    };
+
+    println!("Stopping the server...");
+    if let Some(forwarder) = forwarder {
+        forwarder.stop();
+    }
+    for srv in servers {
+        // Waits for the requests in progress, at most for the shutdown timeout
+        srv.stop(true).await;
+    }
+    result
+}
+
+/// Configuration reloaded on SIGHUP, None when there was none since the last call or the file is invalid
+fn reload_config(signals: &Signals, config_path: &Path) -> Option<Config> {
+    if !signals.take_reload() {
+        return None;
+    }
+    match Config::load(config_path) {
+        Ok(reloaded) => {
+            println!("Reloaded {}, database, http and adapter settings take effect after a restart", config_path.display());
+            Some(reloaded)
+        },
+        Err(err) => {
+            println!("Keeping the current configuration: {}", err);
+            None
+        }
+    }
+}
+
+/// Discovers and polls the sensors until SIGINT/SIGTERM, then disconnects them
+fn collect(
+    signals: &Signals,
+    config_path: &Path,
+    config: &Config,
+    database: SqliteDatabase,
+    app_state: StatePtr<AppState>,
+    commands: &CommandQueue,
+    telemetry: &web::Data<RwLock<TelemetryConfig>>)
+-> Result<(), String> {
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let manager = Manager::new().map_err(|err| format!("BLE is not available: {:?}", err))?;
+    let (mut adapters, events) = AdapterPool::new(config.ble.balance);
+    let service_data = ServiceDataMonitor::default();
+    for (name, central, owned) in get_centrals(&manager, &config.ble) {
+        println!("Using adapter {}{}", name, if owned { " (owned)" } else { "" });
+        service_data.listen(&name);
+        adapters.add(name, central, owned);
+    }
+    if adapters.is_empty() {
+        return Err("No BLE adapters".to_string());
+    }
+
+    // The listener of an unplugged adapter is gone, it's started again once the adapter is back
+    let reset = |name: &str, owned: bool| {
+        let central = reset_central(&manager, name, owned)?;
+        service_data.listen(name);
+        Some(central)
+    };
 
     println!("Starting BLE scan...");
-    central.start_scan().expect("Unable to start scan");
+    let unresponsive: Vec<usize> = (0..adapters.len())
+        .filter(|&index| !adapters.start_scan(index))
+        .collect();
     println!("Scan started");
 
     thread::sleep(Duration::from_secs(2));
 
-    println!("Getting the event receiver");
-    let events = central.event_receiver().unwrap();
-    let mut master = BleMaster::new(database, app_state);
+    let mut drivers = DriverRegistry::new();
+    drivers.register(Box::new(AlphaDriver { framed: config.ble.alpha_framed }));
+    drivers.register(Box::new(BetaDriver));
+    drivers.register_decoder(Box::new(XiaomiDecoder));
+    let poll_interval = Duration::from_secs(config.ble.poll_interval_secs);
+    let mut master = BleMaster::new(database, app_state, Validator::new(config.validation.clone()), drivers, service_data.clone(), poll_interval, config.ble.adopted_only);
+    for index in unresponsive {
+        fail_over(&mut adapters, &mut master, index, reset);
+    }
 
     let mut prev_poll = Instant::now();
     let mut prev_inspect = Instant::now();
 
-    let inspect_interval_secs = 1;
-    let poll_interval_secs = 5 * 60;
+    let mut inspect_interval_secs = config.ble.inspect_interval_secs;
+    let mut poll_interval_secs = config.ble.poll_interval_secs;
 
     println!("Running the app...");
-    wait_for_keyboard_interrupt(Box::new(move || {
-        match events.recv_timeout(Duration::from_secs(1)) {
-            Ok(event) => match event {
+    commands.open();
+    while !signals.stop_requested() {
+        if let Some(reloaded) = reload_config(signals, config_path) {
+            inspect_interval_secs = reloaded.ble.inspect_interval_secs;
+            poll_interval_secs = reloaded.ble.poll_interval_secs;
+            master.reconfigure(
+                Validator::new(reloaded.validation),
+                Duration::from_secs(poll_interval_secs),
+                reloaded.ble.adopted_only);
+            *telemetry.write().expect("Poisoned RwLock") = reloaded.telemetry;
+        }
+
+        match adapters.receive(&events, Duration::from_secs(1)) {
+            Some((index, Some(event))) => match event {
                 CentralEvent::DeviceDiscovered(addr) => {
-                    println!("{} discovered", addr);
-                    match central.peripheral(addr) {
-                        Some(peripheral) => { 
-                            prev_inspect = Instant::now();
-                            master.on_discovered(peripheral);
+                    println!("{} discovered by {}", addr, adapters.name(index));
+                    match adapters.peripheral(index, addr) {
+                        Some(peripheral) => {
+                            if master.on_advertisement(&peripheral) {
+                                // Broadcasting, nothing to connect to
+                            } else if let Some(family) = master.recognizes(&peripheral) {
+                                println!("{} looks like a {:?} sensor, waiting for an advertisement it can decode", addr, family);
+                            } else if master.identifies(&peripheral) {
+                                // Another adapter may see it too, the balancing picks the one to connect through
+                                if let Some((assigned, peripheral)) = adapters.assign(addr) {
+                                    prev_inspect = Instant::now();
+                                    master.on_discovered(peripheral, adapters.name(assigned));
+                                }
+                            }
                         },
                         None => println!("* Failed to get the peripheral")
                     }
                 },
+                CentralEvent::DeviceUpdated(addr) => {
+                    if let Some(peripheral) = adapters.peripheral(index, addr) {
+                        master.on_advertisement(&peripheral);
+                    }
+                },
+                // Broadcasting sensors are never connected, so they only ever get lost.
+                // Not every backend reports it, they are also expired after a few silent poll intervals
+                CentralEvent::DeviceLost(addr) if !matches!(adapters.assigned_to(addr), Some(assigned) if assigned != index) => {
+                    adapters.release(addr);
+                    master.on_disconnect(addr);
+                },
                 CentralEvent::DeviceDisconnected(addr) => {
+                    adapters.release(addr);
                     master.on_disconnect(addr);
                     println!("Rescan after disconnect");
-                    central.start_scan().expect("Failed to start scan");
+                    if !adapters.start_scan(index) {
+                        fail_over(&mut adapters, &mut master, index, reset);
+                    }
                 },
                 _ => {}
             },
-            _ => {}
+            Some((index, None)) => fail_over(&mut adapters, &mut master, index, reset),
+            None => {}
         };
 
         let now = Instant::now();
         let inspect_dt = now.duration_since(prev_inspect);
         if inspect_dt.as_secs() >= inspect_interval_secs {
-            master.pop_and_inspect();
+            match master.pop_and_inspect() {
+                Some((addr, Inspection::Skipped)) => adapters.release(addr),
+                Some((addr, Inspection::Connected)) => adapters.on_connected(addr),
+                // The adapter did connect, the device is to blame
+                Some((addr, Inspection::Rejected)) => {
+                    adapters.on_connected(addr);
+                    adapters.release(addr);
+                },
+                Some((addr, Inspection::Unreachable)) => {
+                    if let Some(failing) = adapters.on_connect_failed(addr) {
+                        fail_over(&mut adapters, &mut master, failing, reset);
+                    }
+                },
+                None => {}
+            }
             prev_inspect = Instant::now();
         }
+        master.execute_commands(commands);
+        for index in adapters.due_for_retry(now) {
+            retry_adapter(&mut adapters, index, reset);
+        }
+
         let poll_dt = now.duration_since(prev_poll);
         if poll_dt.as_secs() >= poll_interval_secs {
             prev_poll = Instant::now();
             let mut sensors = master.sensors.lock().expect("Poisoned mutex");
-            sensors.retain(|sensor| master.try_poll_sensor(sensor));
+            sensors.retain(|sensor| master.try_poll_sensor(sensor.as_ref()));
+            drop(sensors);
+            master.expire_passive();
+            for (addr, rssi) in master.take_signal_strengths() {
+                adapters.note_rssi(addr, rssi);
+            }
         }
-    })).await;
+    }
 
-    println!("Stopping the server...");
-    srv.clone().stop(true).await;
+    println!("Disconnecting the sensors...");
+    let deadline = Instant::now() + shutdown_timeout;
+    for request in commands.close() {
+        request.respond(Err(CommandError::Dropped));
+    }
+    adapters.stop_scan_all();
+    master.shutdown(deadline);
     Ok(())
 }
@@ -0,0 +1,608 @@
+use actix_web::HttpResponse;
+use serde_json::{json, Map, Value};
+
+use crate::api_v2;
+
+/// Description of the REST API in OpenAPI 3.0, kept next to the route registration in `routes`
+pub fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Air sensor API",
+            "description": "Sensors, their readings and the devices found while scanning",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths(),
+        "components": {
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "API token created with `server tokens create`. A `read` token is only needed when `anonymous_read` is off, `write` includes `read` and `admin` includes `write`."
+                }
+            },
+            "schemas": schemas()
+        }
+    })
+}
+
+//#[get("/api/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(spec())
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn array_of(name: &str) -> Value {
+    json!({ "type": "array", "items": schema_ref(name) })
+}
+
+fn string_enum(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn date_time() -> Value {
+    json!({ "type": "string", "format": "date-time" })
+}
+
+fn path_parameter(name: &str, description: &str, schema: Value, example: &str) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "description": description, "schema": schema, "example": example })
+}
+
+fn sensor_id() -> Value {
+    path_parameter("id", "Sensor id", json!({ "type": "integer" }), "1")
+}
+
+fn device_address() -> Value {
+    path_parameter("address", "Bluetooth address of the device", json!({ "type": "string" }), "A4:C1:38:12:34:56")
+}
+
+fn query_parameter(name: &str, description: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "query", "required": false, "description": description, "schema": schema })
+}
+
+fn required_query_parameter(name: &str, description: &str, example: &str) -> Value {
+    json!({ "name": name, "in": "query", "required": true, "description": description, "schema": { "type": "string" }, "example": example })
+}
+
+fn flag(name: &str, description: &str) -> Value {
+    query_parameter(name, description, json!({ "type": "boolean", "default": false }))
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": schema } } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+fn no_content(description: &str) -> Value {
+    json!({ "description": description })
+}
+
+/// An operation open to anyone, or to `read` tokens when the anonymous reads are off
+fn read(summary: &str, parameters: Vec<Value>, responses: Value) -> Value {
+    json!({
+        "summary": summary,
+        "parameters": parameters,
+        "security": [{}, { "bearer": [] }],
+        "responses": responses
+    })
+}
+
+/// An operation requiring a token with the given scope
+fn change(summary: &str, scope: &str, parameters: Vec<Value>, body: Option<Value>, responses: Value) -> Value {
+    let mut operation = json!({
+        "summary": summary,
+        "description": format!("Requires a token with the `{}` scope.", scope),
+        "parameters": parameters,
+        "security": [{ "bearer": [] }],
+        "x-scope": scope,
+        "responses": responses
+    });
+    if let Some(body) = body {
+        operation["requestBody"] = body;
+    }
+    operation
+}
+
+fn readings_parameters() -> Vec<Value> {
+    vec![
+        query_parameter("format", "Overrides the Accept header", string_enum(&["json", "csv", "ndjson"])),
+        flag("wide", "One row per sample with all the kinds side by side instead of one row per reading"),
+        flag("derived", "Adds dew point, heat index and absolute humidity, only in the long layout"),
+        flag("raw", "Skips the sensor calibration"),
+        flag("include_suspect", "Keeps the readings flagged as implausible")
+    ]
+}
+
+fn readings_responses() -> Value {
+    json!({
+        "200": {
+            "description": "Readings, oldest first. The wide layout has one property per kind instead.",
+            "content": {
+                "application/json": { "schema": array_of("TimestampedSensorReading") },
+                "application/x-ndjson": { "schema": schema_ref("TimestampedSensorReading") },
+                "text/csv": { "schema": { "type": "string" } }
+            }
+        },
+        "400": no_content("Unsupported format, or derived readings requested in the wide layout")
+    })
+}
+
+fn sensor_resource_parameters() -> Vec<Value> {
+    vec![
+        flag("raw", "Skips the sensor calibration"),
+        flag("include_suspect", "Considers the readings flagged as implausible"),
+        flag("derived", "Adds dew point, heat index and absolute humidity computed from the latest temperature and humidity")
+    ]
+}
+
+fn paths() -> Value {
+    json!({
+        "/status": {
+            "get": {
+                "summary": "Tells that the server is up",
+                "security": [{}],
+                "responses": {
+                    "200": { "description": "Up", "content": { "text/plain": { "schema": { "type": "string" } } } }
+                }
+            }
+        },
+        "/api/openapi.json": {
+            "get": {
+                "summary": "This document",
+                "security": [{}],
+                "responses": { "200": json_response("OpenAPI document", json!({ "type": "object" })) }
+            }
+        },
+        "/api/kinds": {
+            "get": read("Kinds of readings, stored and derived", vec![],
+                json!({ "200": json_response("Reading kinds", array_of("ReadingKind")) }))
+        },
+        "/api/ingest": {
+            "post": change("Stores the readings pushed by a remote collector, a batch sent again is not stored twice", "write",
+                vec![],
+                Some(json_body(schema_ref("IngestBatch"))),
+                json!({
+                    "200": json_response("What was stored", schema_ref("IngestReport")),
+                    "503": no_content("The database is busy, to be retried later")
+                }))
+        },
+        "/api/sensors/list": {
+            "get": read("Sensors stored in the database", vec![],
+                json!({ "200": json_response("Sensors", array_of("Sensor")) }))
+        },
+        "/api/sensors/{id}": {
+            "get": read("Connection and battery status of a sensor", vec![sensor_id()],
+                json!({
+                    "200": json_response("Status", schema_ref("SensorStatus")),
+                    "404": no_content("No such sensor")
+                }))
+        },
+        "/api/sensors/{id}/readings": {
+            "get": read("All the readings of a sensor", [vec![sensor_id()], readings_parameters()].concat(), readings_responses())
+        },
+        "/api/sensors/{id}/readings/after/{timestamp}": {
+            "get": read("Readings of a sensor taken after the timestamp",
+                [
+                    vec![
+                        sensor_id(),
+                        path_parameter("timestamp", "RFC 3339 timestamp, exclusive", date_time(), "2021-01-01T00:00:00Z")
+                    ],
+                    readings_parameters()
+                ].concat(),
+                readings_responses())
+        },
+        "/api/sensors/{id}/latest/{kind}": {
+            "get": read("Latest reading of a kind, derived kinds are computed from the latest temperature and humidity",
+                vec![
+                    sensor_id(),
+                    path_parameter("kind", "Reading kind symbol", json!({ "type": "string" }), "T"),
+                    flag("raw", "Skips the sensor calibration"),
+                    flag("include_suspect", "Considers the readings flagged as implausible")
+                ],
+                json!({
+                    "200": json_response("Reading", schema_ref("TimestampedSensorReading")),
+                    "404": no_content("No such sensor or no reading of the kind")
+                }))
+        },
+        "/api/sensors/{id}/telemetry": {
+            "get": read("Health of a sensor over time",
+                vec![
+                    sensor_id(),
+                    query_parameter("kind", "Only this kind", schema_ref("TelemetryKind")),
+                    query_parameter("from", "Inclusive", date_time()),
+                    query_parameter("to", "Exclusive", date_time())
+                ],
+                json!({
+                    "200": json_response("Telemetry, oldest first", array_of("TimestampedTelemetry")),
+                    "404": no_content("No such sensor")
+                }))
+        },
+        "/api/sensors/{id}/config": {
+            "post": change("Sends configuration commands to a connected sensor", "write",
+                vec![sensor_id()],
+                Some(json_body(schema_ref("SensorConfig"))),
+                json!({
+                    "204": no_content("Applied"),
+                    "400": no_content("Nothing to configure or an invalid value"),
+                    "404": no_content("No such sensor"),
+                    "409": no_content("The sensor is not connected"),
+                    "501": no_content("The sensor firmware does not support remote configuration"),
+                    "502": no_content("The sensor did not respond"),
+                    "503": no_content("BLE is not running")
+                }))
+        },
+        "/api/sensors/{id}/calibrations": {
+            "get": read("Calibrations of a sensor", vec![sensor_id()],
+                json!({ "200": json_response("Calibrations", array_of("Calibration")) })),
+            "post": change("Corrects the readings of a kind taken since the given moment", "write",
+                vec![sensor_id()],
+                Some(json_body(schema_ref("Calibration"))),
+                json!({
+                    "201": json_response("Stored", schema_ref("Calibration")),
+                    "400": no_content("The kind cannot be calibrated"),
+                    "404": no_content("No such sensor")
+                }))
+        },
+        "/api/v2/sensors": {
+            "get": read("Sensors with their id, status and latest readings", sensor_resource_parameters(),
+                json!({ "200": json_response("Sensors", array_of("SensorResource")) }))
+        },
+        "/api/v2/sensors/{id}": {
+            "get": read("A sensor with its status and latest readings", [vec![sensor_id()], sensor_resource_parameters()].concat(),
+                json!({
+                    "200": json_response("Sensor", schema_ref("SensorResource")),
+                    "404": no_content("No such sensor")
+                }))
+        },
+        "/api/v2/compare": {
+            "get": read("Readings of several sensors averaged over the same time buckets",
+                vec![
+                    required_query_parameter("sensors", "Comma separated sensor ids", "1,2"),
+                    required_query_parameter("kinds", "Comma separated kind symbols, derived kinds included", "T,H"),
+                    query_parameter("from", "Inclusive, a day before `to` by default", date_time()),
+                    query_parameter("to", "Exclusive, now by default", date_time()),
+                    query_parameter("bucket_secs", "Length of the buckets, which start at multiples of it",
+                        json!({ "type": "integer", "default": 300, "minimum": 1, "maximum": api_v2::MAX_BUCKET_SECS })),
+                    query_parameter("reference", "One of the sensors, the others get their difference to it",
+                        json!({ "type": "integer" })),
+                    flag("raw", "Skips the sensor calibration"),
+                    flag("include_suspect", "Keeps the readings flagged as implausible")
+                ],
+                json!({
+                    "200": json_response("Aligned series", schema_ref("Comparison")),
+                    "400": no_content("Invalid sensors, kinds or range, or too many series or buckets"),
+                    "404": no_content("No such sensor")
+                }))
+        },
+        "/api/devices/discovered": {
+            "get": read("Devices waiting to be adopted or ignored", vec![],
+                json!({ "200": json_response("Pending devices, most recently seen first", array_of("DiscoveredDevice")) }))
+        },
+        "/api/devices/list": {
+            "get": read("Devices found while scanning",
+                vec![query_parameter("status", "Only the devices with this status", schema_ref("DeviceStatus"))],
+                json!({ "200": json_response("Devices, most recently seen first", array_of("DiscoveredDevice")) }))
+        },
+        "/api/devices/{address}/adopt": {
+            "post": change("Polls the device and stores its readings", "admin", vec![device_address()], None,
+                json!({ "204": no_content("Adopted"), "404": no_content("Never seen") }))
+        },
+        "/api/devices/{address}/ignore": {
+            "post": change("Never connects to the device nor stores it", "admin", vec![device_address()], None,
+                json!({ "204": no_content("Ignored"), "404": no_content("Never seen") }))
+        }
+    })
+}
+
+fn object(required: &[&str], properties: Value) -> Value {
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+fn schemas() -> Map<String, Value> {
+    let schemas = json!({
+        "SensorFamily": string_enum(&["Alpha", "Beta", "Xiaomi"]),
+        "TelemetryKind": string_enum(&["rssi", "battery_voltage", "battery_level"]),
+        "ReadingQuality": string_enum(&["good", "out_of_range", "rate_of_change"]),
+        "DeviceStatus": string_enum(&["pending", "adopted", "ignored"]),
+        "Sensor": object(&["family", "address", "name"], json!({
+            "family": schema_ref("SensorFamily"),
+            "address": { "type": "string" },
+            "name": { "type": "string", "nullable": true }
+        })),
+        "ReadingKind": object(&["symbol", "name", "unit", "precision"], json!({
+            "symbol": { "type": "string", "description": "Single letter, e.g. T or H" },
+            "name": { "type": "string" },
+            "unit": { "type": "string" },
+            "precision": { "type": "integer", "description": "Number of meaningful decimal places" }
+        })),
+        "TimestampedSensorReading": object(&["timestamp", "kind", "value", "quality"], json!({
+            "timestamp": date_time(),
+            "kind": { "type": "string", "description": "Symbol of the reading kind, `?` for a reading the server does not understand" },
+            "value": {
+                "oneOf": [{ "type": "number" }, { "type": "string", "enum": ["null"] }],
+                "description": "Whole numbers have no fractional part, the string `null` goes with the `?` kind"
+            },
+            "quality": schema_ref("ReadingQuality")
+        })),
+        "TimestampedTelemetry": object(&["timestamp", "kind", "value"], json!({
+            "timestamp": date_time(),
+            "kind": schema_ref("TelemetryKind"),
+            "value": { "type": "number" }
+        })),
+        "SensorStatus": object(&["status", "battery", "battery_voltage", "rssi"], json!({
+            "status": string_enum(&["Online", "Offline"]),
+            "battery": string_enum(&["ok", "low", "unknown"]),
+            "battery_voltage": { "allOf": [schema_ref("TimestampedTelemetry")], "nullable": true },
+            "rssi": { "allOf": [schema_ref("TimestampedTelemetry")], "nullable": true }
+        })),
+        "SensorResource": object(&["id", "family", "address", "name", "status", "battery", "battery_voltage", "rssi", "latest"], json!({
+            "id": { "type": "integer", "description": "Used in the paths of the other sensor routes" },
+            "family": schema_ref("SensorFamily"),
+            "address": { "type": "string" },
+            "name": { "type": "string", "nullable": true },
+            "status": string_enum(&["Online", "Offline"]),
+            "battery": string_enum(&["ok", "low", "unknown"]),
+            "battery_voltage": { "allOf": [schema_ref("TimestampedTelemetry")], "nullable": true },
+            "rssi": { "allOf": [schema_ref("TimestampedTelemetry")], "nullable": true },
+            "latest": {
+                "type": "array",
+                "items": schema_ref("TimestampedSensorReading"),
+                "description": "Latest reading of every kind, ordered by kind"
+            }
+        })),
+        "Comparison": object(&["bucket_secs", "timestamps", "reference", "series"], json!({
+            "bucket_secs": { "type": "integer" },
+            "timestamps": { "type": "array", "items": date_time(), "description": "Start of every bucket" },
+            "reference": { "type": "integer", "nullable": true },
+            "series": array_of("ComparedSeries")
+        })),
+        "ComparedSeries": object(&["sensor", "kind", "values", "difference"], json!({
+            "sensor": { "type": "integer" },
+            "kind": { "type": "string" },
+            "values": {
+                "type": "array",
+                "items": { "type": "number", "nullable": true },
+                "description": "Mean of every bucket, null for the buckets without readings"
+            },
+            "difference": {
+                "type": "array",
+                "items": { "type": "number", "nullable": true },
+                "nullable": true,
+                "description": "Values minus the ones of the reference, null for the reference itself and without one"
+            }
+        })),
+        "Calibration": object(&["kind", "effective_from"], json!({
+            "kind": { "type": "string" },
+            "offset": { "type": "number", "default": 0 },
+            "gain": { "type": "number", "default": 1 },
+            "effective_from": date_time()
+        })),
+        "SensorConfig": object(&[], json!({
+            "name": { "type": "string" },
+            "pin": { "type": "string" },
+            "sleep_ms": { "type": "integer" },
+            "reboot": { "type": "boolean", "default": false },
+            "identify": { "type": "boolean", "default": false }
+        })),
+        "DiscoveredDevice": object(&["address", "name", "family", "status", "first_seen", "last_seen"], json!({
+            "address": { "type": "string" },
+            "name": { "type": "string", "nullable": true },
+            "family": schema_ref("SensorFamily"),
+            "status": schema_ref("DeviceStatus"),
+            "first_seen": date_time(),
+            "last_seen": date_time()
+        })),
+        "IngestBatch": object(&["collector", "sensors"], json!({
+            "collector": { "type": "string" },
+            "sensors": array_of("IngestSensor")
+        })),
+        "IngestSensor": object(&["address", "family", "name"], json!({
+            "address": { "type": "string" },
+            "family": schema_ref("SensorFamily"),
+            "name": { "type": "string", "nullable": true },
+            "readings": array_of("IngestReading"),
+            "telemetry": array_of("IngestTelemetry")
+        })),
+        "IngestReading": object(&["timestamp", "kind", "value"], json!({
+            "timestamp": date_time(),
+            "kind": { "type": "string" },
+            "value": { "type": "number" },
+            "quality": schema_ref("ReadingQuality")
+        })),
+        "IngestTelemetry": object(&["timestamp", "kind", "value"], json!({
+            "timestamp": date_time(),
+            "kind": schema_ref("TelemetryKind"),
+            "value": { "type": "number" }
+        })),
+        "IngestReport": object(&["imported", "duplicates", "conflicts", "skipped", "telemetry"], json!({
+            "imported": { "type": "integer" },
+            "duplicates": { "type": "integer", "description": "Already stored, e.g. sent again after the response got lost" },
+            "conflicts": { "type": "integer", "description": "Already stored with a different value, which is kept" },
+            "skipped": { "type": "integer", "description": "Of a kind unknown to the server" },
+            "telemetry": { "type": "integer" }
+        }))
+    });
+    match schemas {
+        Value::Object(schemas) => schemas,
+        _ => unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, RwLock};
+
+    use actix_web::{http::Method, test, web, App};
+    use chrono::{TimeZone, Utc};
+    use serde::Serialize;
+
+    use super::*;
+    use crate::api::StatusResponse;
+    use crate::api_v2::SensorResource;
+    use crate::compare::{ComparedSeries, Comparison};
+    use crate::auth::{self, TokenScope};
+    use crate::calibration::Calibration;
+    use crate::commands::CommandQueue;
+    use crate::config::TelemetryConfig;
+    use crate::database::Database;
+    use crate::ingest::{IngestBatch, IngestReading, IngestReport, IngestSensor, IngestTelemetry};
+    use crate::routes;
+    use crate::sensor::*;
+    use crate::sqlite_database::SqliteDatabase;
+    use crate::AppState;
+
+    const METHODS: [&str; 4] = ["get", "post", "put", "delete"];
+
+    fn documented_operations() -> BTreeSet<(String, String)> {
+        spec()["paths"].as_object().unwrap()
+            .iter()
+            .flat_map(|(path, item)| item.as_object().unwrap()
+                .keys()
+                .filter(|method| METHODS.contains(&method.as_str()))
+                .map(move |method| (method.clone(), path.clone())))
+            .collect()
+    }
+
+    fn example_path(path: &str, operation: &Value) -> String {
+        operation["parameters"].as_array()
+            .into_iter()
+            .flatten()
+            .filter(|parameter| parameter["in"] == "path")
+            .fold(path.to_string(), |path, parameter| path.replace(
+                &format!("{{{}}}", parameter["name"].as_str().unwrap()),
+                parameter["example"].as_str().expect("Path parameters need an example")))
+    }
+
+    fn request(method: &str, uri: &str, token: &str) -> test::TestRequest {
+        test::TestRequest::with_uri(uri)
+            .method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap())
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .set_payload("{}")
+    }
+
+    #[test]
+    fn served_routes_match_the_document() {
+        let path = std::env::temp_dir().join(format!("airsensor-openapi-{}.sqlite3", std::process::id()));
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        let token = auth::generate_token();
+        db.add_token("test", &auth::hash_token(&token), TokenScope::Admin, Utc::now().naive_utc()).unwrap();
+        let state = Arc::new(RwLock::new(Box::new(AppState::new())));
+        let spec = spec();
+
+        // The routes are registered from the table only
+        let routes: BTreeSet<(String, String)> = routes::table::<SqliteDatabase, AppState>()
+            .iter()
+            .map(|route| (route.method.as_str().to_lowercase(), route.path.to_string()))
+            .collect();
+        let documented = documented_operations();
+        assert_eq!(routes, documented);
+
+        actix_web::rt::System::new("openapi-test").block_on(async move {
+            let mut app = test::init_service(App::new()
+                .configure(|cfg| routes::configure::<SqliteDatabase, AppState>(cfg, Some(TokenScope::Read)))
+                .data(db)
+                .data(state)
+                .app_data(web::Data::new(RwLock::new(TelemetryConfig::default())))
+                .data(CommandQueue::default())).await;
+
+            for (method, path) in documented.iter() {
+                let uri = example_path(path, &spec["paths"][path][method]);
+                let response = test::call_service(&mut app, request(method, &uri, &token).to_request()).await;
+                let status = response.status();
+                let body = test::read_body(response).await;
+                // The handlers answer 404 with a body, the scopes without one
+                let unrouted = status == 405 || (status == 404 && body.is_empty());
+                assert!(!unrouted, "{} {} is documented but not served", method, path);
+                assert_ne!(status, 401, "{} {} rejected the token", method, path);
+            }
+        });
+        let _ = std::fs::remove_file(path);
+    }
+
+    fn assert_properties<T: Serialize>(schema: &str, sample: &T) {
+        let serialized: BTreeSet<String> = serde_json::to_value(sample).unwrap()
+            .as_object().unwrap()
+            .keys().cloned().collect();
+        let documented: BTreeSet<String> = schemas()[schema]["properties"]
+            .as_object().unwrap_or_else(|| panic!("No schema {}", schema))
+            .keys().cloned().collect();
+        assert_eq!(serialized, documented, "{} does not match the serialized type", schema);
+    }
+
+    #[test]
+    fn schemas_match_serialized_types() {
+        let timestamp = Utc.ymd(2021, 7, 18).and_hms(12, 0, 0);
+        let telemetry = TimestampedTelemetry { timestamp, kind: TelemetryKind::Rssi, value: ReadingValue(-60.0) };
+
+        assert_properties("Sensor", &Sensor { family: SensorFamily::Beta, address: "AA".to_string(), name: None });
+        assert_properties("ReadingKind", &ReadingKind { symbol: "T".to_string(), name: "temperature".to_string(), unit: "°C".to_string(), precision: 1 });
+        assert_properties("TimestampedSensorReading", &TimestampedSensorReading {
+            timestamp,
+            reading: SensorReading::Temperature(21.5),
+            quality: ReadingQuality::Good
+        });
+        assert_properties("TimestampedSensorReading", &TimestampedSensorReading {
+            timestamp,
+            reading: SensorReading::Unknown,
+            quality: ReadingQuality::Good
+        });
+        assert_properties("TimestampedTelemetry", &telemetry);
+        assert_properties("SensorStatus", &StatusResponse {
+            status: SensorStatus::Online,
+            battery: BatteryStatus::Ok,
+            battery_voltage: None,
+            rssi: Some(telemetry.clone())
+        });
+        assert_properties("SensorResource", &SensorResource {
+            id: 1,
+            sensor: Sensor { family: SensorFamily::Beta, address: "AA".to_string(), name: None },
+            status: StatusResponse {
+                status: SensorStatus::Offline,
+                battery: BatteryStatus::Unknown,
+                battery_voltage: None,
+                rssi: None
+            },
+            latest: Vec::new()
+        });
+        assert_properties("Comparison", &Comparison {
+            bucket_secs: 300,
+            timestamps: vec![timestamp],
+            reference: Some(2),
+            series: Vec::new()
+        });
+        assert_properties("ComparedSeries", &ComparedSeries {
+            sensor: 1,
+            kind: "T".to_string(),
+            values: vec![Some(ReadingValue(21.5))],
+            difference: None
+        });
+        assert_properties("Calibration", &Calibration { kind: "T".to_string(), offset: -1.5, gain: 1.0, effective_from: timestamp });
+        assert_properties("DiscoveredDevice", &DiscoveredDevice {
+            address: "AA".to_string(),
+            name: None,
+            family: SensorFamily::Xiaomi,
+            status: DeviceStatus::Pending,
+            first_seen: timestamp,
+            last_seen: timestamp
+        });
+        assert_properties("IngestBatch", &IngestBatch { collector: "garage".to_string(), sensors: Vec::new() });
+        assert_properties("IngestSensor", &IngestSensor {
+            address: "AA".to_string(),
+            family: SensorFamily::Beta,
+            name: None,
+            readings: Vec::new(),
+            telemetry: Vec::new()
+        });
+        assert_properties("IngestReading", &IngestReading { timestamp, kind: "T".to_string(), value: 21.5, quality: ReadingQuality::Good });
+        assert_properties("IngestTelemetry", &IngestTelemetry { timestamp, kind: TelemetryKind::Rssi, value: -60.0 });
+        assert_properties("IngestReport", &IngestReport::default());
+    }
+}
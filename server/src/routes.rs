@@ -0,0 +1,141 @@
+use actix_web::{http::Method, web, HttpResponse, Resource, Route};
+
+use crate::api;
+use crate::api_v2;
+use crate::auth::{Authorize, TokenScope};
+use crate::database::Database;
+use crate::openapi;
+use crate::SensorsState;
+
+/// Large enough for the default forwarding batch of 500 readings with their telemetry
+const MAX_INGEST_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Scopes sharing the authorization of their routes, with the token scope the changes need.
+/// The routes outside of them need a `write` token for the changes.
+const SCOPES: [(&str, TokenScope); 3] = [
+    ("/api/sensors", TokenScope::Write),
+    ("/api/devices", TokenScope::Admin),
+    // Sensors with their id, status and latest readings in one document and the comparison
+    // of several of them, v1 stays as it is
+    ("/api/v2", TokenScope::Write)
+];
+
+/// A route of the REST API
+pub struct ApiRoute {
+    pub method: Method,
+    /// Full path, as described in `openapi::spec`
+    pub path: &'static str,
+    /// Open to anyone whatever the `anonymous_read` setting, only outside of the `SCOPES`
+    pub public: bool,
+    max_json_bytes: Option<usize>,
+    /// Adds the handler to a route already limited to the method
+    handler: fn(Route) -> Route
+}
+
+impl ApiRoute {
+    fn new(method: Method, path: &'static str, handler: fn(Route) -> Route) -> Self {
+        ApiRoute { method, path, public: false, max_json_bytes: None, handler }
+    }
+
+    fn public(self) -> Self {
+        ApiRoute { public: true, ..self }
+    }
+
+    fn max_json_bytes(self, limit: usize) -> Self {
+        ApiRoute { max_json_bytes: Some(limit), ..self }
+    }
+}
+
+fn get(path: &'static str, handler: fn(Route) -> Route) -> ApiRoute {
+    ApiRoute::new(Method::GET, path, handler)
+}
+
+fn post(path: &'static str, handler: fn(Route) -> Route) -> ApiRoute {
+    ApiRoute::new(Method::POST, path, handler)
+}
+
+/// Every route of the REST API, in the order they are matched. Each one has to be described
+/// in `openapi::spec`, which the tests check.
+pub fn table<D: Database<SensorHandle=i32> + 'static, S: SensorsState + Sync + Send + 'static>() -> Vec<ApiRoute> {
+    vec![
+        get("/status", |route| route.to(api::status)).public(),
+        get("/api/openapi.json", |route| route.to(openapi::openapi_json)).public(),
+        get("/api/kinds", |route| route.to(api::reading_kinds::<D>)),
+        post("/api/ingest", |route| route.to(api::ingest::<D>)).max_json_bytes(MAX_INGEST_BATCH_BYTES),
+
+        get("/api/sensors/list", |route| route.to(api::sensors_list::<D>)),
+        get("/api/sensors/{id}/readings", |route| route.to(api::sensor_readings::<D>)),
+        get("/api/sensors/{id}", |route| route.to(api::sensor_status::<D, S>)),
+        get("/api/sensors/{id}/readings/after/{timestamp}", |route| route
+            .to(api::sensor_readings_after_time::<D>)
+            .to(api::sensor_readings_after_time_utc::<D>)),
+        get("/api/sensors/{id}/latest/{kind}", |route| route.to(api::sensor_latest_reading::<D>)),
+        get("/api/sensors/{id}/telemetry", |route| route.to(api::sensor_telemetry::<D>)),
+        post("/api/sensors/{id}/config", |route| route.to(api::configure_sensor::<D>)),
+        get("/api/sensors/{id}/calibrations", |route| route.to(api::sensor_calibrations::<D>)),
+        post("/api/sensors/{id}/calibrations", |route| route.to(api::add_sensor_calibration::<D>)),
+
+        get("/api/devices/discovered", |route| route.to(api::discovered_devices::<D>)),
+        get("/api/devices/list", |route| route.to(api::devices_list::<D>)),
+        post("/api/devices/{address}/adopt", |route| route.to(api::adopt_device::<D>)),
+        post("/api/devices/{address}/ignore", |route| route.to(api::ignore_device::<D>)),
+
+        get("/api/v2/sensors", |route| route.to(api_v2::sensors::<D, S>)),
+        get("/api/v2/sensors/{id}", |route| route.to(api_v2::sensor::<D, S>)),
+        get("/api/v2/compare", |route| route.to(api_v2::compare::<D>))
+    ]
+}
+
+/// Resource of the routes sharing the path, the path is relative to their scope
+fn resource(path: &str, routes: &[&ApiRoute]) -> Resource {
+    let mut resource = web::resource(path);
+    for route in routes {
+        if let Some(limit) = route.max_json_bytes {
+            resource = resource.app_data(web::JsonConfig::default().limit(limit));
+        }
+        resource = resource.route((route.handler)(web::route().method(route.method.clone())));
+    }
+    resource
+}
+
+/// Routes grouped by their path, in the order the paths first appear
+fn by_path<'a>(routes: impl Iterator<Item = &'a ApiRoute>) -> Vec<(&'static str, Vec<&'a ApiRoute>)> {
+    let mut paths: Vec<(&'static str, Vec<&'a ApiRoute>)> = Vec::new();
+    for route in routes {
+        match paths.iter_mut().find(|(path, _)| *path == route.path) {
+            Some((_, same_path)) => same_path.push(route),
+            None => paths.push((route.path, vec![route]))
+        }
+    }
+    paths
+}
+
+fn scoped(path: &str) -> bool {
+    SCOPES.iter().any(|(prefix, _)| path.starts_with(prefix))
+}
+
+/// Registers the routes of the `table`
+pub fn configure<D: Database<SensorHandle=i32> + 'static, S: SensorsState + Sync + Send + 'static>(
+    cfg: &mut web::ServiceConfig,
+    read: Option<TokenScope>) {
+
+    let routes = table::<D, S>();
+
+    for (path, same_path) in by_path(routes.iter().filter(|route| !scoped(route.path))) {
+        if same_path.iter().all(|route| route.public) {
+            cfg.service(resource(path, &same_path));
+        } else {
+            cfg.service(resource(path, &same_path).wrap(Authorize::<D>::new(read, TokenScope::Write)));
+        }
+    }
+
+    for (prefix, changes) in SCOPES.iter() {
+        let mut scope = web::scope(prefix);
+        for (path, same_path) in by_path(routes.iter().filter(|route| route.path.starts_with(prefix))) {
+            scope = scope.service(resource(&path[prefix.len()..], &same_path));
+        }
+        cfg.service(scope
+            .default_service(web::route().to(HttpResponse::NotFound))
+            .wrap(Authorize::<D>::new(read, *changes)));
+    }
+}
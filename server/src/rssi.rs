@@ -0,0 +1,129 @@
+use btleplug::api::BDAddr;
+
+#[cfg(target_os = "linux")]
+mod hcitool {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use btleplug::api::BDAddr;
+
+    /// `hcitool` answers within milliseconds, a hung one must not stall the polling
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Set once `hcitool` turned out to be unusable, i.e. missing or run without the privileges
+    static UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+    enum HcitoolError {
+        /// No point in trying again
+        Unavailable(String),
+        /// E.g. the sensor disconnected in the meantime
+        Failed
+    }
+
+    pub fn read(adapter: &str, address: BDAddr) -> Option<i8> {
+        if UNAVAILABLE.load(Ordering::Relaxed) {
+            return None;
+        }
+        match run(adapter, address) {
+            Ok(output) => super::parse(&output),
+            Err(HcitoolError::Unavailable(reason)) => {
+                println!("Not reading the RSSI anymore: {}", reason);
+                UNAVAILABLE.store(true, Ordering::Relaxed);
+                None
+            },
+            Err(HcitoolError::Failed) => None
+        }
+    }
+
+    fn run(adapter: &str, address: BDAddr) -> Result<String, HcitoolError> {
+        let mut child = Command::new("hcitool")
+            .arg("-i")
+            .arg(adapter)
+            .arg("rssi")
+            .arg(address.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| HcitoolError::Unavailable(format!("could not run hcitool: {}", err)))?;
+
+        let deadline = Instant::now() + TIMEOUT;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                // E.g. the adapter is busy connecting, it may answer the next time
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(HcitoolError::Failed);
+                },
+                Err(_) => return Err(HcitoolError::Failed)
+            }
+        };
+
+        if !status.success() {
+            let error = read_pipe(child.stderr.take());
+            if error.contains("not permitted") || error.contains("Permission denied") {
+                return Err(HcitoolError::Unavailable(error.trim().to_string()));
+            }
+            return Err(HcitoolError::Failed);
+        }
+        Ok(read_pipe(child.stdout.take()))
+    }
+
+    fn read_pipe<R: Read>(pipe: Option<R>) -> String {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output);
+        }
+        output
+    }
+}
+
+/// Signal strength of an established connection through the adapter in dBm.
+/// btleplug does not expose it in the peripheral properties, so on Linux it's read with `hcitool`,
+/// which only knows about the connections of the adapter it's told to use.
+#[cfg(target_os = "linux")]
+pub fn read(adapter: &str, address: BDAddr) -> Option<i8> {
+    hcitool::read(adapter, address)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read(_adapter: &str, _address: BDAddr) -> Option<i8> {
+    None
+}
+
+/// `hcitool` prints "RSSI return value: -42"
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse(output: &str) -> Option<i8> {
+    output.trim()
+        .strip_prefix("RSSI return value:")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_hcitool_output() {
+        assert_eq!(parse("RSSI return value: -42\n"), Some(-42));
+        assert_eq!(parse("RSSI return value: 0"), Some(0));
+    }
+
+    #[test]
+    fn rejects_malformed_output() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("Not connected."), None);
+        assert_eq!(parse("-42"), None);
+        assert_eq!(parse("RSSI return value: "), None);
+        assert_eq!(parse("RSSI return value: strong"), None);
+        assert_eq!(parse("RSSI return value: -300"), None);
+    }
+}
@@ -7,6 +7,7 @@ table! {
         id -> Integer,
         address -> Text,
         name -> Nullable<Text>,
+        family -> Text,
     }
 }
 
@@ -17,7 +18,72 @@ table! {
         sensor -> Integer,
         timestamp -> Timestamp,
         kind -> Char,
-        value -> Integer,
+        value -> Double,
+        quality -> Integer,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    ReadingKinds(symbol) {
+        symbol -> Char,
+        name -> Text,
+        unit -> Text,
+        precision -> Integer,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    Calibrations(id) {
+        id -> Integer,
+        sensor -> Integer,
+        kind -> Char,
+        offset -> Double,
+        gain -> Double,
+        effective_from -> Timestamp,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    Telemetry(id) {
+        id -> Integer,
+        sensor -> Integer,
+        timestamp -> Timestamp,
+        kind -> Text,
+        value -> Double,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    Devices(address) {
+        address -> Text,
+        name -> Nullable<Text>,
+        family -> Text,
+        status -> Text,
+        first_seen -> Timestamp,
+        last_seen -> Timestamp,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    ForwardCursors(target) {
+        target -> Text,
+        readings -> Integer,
+        telemetry -> Integer,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    ApiTokens(name) {
+        name -> Text,
+        hash -> Text,
+        scope -> Text,
+        created -> Timestamp,
     }
 }
 
@@ -27,7 +93,8 @@ pub struct ReadingDTO {
    pub sensor: i32,
    pub timestamp: NaiveDateTime,
    pub kind: String,
-   pub value: i32
+   pub value: f64,
+   pub quality: i32
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -35,21 +102,98 @@ pub struct ReadingDTO {
 pub struct AddReadingDTO {
    pub sensor: i32,
    pub timestamp: NaiveDateTime,
-   pub kind: &'static str,
-   pub value: i32
+   pub kind: String,
+   pub value: f64,
+   pub quality: i32
 }
 
 #[derive(Serialize, Debug, Clone, Queryable)]
 pub struct SensorDTO {
    pub id: i32,
    pub address: String,
-   pub name: Option<String>
+   pub name: Option<String>,
+   pub family: String
 }
 
 #[derive(Debug, Clone, Insertable)]
 #[table_name="Sensors"]
 pub struct AddSensorDTO {
    pub name: Option<String>,
-   pub address: String
+   pub address: String,
+   pub family: String
+}
+
+#[derive(Serialize, Debug, Clone, Queryable, Insertable)]
+#[table_name="ReadingKinds"]
+pub struct ReadingKindDTO {
+   pub symbol: String,
+   pub name: String,
+   pub unit: String,
+   pub precision: i32
 }
 
+#[derive(Serialize, Debug, Clone, Queryable)]
+pub struct CalibrationDTO {
+   pub id: i32,
+   pub sensor: i32,
+   pub kind: String,
+   pub offset: f64,
+   pub gain: f64,
+   pub effective_from: NaiveDateTime
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name="Calibrations"]
+pub struct AddCalibrationDTO {
+   pub sensor: i32,
+   pub kind: String,
+   pub offset: f64,
+   pub gain: f64,
+   pub effective_from: NaiveDateTime
+}
+
+#[derive(Serialize, Debug, Clone, Queryable)]
+pub struct TelemetryDTO {
+   pub id: i32,
+   pub sensor: i32,
+   pub timestamp: NaiveDateTime,
+   pub kind: String,
+   pub value: f64
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name="Telemetry"]
+pub struct AddTelemetryDTO {
+   pub sensor: i32,
+   pub timestamp: NaiveDateTime,
+   pub kind: String,
+   pub value: f64
+}
+
+#[derive(Serialize, Debug, Clone, Queryable, Insertable)]
+#[table_name="Devices"]
+pub struct DeviceDTO {
+   pub address: String,
+   pub name: Option<String>,
+   pub family: String,
+   pub status: String,
+   pub first_seen: NaiveDateTime,
+   pub last_seen: NaiveDateTime
+}
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name="ForwardCursors"]
+pub struct ForwardCursorDTO {
+   pub target: String,
+   pub readings: i32,
+   pub telemetry: i32
+}
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name="ApiTokens"]
+pub struct ApiTokenDTO {
+   pub name: String,
+   pub hash: String,
+   pub scope: String,
+   pub created: NaiveDateTime
+}
@@ -3,46 +3,270 @@ use serde::{Deserialize, Serialize, ser::SerializeStruct};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum SensorFamily {
-    Alpha
+    Alpha,
+    /// Reports an arbitrary list of typed measurements
+    Beta,
+    /// Off-the-shelf LYWSD03MMC thermometers running the ATC1441/PVVX firmware, broadcast only
+    Xiaomi
+}
+
+impl SensorFamily {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorFamily::Alpha => "Alpha",
+            SensorFamily::Beta => "Beta",
+            SensorFamily::Xiaomi => "Xiaomi"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Alpha" => Some(SensorFamily::Alpha),
+            "Beta" => Some(SensorFamily::Beta),
+            "Xiaomi" => Some(SensorFamily::Xiaomi),
+            _ => None
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all="lowercase")]
 pub enum SensorReading {
-    Temperature(i32),
-    Humidity(u8),
+    Temperature(f32),
+    Humidity(f32),
+    DewPoint(f32),
+    HeatIndex(f32),
+    AbsoluteHumidity(f32),
+    /// Any other kind registered in the database, identified by its symbol
+    Measurement(String, f32),
     Unknown
 }
 
+impl SensorReading {
+    pub fn from_symbol(symbol: &str, value: f32) -> Self {
+        match symbol {
+            "T" => SensorReading::Temperature(value),
+            "H" => SensorReading::Humidity(value),
+            "D" | "I" | "A" | "?" | "" => SensorReading::Unknown,
+            _ => SensorReading::Measurement(symbol.to_string(), value)
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        match self {
+            SensorReading::Temperature(_) => "T",
+            SensorReading::Humidity(_) => "H",
+            SensorReading::DewPoint(_) => "D",
+            SensorReading::HeatIndex(_) => "I",
+            SensorReading::AbsoluteHumidity(_) => "A",
+            SensorReading::Measurement(symbol, _) => symbol,
+            SensorReading::Unknown => "?"
+        }
+    }
+
+    pub fn value(&self) -> Option<f32> {
+        match self {
+            SensorReading::Temperature(value)
+            | SensorReading::Humidity(value)
+            | SensorReading::DewPoint(value)
+            | SensorReading::HeatIndex(value)
+            | SensorReading::AbsoluteHumidity(value)
+            | SensorReading::Measurement(_, value) => Some(*value),
+            SensorReading::Unknown => None
+        }
+    }
+
+    /// The same kind of reading with a different value
+    pub fn with_value(&self, value: f32) -> Self {
+        match self {
+            SensorReading::Temperature(_) => SensorReading::Temperature(value),
+            SensorReading::Humidity(_) => SensorReading::Humidity(value.clamp(0.0, 100.0)),
+            SensorReading::DewPoint(_) => SensorReading::DewPoint(value),
+            SensorReading::HeatIndex(_) => SensorReading::HeatIndex(value),
+            SensorReading::AbsoluteHumidity(_) => SensorReading::AbsoluteHumidity(value),
+            SensorReading::Measurement(symbol, _) => SensorReading::Measurement(symbol.clone(), value),
+            SensorReading::Unknown => SensorReading::Unknown
+        }
+    }
+}
+
+/// Reading value as written to JSON and CSV.
+/// Whole numbers have no fractional part, just like when the readings were stored as integers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReadingValue(pub f32);
+
+impl serde::Serialize for ReadingValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0.fract() == 0.0 && self.0.abs() < i32::MAX as f32 {
+            serializer.serialize_i32(self.0 as i32)
+        } else {
+            serializer.serialize_f32(self.0)
+        }
+    }
+}
+
+/// Description of a kind of reading stored in the database
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadingKind {
+    pub symbol: String,
+    pub name: String,
+    pub unit: String,
+    /// Number of meaningful decimal places
+    pub precision: u8
+}
+
+/// Health of the sensor itself rather than a measurement of its surroundings
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum TelemetryKind {
+    /// Signal strength of the connection in dBm
+    Rssi,
+    /// Supply voltage in volts
+    BatteryVoltage,
+    /// Remaining battery in percent, as estimated by the sensor
+    BatteryLevel
+}
+
+impl TelemetryKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TelemetryKind::Rssi => "rssi",
+            TelemetryKind::BatteryVoltage => "battery_voltage",
+            TelemetryKind::BatteryLevel => "battery_level"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rssi" => Some(TelemetryKind::Rssi),
+            "battery_voltage" => Some(TelemetryKind::BatteryVoltage),
+            "battery_level" => Some(TelemetryKind::BatteryLevel),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimestampedTelemetry {
+    pub timestamp: DateTime<Utc>,
+    pub kind: TelemetryKind,
+    pub value: ReadingValue
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum BatteryStatus {
+    Ok,
+    Low,
+    /// The sensor never reported its supply voltage
+    Unknown
+}
+
+/// Decision of the user about a device found while scanning
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum DeviceStatus {
+    /// Not decided yet, polled unless only the adopted devices are
+    Pending,
+    Adopted,
+    /// Never connected to nor stored, e.g. a neighbour's sensor
+    Ignored
+}
+
+impl DeviceStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceStatus::Pending => "pending",
+            DeviceStatus::Adopted => "adopted",
+            DeviceStatus::Ignored => "ignored"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(DeviceStatus::Pending),
+            "adopted" => Some(DeviceStatus::Adopted),
+            "ignored" => Some(DeviceStatus::Ignored),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DiscoveredDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub family: SensorFamily,
+    pub status: DeviceStatus,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum SensorStatus {
     Online,
     Offline
 }
 
+/// Result of the plausibility checks done when the reading was stored.
+/// Ordered from the most to the least trustworthy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum ReadingQuality {
+    Good,
+    OutOfRange,
+    RateOfChange
+}
+
+impl Default for ReadingQuality {
+    fn default() -> Self {
+        ReadingQuality::Good
+    }
+}
+
+impl ReadingQuality {
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            1 => ReadingQuality::OutOfRange,
+            2 => ReadingQuality::RateOfChange,
+            _ => ReadingQuality::Good
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            ReadingQuality::Good => 0,
+            ReadingQuality::OutOfRange => 1,
+            ReadingQuality::RateOfChange => 2
+        }
+    }
+
+    pub fn is_suspect(&self) -> bool {
+        *self != ReadingQuality::Good
+    }
+}
+
 pub struct TimestampedSensorReading {
     pub timestamp: DateTime<Utc>,
-    pub reading: SensorReading
+    pub reading: SensorReading,
+    pub quality: ReadingQuality
 }
 
 impl serde::Serialize for TimestampedSensorReading {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut state = serializer.serialize_struct("timestamped_reading", 3)?;
+        let mut state = serializer.serialize_struct("timestamped_reading", 4)?;
         state.serialize_field("timestamp", &self.timestamp)?;
-        match self.reading {
-            SensorReading::Temperature(temperature) => {
-                state.serialize_field("kind", "T")?;
-                state.serialize_field("value", &temperature)?;
-            },
-            SensorReading::Humidity(humidity) => {
-                state.serialize_field("kind", "H")?;
-                state.serialize_field("value", &humidity)?;
+        match self.reading.value() {
+            Some(value) => {
+                state.serialize_field("kind", self.reading.symbol())?;
+                state.serialize_field("value", &ReadingValue(value))?;
             },
-            SensorReading::Unknown => {
+            None => {
                 state.serialize_field("kind", "?")?;
                 state.serialize_field("value", "null")?;
             }
         }
+        state.serialize_field("quality", &self.quality)?;
         state.end()
     }
 }
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Requests delivered by the process signals, checked between the iterations of the main loop
+/// so that a poll or a database write in progress is always finished first.
+pub struct Signals {
+    /// SIGINT or SIGTERM
+    stop: Arc<AtomicBool>,
+    /// SIGHUP
+    reload: Arc<AtomicBool>
+}
+
+impl Signals {
+    pub fn register() -> Self {
+        let signals = Signals {
+            stop: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(AtomicBool::new(false))
+        };
+        signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&signals.stop)).expect("Failed to register SIGINT hook");
+        signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&signals.stop)).expect("Failed to register SIGTERM hook");
+        signal_hook::flag::register(signal_hook::SIGHUP, Arc::clone(&signals.reload)).expect("Failed to register SIGHUP hook");
+        signals
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// True once for every SIGHUP received since the last call
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::Relaxed)
+    }
+}
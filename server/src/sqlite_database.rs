@@ -3,10 +3,13 @@ use std::time::Instant;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::r2d2;
 use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
 
 use log::info;
 
-use crate::{database::{Database, DatabaseError}, schema, sensor::SensorReading, sensor::{Sensor, SensorFamily, TimestampedSensorReading}};
+use crate::auth::{ApiToken, TokenScope};
+use crate::calibration::Calibration;
+use crate::{database::{Database, DatabaseError}, schema, sensor::SensorReading, sensor::{DeviceStatus, DiscoveredDevice, ReadingKind, ReadingQuality, ReadingValue, Sensor, SensorFamily, TelemetryKind, TimestampedSensorReading, TimestampedTelemetry}};
 
 type DbPool = r2d2::Pool<r2d2::ConnectionManager<SqliteConnection>>;
 type DbConnection = r2d2::PooledConnection<r2d2::ConnectionManager<SqliteConnection>>;
@@ -19,23 +22,24 @@ pub struct SqliteDatabase {
 }
 
 impl SqliteDatabase {
-    pub fn new(path: &'static str) -> Self {
+    pub fn new(path: &str) -> Self {
+        let database = Self::open(path);
+        database.migrate().expect("Migration failed");
+        database
+    }
+
+    /// Connects to the database without running the pending migrations.
+    pub fn open(path: &str) -> Self {
         let db_manager = r2d2::ConnectionManager::<SqliteConnection>::new(path);
         let db_pool = r2d2::Pool::builder()
             .build(db_manager)
             .expect("Could not create database pool");
 
-        println!("Database connected");
-        {
-            let migration_connection = db_pool.get().expect("Failed to get database connection for migrations");
-            embedded_migrations::run_with_output(&migration_connection, &mut std::io::stdout())
-                .expect("Migration failed");
-        }
+        info!("Database connected");
 
         SqliteDatabase {
             pool: db_pool
         }
-
     }
 
     fn find_sensor(&self, addr: &String) -> Result<schema::SensorDTO, diesel::result::Error> {
@@ -58,24 +62,78 @@ impl SqliteDatabase {
     }
 
     fn to_reading(dto: &schema::ReadingDTO) -> TimestampedSensorReading {
-        let reading = match dto.kind.as_ref() {
-            "T" => SensorReading::Temperature(dto.value),
-            "H" => SensorReading::Humidity(dto.value as u8),
-            _ => SensorReading::Unknown
-        };
+        let reading = SensorReading::from_symbol(&dto.kind, dto.value as f32);
 
         let utc = DateTime::<Utc>::from_utc(dto.timestamp, Utc);
-        TimestampedSensorReading { timestamp: utc, reading }
+        TimestampedSensorReading { timestamp: utc, reading, quality: ReadingQuality::from_code(dto.quality) }
+    }
+
+    fn to_add_reading_dto(handle: &i32, timestamp: NaiveDateTime, reading: &SensorReading, quality: ReadingQuality) -> schema::AddReadingDTO {
+        let (kind, value) = match reading {
+            SensorReading::Temperature(temperature) => ("T".to_string(), *temperature as f64),
+            SensorReading::Humidity(humidity) => ("H".to_string(), *humidity as f64),
+            SensorReading::Measurement(symbol, value) => (symbol.clone(), *value as f64),
+            SensorReading::DewPoint(_)
+            | SensorReading::HeatIndex(_)
+            | SensorReading::AbsoluteHumidity(_) => panic!("An attempt to insert derived sensor reading"),
+            SensorReading::Unknown => panic!("An attempt to insert unknown sensor reading")
+        };
+
+        schema::AddReadingDTO {
+            sensor: *handle,
+            timestamp, kind, value,
+            quality: quality.code()
+        }
+    }
+
+    /// Telemetry of a kind unknown to this build is skipped
+    fn to_telemetry(dto: &schema::TelemetryDTO) -> Option<TimestampedTelemetry> {
+        TelemetryKind::from_name(&dto.kind).map(|kind| TimestampedTelemetry {
+            timestamp: DateTime::<Utc>::from_utc(dto.timestamp, Utc),
+            kind,
+            value: ReadingValue(dto.value as f32)
+        })
+    }
+
+    fn to_calibration(dto: &schema::CalibrationDTO) -> Calibration {
+        Calibration {
+            kind: dto.kind.clone(),
+            offset: dto.offset as f32,
+            gain: dto.gain as f32,
+            effective_from: DateTime::<Utc>::from_utc(dto.effective_from, Utc)
+        }
     }
 
     fn to_sensor(sensor: &schema::SensorDTO) -> Sensor {
         Sensor {
-            family: SensorFamily::Alpha, // No other sensors supported for now!
+            // Sensors of a family unknown to this build are still listed
+            family: SensorFamily::from_name(&sensor.family).unwrap_or(SensorFamily::Alpha),
             address: sensor.address.clone(),
             name: sensor.name.clone()
         }
     }
 
+    /// Tokens of an unknown scope are skipped
+    fn to_token(dto: &schema::ApiTokenDTO) -> Option<ApiToken> {
+        Some(ApiToken {
+            name: dto.name.clone(),
+            scope: TokenScope::from_name(&dto.scope)?,
+            created: DateTime::<Utc>::from_utc(dto.created, Utc)
+        })
+    }
+
+    /// Devices of an unknown family or status are skipped
+    fn to_device(dto: &schema::DeviceDTO) -> Option<DiscoveredDevice> {
+        Some(DiscoveredDevice {
+            address: dto.address.clone(),
+            name: dto.name.clone(),
+            family: SensorFamily::from_name(&dto.family)?,
+            status: DeviceStatus::from_name(&dto.status)?,
+            first_seen: DateTime::<Utc>::from_utc(dto.first_seen, Utc),
+            last_seen: DateTime::<Utc>::from_utc(dto.last_seen, Utc)
+        })
+    }
+
     fn map_readings(readings: Vec<schema::ReadingDTO>) -> Vec<TimestampedSensorReading> {
         readings
             .iter()
@@ -120,7 +178,8 @@ impl Database for SqliteDatabase {
                         diesel::insert_into(table)
                             .values(schema::AddSensorDTO {
                                 name: sensor.name.clone(),
-                                address: sensor.address.to_string()
+                                address: sensor.address.to_string(),
+                                family: sensor.family.name().to_string()
                             })
                             .execute(&conn)
                             .map(|_| true)
@@ -132,36 +191,67 @@ impl Database for SqliteDatabase {
         }
     }
 
+    fn set_sensor_name(&self, handle: &Self::SensorHandle, name: Option<String>) -> Result<(), DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::update(schema::Sensors::table.filter(schema::Sensors::id.eq(handle)))
+                    .set(schema::Sensors::name.eq(name))
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+                    .and_then(|updates| match updates {
+                        0 => Err(DatabaseError::NotFound),
+                        _ => Ok(())
+                    })
+            })
+    }
+
     fn add_reading(&self,
         handle: &Self::SensorHandle,
         timestamp: NaiveDateTime,
-        reading: &SensorReading)
+        reading: &SensorReading,
+        quality: ReadingQuality)
     -> Result<(), DatabaseError> {
 
-        let (kind, value) = match reading {
-            SensorReading::Temperature(temperature) => ("T", *temperature as i32),
-            SensorReading::Humidity(humidity) => ("H", *humidity as i32),
-            SensorReading::Unknown => panic!("An attempt to insert unknown sensor reading")
-        };
+        let dto = Self::to_add_reading_dto(handle, timestamp, reading, quality);
 
         self.connection_or_busy()
             .and_then(|conn| {
                 diesel::insert_into(schema::Readings::table)
-                    .values(schema::AddReadingDTO {
-                        sensor: *handle,
-                        timestamp, kind, value
-                    })
+                    .values(dto)
                     .execute(&conn)
                     .map_err(Self::sql_error_to_db_error)
                     .map(|inserts| assert!(inserts == 1))
             })
     }
 
+    fn add_readings(&self,
+        handle: &Self::SensorHandle,
+        readings: &[TimestampedSensorReading])
+    -> Result<(), DatabaseError> {
+
+        let dtos: Vec<schema::AddReadingDTO> = readings
+            .iter()
+            .map(|reading| Self::to_add_reading_dto(handle, reading.timestamp.naive_utc(), &reading.reading, reading.quality))
+            .collect();
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                conn.transaction(|| {
+                    diesel::insert_into(schema::Readings::table)
+                        .values(&dtos[..])
+                        .execute(&*conn)
+                })
+                .map_err(Self::sql_error_to_db_error)
+                .map(|inserts| assert!(inserts == dtos.len()))
+            })
+    }
+
     fn get_readings(&self, handle: &Self::SensorHandle) -> Result<Vec<TimestampedSensorReading>, DatabaseError> {
         self.connection_or_busy()
             .and_then(|conn| {
                 schema::Readings::table
                     .filter(schema::Readings::sensor.eq(handle))
+                    .order_by((schema::Readings::timestamp.asc(), schema::Readings::id.asc()))
                     .load::<schema::ReadingDTO>(&conn)
                     .map_err(Self::sql_error_to_db_error)
             })
@@ -178,6 +268,7 @@ impl Database for SqliteDatabase {
                 schema::Readings::table
                     .filter(schema::Readings::sensor.eq(handle))
                     .filter(schema::Readings::timestamp.gt(timestamp))
+                    .order_by((schema::Readings::timestamp.asc(), schema::Readings::id.asc()))
                     .load::<schema::ReadingDTO>(&conn)
                     .map_err(Self::sql_error_to_db_error)
             });
@@ -207,41 +298,570 @@ impl Database for SqliteDatabase {
             .map_err(Self::sql_error_to_db_error)
     }
 
-
-    fn get_sensor_handle(&self, sensor: &Sensor) -> Result<Self::SensorHandle, DatabaseError> {
+    fn get_sensors(&self) -> Result<Vec<Sensor>, DatabaseError> {
         self.connection_or_busy()
             .and_then(|conn| {
                 schema::Sensors::table
-                    .filter(schema::Sensors::dsl::address.eq(sensor.address.to_string()))
-                    .filter(schema::Sensors::dsl::name.eq(&sensor.name))
-                    .first::<schema::SensorDTO>(&conn)
+                    .load::<schema::SensorDTO>(&conn)
                     .map_err(Self::sql_error_to_db_error)
             })
-            .map(|dto| dto.id)
+            .map(Self::map_sensors)
     }
 
-    fn get_sensors(&self) -> Result<Vec<Sensor>, DatabaseError> {
+    fn get_latest_reading(&self, handle: &Self::SensorHandle, kind: String, include_suspect: bool)
+        -> Result<TimestampedSensorReading, DatabaseError> {
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                let mut query = schema::Readings::table
+                    .filter(schema::Readings::sensor.eq(handle))
+                    .filter(schema::Readings::kind.eq(kind))
+                    .into_boxed();
+                if !include_suspect {
+                    query = query.filter(schema::Readings::quality.eq(ReadingQuality::Good.code()));
+                }
+                query
+                    .order_by((schema::Readings::timestamp.desc(), schema::Readings::id.desc()))
+                    .first::<schema::ReadingDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|reading| Self::to_reading(&reading))
+    }
+
+    fn get_latest_readings(&self, handle: &Self::SensorHandle, include_suspect: bool)
+        -> Result<Vec<TimestampedSensorReading>, DatabaseError> {
+
+        let conn = self.connection_or_busy()?;
+        let qualities = if include_suspect {
+            vec![ReadingQuality::Good.code(), ReadingQuality::OutOfRange.code(), ReadingQuality::RateOfChange.code()]
+        } else {
+            vec![ReadingQuality::Good.code()]
+        };
+        let kinds = schema::Readings::table
+            .filter(schema::Readings::sensor.eq(handle))
+            .filter(schema::Readings::quality.eq_any(&qualities))
+            .select(schema::Readings::kind)
+            .distinct()
+            .order_by(schema::Readings::kind.asc())
+            .load::<String>(&conn)
+            .map_err(Self::sql_error_to_db_error)?;
+
+        // Imported readings get higher ids than newer ones stored before, so the timestamp decides
+        kinds.into_iter()
+            .map(|kind| schema::Readings::table
+                .filter(schema::Readings::sensor.eq(handle))
+                .filter(schema::Readings::kind.eq(kind))
+                .filter(schema::Readings::quality.eq_any(&qualities))
+                .order_by((schema::Readings::timestamp.desc(), schema::Readings::id.desc()))
+                .first::<schema::ReadingDTO>(&conn))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self::map_readings)
+            .map_err(Self::sql_error_to_db_error)
+    }
+
+    fn get_sensors_with_handles(&self) -> Result<Vec<(Self::SensorHandle, Sensor)>, DatabaseError> {
         self.connection_or_busy()
             .and_then(|conn| {
                 schema::Sensors::table
                     .load::<schema::SensorDTO>(&conn)
                     .map_err(Self::sql_error_to_db_error)
             })
-            .map(Self::map_sensors)
+            .map(|sensors| sensors
+                .iter()
+                .map(|sensor| (sensor.id, Self::to_sensor(sensor)))
+                .collect())
     }
 
-    fn get_latest_reading(&self, handle: &Self::SensorHandle, kind: String)
-        -> Result<TimestampedSensorReading, DatabaseError> {
+    fn get_readings_between(&self,
+        handle: &Self::SensorHandle,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>)
+        -> Result<Vec<TimestampedSensorReading>, DatabaseError> {
 
         self.connection_or_busy()
             .and_then(|conn| {
-                schema::Readings::table
+                let mut query = schema::Readings::table
                     .filter(schema::Readings::sensor.eq(handle))
-                    .filter(schema::Readings::kind.eq(kind))
-                    .order_by(schema::Readings::id.desc())
-                    .first::<schema::ReadingDTO>(&conn)
+                    .into_boxed();
+                if let Some(from) = from {
+                    query = query.filter(schema::Readings::timestamp.ge(from));
+                }
+                if let Some(to) = to {
+                    query = query.filter(schema::Readings::timestamp.lt(to));
+                }
+                query
+                    .order_by((schema::Readings::timestamp.asc(), schema::Readings::id.asc()))
+                    .load::<schema::ReadingDTO>(&conn)
                     .map_err(Self::sql_error_to_db_error)
             })
-            .map(|reading| Self::to_reading(&reading))
+            .map(Self::map_readings)
+    }
+
+    fn get_reading_kinds(&self) -> Result<Vec<ReadingKind>, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::ReadingKinds::table
+                    .load::<schema::ReadingKindDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|kinds| kinds
+                .into_iter()
+                .map(|kind| ReadingKind {
+                    symbol: kind.symbol,
+                    name: kind.name,
+                    unit: kind.unit,
+                    precision: kind.precision as u8
+                })
+                .collect())
+    }
+
+    fn register_reading_kind(&self, kind: &ReadingKind) -> Result<bool, DatabaseError> {
+        let conn = self.connection_or_busy()?;
+        let existing = schema::ReadingKinds::table
+            .filter(schema::ReadingKinds::symbol.eq(&kind.symbol))
+            .count()
+            .get_result::<i64>(&conn)
+            .map_err(Self::sql_error_to_db_error)?;
+        if existing > 0 {
+            return Ok(false);
+        }
+
+        diesel::insert_into(schema::ReadingKinds::table)
+            .values(schema::ReadingKindDTO {
+                symbol: kind.symbol.clone(),
+                name: kind.name.clone(),
+                unit: kind.unit.clone(),
+                precision: kind.precision as i32
+            })
+            .execute(&conn)
+            .map(|_| true)
+            .map_err(Self::sql_error_to_db_error)
+    }
+
+    fn get_calibrations(&self, handle: &Self::SensorHandle) -> Result<Vec<Calibration>, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::Calibrations::table
+                    .filter(schema::Calibrations::sensor.eq(handle))
+                    .order_by((schema::Calibrations::effective_from.asc(), schema::Calibrations::id.asc()))
+                    .load::<schema::CalibrationDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|calibrations| calibrations
+                .iter()
+                .map(Self::to_calibration)
+                .collect())
+    }
+
+    fn delete_calibrations(&self, handle: &Self::SensorHandle, kind: &str, effective_from: NaiveDateTime) -> Result<usize, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::delete(schema::Calibrations::table
+                    .filter(schema::Calibrations::sensor.eq(handle))
+                    .filter(schema::Calibrations::kind.eq(kind))
+                    .filter(schema::Calibrations::effective_from.eq(effective_from)))
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+                    .and_then(|deleted| match deleted {
+                        0 => Err(DatabaseError::NotFound),
+                        deleted => Ok(deleted)
+                    })
+            })
+    }
+
+    fn add_calibration(&self, handle: &Self::SensorHandle, calibration: &Calibration) -> Result<(), DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::insert_into(schema::Calibrations::table)
+                    .values(schema::AddCalibrationDTO {
+                        sensor: *handle,
+                        kind: calibration.kind.clone(),
+                        offset: calibration.offset as f64,
+                        gain: calibration.gain as f64,
+                        effective_from: calibration.effective_from.naive_utc()
+                    })
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+                    .map(|inserts| assert!(inserts == 1))
+            })
+    }
+
+    fn add_telemetry(&self,
+        handle: &Self::SensorHandle,
+        timestamp: NaiveDateTime,
+        kind: TelemetryKind,
+        value: f32)
+    -> Result<(), DatabaseError> {
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::insert_into(schema::Telemetry::table)
+                    .values(schema::AddTelemetryDTO {
+                        sensor: *handle,
+                        timestamp,
+                        kind: kind.name().to_string(),
+                        value: value as f64
+                    })
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+                    .map(|inserts| assert!(inserts == 1))
+            })
+    }
+
+    fn get_telemetry_between(&self,
+        handle: &Self::SensorHandle,
+        kind: Option<TelemetryKind>,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>)
+        -> Result<Vec<TimestampedTelemetry>, DatabaseError> {
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                let mut query = schema::Telemetry::table
+                    .filter(schema::Telemetry::sensor.eq(handle))
+                    .into_boxed();
+                if let Some(kind) = kind {
+                    query = query.filter(schema::Telemetry::kind.eq(kind.name()));
+                }
+                if let Some(from) = from {
+                    query = query.filter(schema::Telemetry::timestamp.ge(from));
+                }
+                if let Some(to) = to {
+                    query = query.filter(schema::Telemetry::timestamp.lt(to));
+                }
+                query
+                    .order_by((schema::Telemetry::timestamp.asc(), schema::Telemetry::id.asc()))
+                    .load::<schema::TelemetryDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|telemetry| telemetry
+                .iter()
+                .filter_map(Self::to_telemetry)
+                .collect())
+    }
+
+    fn get_latest_telemetry(&self, handle: &Self::SensorHandle, kind: TelemetryKind)
+        -> Result<TimestampedTelemetry, DatabaseError> {
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::Telemetry::table
+                    .filter(schema::Telemetry::sensor.eq(handle))
+                    .filter(schema::Telemetry::kind.eq(kind.name()))
+                    .order_by((schema::Telemetry::timestamp.desc(), schema::Telemetry::id.desc()))
+                    .first::<schema::TelemetryDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .and_then(|dto| Self::to_telemetry(&dto).ok_or(DatabaseError::NotFound))
+    }
+
+    fn delete_telemetry_before(&self, timestamp: NaiveDateTime) -> Result<usize, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::delete(schema::Telemetry::table
+                    .filter(schema::Telemetry::timestamp.lt(timestamp)))
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+    }
+
+    fn record_device(&self, sensor: &Sensor, seen: NaiveDateTime) -> Result<(), DatabaseError> {
+        let conn = self.connection_or_busy()?;
+        let updates = diesel::update(schema::Devices::table.filter(schema::Devices::address.eq(&sensor.address)))
+            .set((
+                schema::Devices::name.eq(&sensor.name),
+                schema::Devices::last_seen.eq(seen)
+            ))
+            .execute(&conn)
+            .map_err(Self::sql_error_to_db_error)?;
+        if updates > 0 {
+            return Ok(());
+        }
+
+        diesel::insert_into(schema::Devices::table)
+            .values(schema::DeviceDTO {
+                address: sensor.address.clone(),
+                name: sensor.name.clone(),
+                family: sensor.family.name().to_string(),
+                status: DeviceStatus::Pending.name().to_string(),
+                first_seen: seen,
+                last_seen: seen
+            })
+            .execute(&conn)
+            .map(|inserts| assert!(inserts == 1))
+            .map_err(Self::sql_error_to_db_error)
+    }
+
+    fn get_device_status(&self, address: &str) -> Result<DeviceStatus, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::Devices::table
+                    .filter(schema::Devices::address.eq(address))
+                    .select(schema::Devices::status)
+                    .first::<String>(&conn)
+                    .map_err(|err| match err {
+                        diesel::result::Error::NotFound => DatabaseError::NotFound,
+                        err => Self::sql_error_to_db_error(err)
+                    })
+            })
+            .and_then(|status| DeviceStatus::from_name(&status)
+                .ok_or_else(|| DatabaseError::Other(format!("Unknown device status {}", status))))
+    }
+
+    fn set_device_status(&self, address: &str, status: DeviceStatus) -> Result<(), DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::update(schema::Devices::table.filter(schema::Devices::address.eq(address)))
+                    .set(schema::Devices::status.eq(status.name()))
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+                    .and_then(|updates| match updates {
+                        0 => Err(DatabaseError::NotFound),
+                        _ => Ok(())
+                    })
+            })
+    }
+
+    fn get_devices(&self, status: Option<DeviceStatus>) -> Result<Vec<DiscoveredDevice>, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                let mut query = schema::Devices::table.into_boxed();
+                if let Some(status) = status {
+                    query = query.filter(schema::Devices::status.eq(status.name()));
+                }
+                query
+                    .order_by(schema::Devices::last_seen.desc())
+                    .load::<schema::DeviceDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|devices| devices
+                .iter()
+                .filter_map(Self::to_device)
+                .collect())
+    }
+
+    fn get_readings_since(&self, after_id: i32, limit: i64)
+        -> Result<Vec<(i32, Self::SensorHandle, TimestampedSensorReading)>, DatabaseError> {
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::Readings::table
+                    .filter(schema::Readings::id.gt(after_id))
+                    .order_by(schema::Readings::id.asc())
+                    .limit(limit)
+                    .load::<schema::ReadingDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|readings| readings
+                .iter()
+                .map(|dto| (dto.id, dto.sensor, Self::to_reading(dto)))
+                .collect())
+    }
+
+    fn get_telemetry_since(&self, after_id: i32, limit: i64)
+        -> Result<Vec<(i32, Self::SensorHandle, TimestampedTelemetry)>, DatabaseError> {
+
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::Telemetry::table
+                    .filter(schema::Telemetry::id.gt(after_id))
+                    .order_by(schema::Telemetry::id.asc())
+                    .limit(limit)
+                    .load::<schema::TelemetryDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|telemetry| telemetry
+                .iter()
+                .filter_map(|dto| Self::to_telemetry(dto).map(|telemetry| (dto.id, dto.sensor, telemetry)))
+                .collect())
+    }
+
+    fn get_forward_cursor(&self, target: &str) -> Result<(i32, i32), DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::ForwardCursors::table
+                    .filter(schema::ForwardCursors::target.eq(target))
+                    .first::<schema::ForwardCursorDTO>(&conn)
+                    .optional()
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|cursor| cursor
+                .map(|cursor| (cursor.readings, cursor.telemetry))
+                .unwrap_or((0, 0)))
+    }
+
+    fn set_forward_cursor(&self, target: &str, readings_id: i32, telemetry_id: i32) -> Result<(), DatabaseError> {
+        let cursor = schema::ForwardCursorDTO {
+            target: target.to_string(),
+            readings: readings_id,
+            telemetry: telemetry_id
+        };
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::replace_into(schema::ForwardCursors::table)
+                    .values(&cursor)
+                    .execute(&conn)
+                    .map(|_| ())
+                    .map_err(Self::sql_error_to_db_error)
+            })
+    }
+
+    fn add_token(&self, name: &str, hash: &str, scope: TokenScope, created: NaiveDateTime) -> Result<bool, DatabaseError> {
+        let conn = self.connection_or_busy()?;
+        let existing = schema::ApiTokens::table
+            .filter(schema::ApiTokens::name.eq(name))
+            .count()
+            .get_result::<i64>(&conn)
+            .map_err(Self::sql_error_to_db_error)?;
+        if existing > 0 {
+            return Ok(false);
+        }
+
+        diesel::insert_into(schema::ApiTokens::table)
+            .values(schema::ApiTokenDTO {
+                name: name.to_string(),
+                hash: hash.to_string(),
+                scope: scope.name().to_string(),
+                created
+            })
+            .execute(&conn)
+            .map(|_| true)
+            .map_err(Self::sql_error_to_db_error)
+    }
+
+    fn get_token_scope(&self, hash: &str) -> Result<TokenScope, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::ApiTokens::table
+                    .filter(schema::ApiTokens::hash.eq(hash))
+                    .select(schema::ApiTokens::scope)
+                    .first::<String>(&conn)
+                    .map_err(|err| match err {
+                        diesel::result::Error::NotFound => DatabaseError::NotFound,
+                        err => Self::sql_error_to_db_error(err)
+                    })
+            })
+            .and_then(|scope| TokenScope::from_name(&scope)
+                .ok_or_else(|| DatabaseError::Other(format!("Unknown token scope {}", scope))))
+    }
+
+    fn get_tokens(&self) -> Result<Vec<ApiToken>, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                schema::ApiTokens::table
+                    .order_by(schema::ApiTokens::created.asc())
+                    .load::<schema::ApiTokenDTO>(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+            .map(|tokens| tokens
+                .iter()
+                .filter_map(Self::to_token)
+                .collect())
+    }
+
+    fn revoke_token(&self, name: &str) -> Result<(), DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::delete(schema::ApiTokens::table.filter(schema::ApiTokens::name.eq(name)))
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+                    .and_then(|deleted| match deleted {
+                        0 => Err(DatabaseError::NotFound),
+                        _ => Ok(())
+                    })
+            })
+    }
+
+    fn delete_readings_before(&self, timestamp: NaiveDateTime) -> Result<usize, DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                diesel::delete(schema::Readings::table
+                    .filter(schema::Readings::timestamp.lt(timestamp)))
+                    .execute(&conn)
+                    .map_err(Self::sql_error_to_db_error)
+            })
+    }
+
+    fn vacuum(&self) -> Result<(), DatabaseError> {
+        self.connection_or_busy()
+            .and_then(|conn| {
+                conn.batch_execute("VACUUM")
+                    .map_err(Self::sql_error_to_db_error)
+            })
+    }
+
+    fn migrate(&self) -> Result<(), DatabaseError> {
+        let migration_connection = self.connection_or_busy()?;
+        embedded_migrations::run_with_output(&migration_connection, &mut std::io::stdout())
+            .map_err(|err| DatabaseError::Other(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2021, 7, 18).and_hms(12, minute, 0)
+    }
+
+    #[test]
+    fn latest_readings_go_by_the_timestamp() {
+        let path = std::env::temp_dir().join(format!("airsensor-latest-{}.sqlite3", std::process::id()));
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        let sensor = Sensor { family: SensorFamily::Beta, address: "EE".to_string(), name: None };
+        db.create_sensor_if_not_exists(&sensor).unwrap();
+        let handle = db.get_sensor_by_addr(sensor.address.clone()).unwrap();
+
+        db.add_reading(&handle, at(30), &SensorReading::Temperature(21.0), ReadingQuality::Good).unwrap();
+        db.add_reading(&handle, at(30), &SensorReading::Humidity(40.0), ReadingQuality::Good).unwrap();
+        // Imported afterwards, but older
+        db.add_reading(&handle, at(10), &SensorReading::Temperature(18.0), ReadingQuality::Good).unwrap();
+        db.add_reading(&handle, at(40), &SensorReading::Humidity(99.0), ReadingQuality::OutOfRange).unwrap();
+
+        let latest = |include_suspect| db.get_latest_readings(&handle, include_suspect).unwrap()
+            .into_iter()
+            .map(|reading| (reading.timestamp.naive_utc(), reading.reading.symbol().to_string(), reading.reading.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(latest(false), vec![
+            (at(30), "H".to_string(), Some(40.0)),
+            (at(30), "T".to_string(), Some(21.0))
+        ]);
+        assert_eq!(latest(true), vec![
+            (at(40), "H".to_string(), Some(99.0)),
+            (at(30), "T".to_string(), Some(21.0))
+        ]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn latest_reading_of_v1_and_v2_agree_after_an_import() {
+        let path = std::env::temp_dir().join(format!("airsensor-latest-v1-{}.sqlite3", std::process::id()));
+        let db = SqliteDatabase::new(path.to_str().unwrap());
+        let sensor = Sensor { family: SensorFamily::Beta, address: "EF".to_string(), name: None };
+        db.create_sensor_if_not_exists(&sensor).unwrap();
+        let handle = db.get_sensor_by_addr(sensor.address.clone()).unwrap();
+
+        db.add_reading(&handle, at(30), &SensorReading::Temperature(21.0), ReadingQuality::Good).unwrap();
+        // Imported afterwards, but older
+        db.add_reading(&handle, at(10), &SensorReading::Temperature(18.0), ReadingQuality::Good).unwrap();
+        db.add_reading(&handle, at(20), &SensorReading::Temperature(19.0), ReadingQuality::Good).unwrap();
+
+        let v1 = db.get_latest_reading(&handle, "T".to_string(), false).unwrap();
+        let v2 = db.get_latest_readings(&handle, false).unwrap();
+        assert_eq!(v1.timestamp.naive_utc(), at(30));
+        assert_eq!(v2.len(), 1);
+        assert_eq!(v2[0].timestamp, v1.timestamp);
+        assert_eq!(v2[0].reading.value(), Some(21.0));
+
+        let timestamps = |readings: Vec<TimestampedSensorReading>| readings.into_iter()
+            .map(|reading| reading.timestamp.naive_utc())
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps(db.get_readings(&handle).unwrap()), vec![at(10), at(20), at(30)]);
+        assert_eq!(timestamps(db.get_readings_between(&handle, None, None).unwrap()), vec![at(10), at(20), at(30)]);
+        assert_eq!(timestamps(db.get_readings_after(&handle, at(10)).unwrap()), vec![at(20), at(30)]);
+        let _ = std::fs::remove_file(path);
     }
 }
@@ -0,0 +1,142 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use actix_web::{dev::Server, http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+
+use crate::config::TlsConfig;
+
+/// Certificate and key read from the configured files, read again when either file changes
+/// so that the renewed certificates (e.g. by certbot) are used without a restart.
+pub struct ReloadingCertificate {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<(CertifiedKey, SystemTime)>
+}
+
+impl ReloadingCertificate {
+    pub fn load(config: &TlsConfig) -> Result<Arc<Self>, String> {
+        let modified = last_modified(&config.cert, &config.key)
+            .map_err(|err| format!("Could not read the certificate: {}", err))?;
+        let certified = load_certified_key(&config.cert, &config.key)?;
+        Ok(Arc::new(ReloadingCertificate {
+            cert_path: config.cert.clone(),
+            key_path: config.key.clone(),
+            current: RwLock::new((certified, modified))
+        }))
+    }
+
+    /// Checks the files every interval for as long as the certificate is used
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let certificate: Weak<Self> = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match certificate.upgrade() {
+                Some(certificate) => certificate.reload_if_modified(),
+                None => return
+            }
+        });
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = match last_modified(&self.cert_path, &self.key_path) {
+            Ok(modified) => modified,
+            Err(err) => {
+                println!("Could not check the certificate: {}", err);
+                return;
+            }
+        };
+        if modified == self.current.read().expect("Poisoned RwLock").1 {
+            return;
+        }
+
+        // The key may not be written yet while the files are being rotated, the next check will retry
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(certified) => {
+                println!("Reloaded the certificate {}", self.cert_path);
+                *self.current.write().expect("Poisoned RwLock") = (certified, modified);
+            },
+            Err(err) => println!("Keeping the current certificate: {}", err)
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().expect("Poisoned RwLock").0.clone())
+    }
+}
+
+fn last_modified(cert_path: &str, key_path: &str) -> io::Result<SystemTime> {
+    let cert = fs::metadata(cert_path)?.modified()?;
+    let key = fs::metadata(key_path)?.modified()?;
+    Ok(cert.max(key))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
+    let open = |path: &str| File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Could not open {}: {}", path, err));
+
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .map_err(|_| format!("Invalid certificate {}", cert_path))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert_path));
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|_| format!("Invalid key {}", key_path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?)
+            .map_err(|_| format!("Invalid key {}", key_path))?;
+    }
+    let key = keys.first().ok_or(format!("No private key in {}", key_path))?;
+    let signing_key = sign::any_supported_type(key)
+        .map_err(|_| format!("Unsupported private key type in {}", key_path))?;
+
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, String> {
+    let certificate = ReloadingCertificate::load(config)?;
+    certificate.watch(Duration::from_secs(config.reload_check_secs));
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.cert_resolver = certificate;
+    Ok(server_config)
+}
+
+/// Answers every plain HTTP request with a permanent redirect to the same URL over HTTPS.
+/// Has to be started from within the actix system of the HTTPS server.
+pub fn redirect_server(bind: &str, https_port: u16) -> io::Result<Server> {
+    Ok(HttpServer::new(move || App::new()
+            .default_service(web::route().to(move |request: HttpRequest| redirect(request, https_port))))
+        .bind(bind)?
+        .disable_signals()
+        .workers(1)
+        .run())
+}
+
+async fn redirect(request: HttpRequest, https_port: u16) -> HttpResponse {
+    let connection = request.connection_info();
+    let host = connection.host();
+    // Strips the port of the plain listener, IPv6 addresses are enclosed in brackets
+    let hostname = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host
+    };
+    let authority = match https_port {
+        443 => hostname.to_string(),
+        port => format!("{}:{}", hostname, port)
+    };
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    HttpResponse::MovedPermanently()
+        .header(header::LOCATION, format!("https://{}{}", authority, path))
+        .finish()
+}
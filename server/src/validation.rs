@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::config::ValidationLimits;
+use crate::sensor::{ReadingQuality, SensorReading, TimestampedSensorReading};
+
+/// Flags the readings which are unlikely to be real, e.g. the DHT11 glitches.
+/// Suspect readings are still stored so that nothing is lost when the limits are too strict.
+pub struct Validator {
+    limits: HashMap<String, ValidationLimits>
+}
+
+impl Validator {
+    pub fn new(limits: HashMap<String, ValidationLimits>) -> Self {
+        Validator { limits }
+    }
+
+    /// `previous` is the latest good reading of the same kind, if any.
+    pub fn check(&self,
+        timestamp: DateTime<Utc>,
+        reading: &SensorReading,
+        previous: Option<&TimestampedSensorReading>)
+    -> ReadingQuality {
+
+        let (limits, value) = match (self.limits.get(reading.symbol()), reading.value()) {
+            (Some(limits), Some(value)) => (limits, value),
+            _ => return ReadingQuality::Good
+        };
+
+        let below = matches!(limits.min, Some(min) if value < min);
+        let above = matches!(limits.max, Some(max) if value > max);
+        if below || above {
+            return ReadingQuality::OutOfRange;
+        }
+
+        let previous = previous.and_then(|previous| previous.reading.value()
+            .map(|previous_value| (previous.timestamp, previous_value)));
+
+        if let (Some(max_change), Some((previous_timestamp, previous_value))) = (limits.max_change_per_minute, previous) {
+            let minutes = (timestamp - previous_timestamp).num_seconds() as f32 / 60.0;
+            // Readings taken within the same minute are compared as if a minute has passed
+            let change_per_minute = (value - previous_value).abs() / minutes.max(1.0);
+            if change_per_minute > max_change {
+                return ReadingQuality::RateOfChange;
+            }
+        }
+
+        ReadingQuality::Good
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    fn validator() -> Validator {
+        let mut limits = HashMap::new();
+        limits.insert("T".to_string(), ValidationLimits { min: Some(-40.0), max: Some(80.0), max_change_per_minute: Some(3.0) });
+        limits.insert("H".to_string(), ValidationLimits { min: Some(1.0), max: None, max_change_per_minute: None });
+        Validator::new(limits)
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.ymd(2021, 2, 7).and_hms(10, 0, 0) + Duration::seconds(seconds)
+    }
+
+    fn previous(seconds: i64, value: f32) -> TimestampedSensorReading {
+        TimestampedSensorReading { timestamp: at(seconds), reading: SensorReading::Temperature(value), quality: ReadingQuality::Good }
+    }
+
+    #[test]
+    fn flags_readings_out_of_range() {
+        let validator = validator();
+        assert_eq!(validator.check(at(0), &SensorReading::Temperature(80.5), None), ReadingQuality::OutOfRange);
+        assert_eq!(validator.check(at(0), &SensorReading::Temperature(-41.0), None), ReadingQuality::OutOfRange);
+        assert_eq!(validator.check(at(0), &SensorReading::Temperature(80.0), None), ReadingQuality::Good);
+        assert_eq!(validator.check(at(0), &SensorReading::Humidity(0.0), None), ReadingQuality::OutOfRange);
+        assert_eq!(validator.check(at(0), &SensorReading::Humidity(150.0), None), ReadingQuality::Good);
+    }
+
+    #[test]
+    fn flags_changes_faster_than_the_limit() {
+        let validator = validator();
+        let before = previous(0, 20.0);
+        // 10 degrees in 5 minutes is 2 per minute, 10 in 2 minutes is 5
+        assert_eq!(validator.check(at(300), &SensorReading::Temperature(30.0), Some(&before)), ReadingQuality::Good);
+        assert_eq!(validator.check(at(120), &SensorReading::Temperature(30.0), Some(&before)), ReadingQuality::RateOfChange);
+        assert_eq!(validator.check(at(120), &SensorReading::Temperature(10.0), Some(&before)), ReadingQuality::RateOfChange);
+    }
+
+    #[test]
+    fn compares_readings_within_a_minute_as_a_minute_apart() {
+        let validator = validator();
+        let before = previous(0, 20.0);
+        assert_eq!(validator.check(at(10), &SensorReading::Temperature(22.5), Some(&before)), ReadingQuality::Good);
+        assert_eq!(validator.check(at(10), &SensorReading::Temperature(23.5), Some(&before)), ReadingQuality::RateOfChange);
+    }
+
+    #[test]
+    fn accepts_kinds_without_limits() {
+        let validator = validator();
+        assert_eq!(validator.check(at(0), &SensorReading::Measurement("P".to_string(), -5000.0), None), ReadingQuality::Good);
+        assert_eq!(validator.check(at(0), &SensorReading::Unknown, None), ReadingQuality::Good);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::advertisement::{Advertisement, AdvertisementDecoder, BroadcastReadings};
+use crate::sensor::{SensorFamily, SensorReading, TelemetryKind};
+
+/// Environmental Sensing service, used by the ATC1441 and PVVX custom formats
+const ENVIRONMENTAL_SENSING_UUID: u16 = 0x181A;
+const BTHOME_UUID: u16 = 0xFCD2;
+
+const ATC1441_LENGTH: usize = 13;
+const PVVX_LENGTH: usize = 15;
+
+/// Advertised by the stock firmware, the ATC1441 and PVVX ones default to `ATC_` and the end of the MAC
+const STOCK_NAME: &str = "LYWSD03MMC";
+const CUSTOM_NAME_PREFIX: &str = "ATC_";
+
+const BTHOME_ENCRYPTED: u8 = 0x01;
+const BTHOME_VERSION_2: u8 = 2;
+
+/// LYWSD03MMC thermometers flashed with the ATC1441 or PVVX firmware, which broadcast
+/// temperature, humidity and battery in one of the formats the firmware can be configured to.
+/// The stock firmware encrypts its advertisements and is not supported.
+pub struct XiaomiDecoder;
+
+impl AdvertisementDecoder for XiaomiDecoder {
+    fn family(&self) -> SensorFamily {
+        SensorFamily::Xiaomi
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Option<BroadcastReadings> {
+        if let Some(data) = advertisement.service_payload(ENVIRONMENTAL_SENSING_UUID) {
+            return match data.len() {
+                ATC1441_LENGTH => decode_atc1441(data),
+                PVVX_LENGTH => decode_pvvx(data),
+                _ => None
+            };
+        }
+        advertisement.service_payload(BTHOME_UUID).and_then(decode_bthome)
+    }
+
+    fn recognizes(&self, advertisement: &Advertisement) -> bool {
+        advertisement.local_name.as_ref()
+            .map_or(false, |name| name == STOCK_NAME || name.starts_with(CUSTOM_NAME_PREFIX))
+    }
+}
+
+/// `[MAC (6, big endian), temperature i16 BE 0.1°C, humidity u8 %, battery u8 %, battery u16 BE mV, counter u8]`
+pub fn decode_atc1441(data: &[u8]) -> Option<BroadcastReadings> {
+    if data.len() != ATC1441_LENGTH {
+        return None;
+    }
+
+    let temperature = i16::from_be_bytes([data[6], data[7]]) as f32 / 10.0;
+    let voltage = u16::from_be_bytes([data[10], data[11]]) as f32 / 1000.0;
+    Some(BroadcastReadings {
+        counter: Some(data[12] as u32),
+        readings: vec![
+            SensorReading::Temperature(temperature),
+            SensorReading::Humidity(data[8] as f32)
+        ],
+        telemetry: vec![
+            (TelemetryKind::BatteryLevel, data[9] as f32),
+            (TelemetryKind::BatteryVoltage, voltage)
+        ]
+    })
+}
+
+/// `[MAC (6, little endian), temperature i16 LE 0.01°C, humidity u16 LE 0.01%, battery u16 LE mV,
+/// battery u8 %, counter u8, flags u8]`
+pub fn decode_pvvx(data: &[u8]) -> Option<BroadcastReadings> {
+    if data.len() != PVVX_LENGTH {
+        return None;
+    }
+
+    let temperature = i16::from_le_bytes([data[6], data[7]]) as f32 / 100.0;
+    let humidity = u16::from_le_bytes([data[8], data[9]]) as f32 / 100.0;
+    let voltage = u16::from_le_bytes([data[10], data[11]]) as f32 / 1000.0;
+    Some(BroadcastReadings {
+        counter: Some(data[13] as u32),
+        readings: vec![
+            SensorReading::Temperature(temperature),
+            SensorReading::Humidity(humidity)
+        ],
+        telemetry: vec![
+            (TelemetryKind::BatteryLevel, data[12] as f32),
+            (TelemetryKind::BatteryVoltage, voltage)
+        ]
+    })
+}
+
+/// Unencrypted BTHome v2: a device information byte followed by `[object id, value]` pairs.
+/// Objects are sorted by the id, so decoding stops at the first one we do not know the length of.
+pub fn decode_bthome(data: &[u8]) -> Option<BroadcastReadings> {
+    let (&info, mut objects) = data.split_first()?;
+    if info & BTHOME_ENCRYPTED != 0 || info >> 5 != BTHOME_VERSION_2 {
+        return None;
+    }
+
+    let mut broadcast = BroadcastReadings {
+        counter: None,
+        readings: Vec::new(),
+        telemetry: Vec::new()
+    };
+    while let Some((&id, rest)) = objects.split_first() {
+        let length = match id {
+            0x00 | 0x01 | 0x2E => 1,
+            0x02 | 0x03 | 0x0C | 0x45 => 2,
+            _ => {
+                println!("Unknown BTHome object 0x{:02X}, ignoring the rest", id);
+                break;
+            }
+        };
+        if rest.len() < length {
+            return None;
+        }
+        let (value, rest) = rest.split_at(length);
+        let unsigned = || u16::from_le_bytes([value[0], value[1]]) as f32;
+        let signed = || i16::from_le_bytes([value[0], value[1]]) as f32;
+
+        match id {
+            0x00 => broadcast.counter = Some(value[0] as u32),
+            0x01 => broadcast.telemetry.push((TelemetryKind::BatteryLevel, value[0] as f32)),
+            0x02 => broadcast.readings.push(SensorReading::Temperature(signed() / 100.0)),
+            0x03 => broadcast.readings.push(SensorReading::Humidity(unsigned() / 100.0)),
+            0x0C => broadcast.telemetry.push((TelemetryKind::BatteryVoltage, unsigned() / 1000.0)),
+            0x2E => broadcast.readings.push(SensorReading::Humidity(value[0] as f32)),
+            0x45 => broadcast.readings.push(SensorReading::Temperature(signed() / 10.0)),
+            _ => unreachable!()
+        }
+        objects = rest;
+    }
+
+    if broadcast.readings.is_empty() && broadcast.telemetry.is_empty() {
+        None
+    } else {
+        Some(broadcast)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{} != {}", actual, expected);
+    }
+
+    fn assert_reading(reading: &SensorReading, expected: SensorReading) {
+        match (reading, &expected) {
+            (SensorReading::Temperature(actual), SensorReading::Temperature(value))
+            | (SensorReading::Humidity(actual), SensorReading::Humidity(value)) => assert_close(*actual, *value),
+            _ => panic!("{:?} != {:?}", reading, expected)
+        }
+    }
+
+    fn assert_telemetry(telemetry: &[(TelemetryKind, f32)], kind: TelemetryKind, expected: f32) {
+        let (_, value) = telemetry.iter()
+            .find(|(candidate, _)| *candidate == kind)
+            .unwrap_or_else(|| panic!("No {} in {:?}", kind.name(), telemetry));
+        assert_close(*value, expected);
+    }
+
+    fn advertisement(uuid: u16, data: &[u8]) -> Advertisement {
+        Advertisement {
+            service_data: vec![(uuid, data.to_vec())],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decodes_atc1441() {
+        // A4:C1:38:12:34:56, 23.4°C, 45%, 87%, 2950mV, frame 0x12
+        let data = [0xA4, 0xC1, 0x38, 0x12, 0x34, 0x56, 0x00, 0xEA, 0x2D, 0x57, 0x0B, 0x86, 0x12];
+        let broadcast = XiaomiDecoder.decode(&advertisement(0x181A, &data)).expect("Not decoded");
+
+        assert_eq!(broadcast.counter, Some(0x12));
+        assert_reading(&broadcast.readings[0], SensorReading::Temperature(23.4));
+        assert_reading(&broadcast.readings[1], SensorReading::Humidity(45.0));
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryLevel, 87.0);
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryVoltage, 2.95);
+    }
+
+    #[test]
+    fn decodes_negative_atc1441_temperature() {
+        let data = [0xA4, 0xC1, 0x38, 0x12, 0x34, 0x56, 0xFF, 0xCB, 0x50, 0x64, 0x0C, 0x1C, 0x01];
+        let broadcast = decode_atc1441(&data).expect("Not decoded");
+
+        assert_reading(&broadcast.readings[0], SensorReading::Temperature(-5.3));
+        assert_reading(&broadcast.readings[1], SensorReading::Humidity(80.0));
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryVoltage, 3.1);
+    }
+
+    #[test]
+    fn decodes_pvvx() {
+        // A4:C1:38:12:34:56 reversed, 23.45°C, 45.67%, 2950mV, 87%, frame 0x12, flags
+        let data = [0x56, 0x34, 0x12, 0x38, 0xC1, 0xA4, 0x29, 0x09, 0xD7, 0x11, 0x86, 0x0B, 0x57, 0x12, 0x04];
+        let broadcast = XiaomiDecoder.decode(&advertisement(0x181A, &data)).expect("Not decoded");
+
+        assert_eq!(broadcast.counter, Some(0x12));
+        assert_reading(&broadcast.readings[0], SensorReading::Temperature(23.45));
+        assert_reading(&broadcast.readings[1], SensorReading::Humidity(45.67));
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryLevel, 87.0);
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryVoltage, 2.95);
+    }
+
+    #[test]
+    fn decodes_bthome() {
+        // v2 unencrypted, packet 0x12, battery 87%, 23.45°C, 45.67%, 2.950V
+        let data = [0x40, 0x00, 0x12, 0x01, 0x57, 0x02, 0x29, 0x09, 0x03, 0xD7, 0x11, 0x0C, 0x86, 0x0B];
+        let broadcast = XiaomiDecoder.decode(&advertisement(0xFCD2, &data)).expect("Not decoded");
+
+        assert_eq!(broadcast.counter, Some(0x12));
+        assert_reading(&broadcast.readings[0], SensorReading::Temperature(23.45));
+        assert_reading(&broadcast.readings[1], SensorReading::Humidity(45.67));
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryLevel, 87.0);
+        assert_telemetry(&broadcast.telemetry, TelemetryKind::BatteryVoltage, 2.95);
+    }
+
+    #[test]
+    fn decodes_bthome_up_to_unknown_object() {
+        // 0.1°C temperature, 1% humidity, then a pressure object
+        let data = [0x40, 0x45, 0x13, 0xFF, 0x2E, 0x3C, 0x04, 0x13, 0x8A, 0x01];
+        let broadcast = decode_bthome(&data).expect("Not decoded");
+
+        assert_eq!(broadcast.counter, None);
+        assert_eq!(broadcast.readings.len(), 2);
+        assert_reading(&broadcast.readings[0], SensorReading::Temperature(-23.7));
+        assert_reading(&broadcast.readings[1], SensorReading::Humidity(60.0));
+    }
+
+    #[test]
+    fn rejects_encrypted_and_truncated_bthome() {
+        assert!(decode_bthome(&[0x41, 0x02, 0x29, 0x09]).is_none());
+        assert!(decode_bthome(&[0x40, 0x02, 0x29]).is_none());
+        // BTHome v1 has no device information byte
+        assert!(decode_bthome(&[0x02, 0x00, 0x29, 0x09]).is_none());
+    }
+
+    #[test]
+    fn ignores_other_advertisements() {
+        assert!(XiaomiDecoder.decode(&advertisement(0x181A, &[0x00; 10])).is_none());
+        assert!(XiaomiDecoder.decode(&advertisement(0xFE95, &[0x50, 0x30, 0x5B, 0x05])).is_none());
+        assert!(XiaomiDecoder.decode(&Advertisement::default()).is_none());
+    }
+
+    #[test]
+    fn recognizes_by_name() {
+        let named = |name: &str| Advertisement { local_name: Some(name.to_string()), ..Default::default() };
+
+        assert!(XiaomiDecoder.recognizes(&named("LYWSD03MMC")));
+        assert!(XiaomiDecoder.recognizes(&named("ATC_123456")));
+        assert!(!XiaomiDecoder.recognizes(&named("Weather")));
+        assert!(!XiaomiDecoder.recognizes(&Advertisement::default()));
+    }
+}
@@ -46,17 +46,6 @@ export class SensorUpdater {
         ]);
         let is_recent = interval_func.get(interval);
 
-        let is_relevant = (reading: TimestampedSensorReading) => {
-            switch (reading.kind) {
-                case ReadingKind.Temperature:
-                    return reading.value > 0;
-                case ReadingKind.Humidity:
-                    return reading.value > 20;
-            }
-
-            return true;
-        };
-
         let reading_to_xy = (reading: TimestampedSensorReading) => {
             return {
                 x: reading.timestamp,
@@ -66,7 +55,6 @@ export class SensorUpdater {
 
         return this.allReadings
             .filter(reading => reading.kind == kind)
-            .filter(is_relevant)
             .filter(is_recent)
             .map(reading_to_xy);
     }
@@ -15,10 +15,17 @@ export async function fetch_latest(sensor_id: Number, kind: ReadingKind): Promis
         .then(data => data.value);
 }
 
+export enum ReadingQuality {
+    Good = "good",
+    OutOfRange = "out_of_range",
+    RateOfChange = "rate_of_change"
+}
+
 export class TimestampedSensorReading {
     kind: ReadingKind;
     value: number;
     timestamp: Date;
+    quality: ReadingQuality;
 }
 
 export async function fetch_readings(sensor_id: Number): Promise<Array<TimestampedSensorReading>> {